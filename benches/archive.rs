@@ -0,0 +1,109 @@
+// © 2019 3D Robotics. License: Apache-2.0
+// Throughput benchmarks for archive assembly and streaming from in-memory sources. Run them with
+//
+//     cargo bench
+//
+// or `cargo bench -- deep_range` for one group.
+
+use bytes::Bytes;
+use chrono::{ DateTime, Utc };
+use criterion::{ criterion_group, criterion_main, BenchmarkId, Criterion, Throughput };
+use futures::{ executor::block_on, Stream, TryStreamExt };
+use zipstream::stream_range::{ BoxError, DynStreamRange, Range, StreamRange };
+use zipstream::zip::{ ZipEntry, ZipOptions, zip_stream };
+
+/// `count` entries that share the contents `data`, so that copying it isn't measured
+fn entries(count: usize, data: &Bytes) -> Vec<ZipEntry> {
+    let last_modified = "2018-12-06T20:15:59Z".parse::<DateTime<Utc>>().unwrap();
+
+    (0..count).map(|i| ZipEntry {
+        archive_path: format!("dir{}/file{}.bin", i % 100, i),
+        data: Box::new(data.clone()),
+        crc: 0,
+        last_modified,
+        last_accessed: None,
+        created: None,
+        symlink_target: None,
+        sha256: None,
+    }).collect()
+}
+
+/// Read `range` of the stream, returning the number of bytes produced. Each chunk is copied once,
+/// as it would be into a socket buffer, since in-memory sources are otherwise never touched.
+fn drain(stream: &impl StreamRange, range: Range) -> u64 {
+    let mut buf = Vec::new();
+    block_on(stream.stream_range(range).try_fold(0, |n, b| {
+        buf.clear();
+        buf.extend_from_slice(&b);
+        futures::future::ok(n + b.len() as u64)
+    })).unwrap()
+}
+
+/// Number of bytes produced by `stream`, without copying them
+fn count_bytes(stream: impl Stream<Item = Result<Bytes, BoxError>>) -> u64 {
+    block_on(stream.try_fold(0, |n, b| futures::future::ok(n + b.len() as u64))).unwrap()
+}
+
+/// Archive length for `count` entries of `data`
+fn archive_len(count: usize, data: &Bytes, options: ZipOptions) -> u64 {
+    zip_stream(entries(count, data), options).len()
+}
+
+fn many_small_entries(c: &mut Criterion) {
+    let mut group = c.benchmark_group("many_small_entries");
+    let data = Bytes::from(vec![0x5a; 1024]);
+
+    for &count in &[100, 10_000] {
+        group.throughput(Throughput::Bytes(archive_len(count, &data, ZipOptions::default())));
+        group.bench_with_input(BenchmarkId::new("assemble_and_stream_1KiB", count), &count, |b, &count| b.iter(|| {
+            let zip = zip_stream(entries(count, &data), ZipOptions::default());
+            drain(&zip, Range { start: 0, end: zip.len() })
+        }));
+    }
+}
+
+fn large_entries(c: &mut Criterion) {
+    let mut group = c.benchmark_group("large_entries");
+    group.sample_size(10);
+    let options = || ZipOptions { force_zip64: true, ..ZipOptions::default() };
+
+    for &(count, size) in &[(1, 64 << 20), (16, 8 << 20)] {
+        let data = Bytes::from(vec![0x5a; size]);
+        group.throughput(Throughput::Bytes(archive_len(count, &data, options())));
+        group.bench_function(format!("assemble_and_stream_{}x{}MiB", count, size >> 20), |b| b.iter(|| {
+            let zip = zip_stream(entries(count, &data), options());
+            drain(&zip, Range { start: 0, end: zip.len() })
+        }));
+    }
+}
+
+fn deep_range(c: &mut Criterion) {
+    // Skipping to the end must not read or copy the data before the range
+    let mut group = c.benchmark_group("deep_range");
+    let zip = zip_stream(entries(50_000, &Bytes::from(vec![0x5a; 64 << 10])), ZipOptions::default());
+    let end = zip.len();
+
+    for &len in &[1, 1 << 20] {
+        group.throughput(Throughput::Bytes(len));
+        group.bench_with_input(BenchmarkId::new(format!("range_at_end_of_{}GiB", end >> 30), len), &len, |b, &len| b.iter(|| {
+            drain(&zip, Range { start: end - len, end })
+        }));
+    }
+}
+
+fn bytes_ranges(c: &mut Criterion) {
+    // 10,000 range requests on one `Bytes`, through its own stream type and through the boxed form
+    let mut group = c.benchmark_group("bytes_ranges");
+    let data = Bytes::from(vec![0x5a; 1000]);
+    let ranges = || (0..10_000).map(|i| Range { start: i % 500, end: 500 + i % 500 });
+
+    group.bench_function("bytes", |b| b.iter(|| {
+        ranges().map(|range| count_bytes(data.stream_range(range))).sum::<u64>()
+    }));
+    group.bench_function("boxed", |b| b.iter(|| {
+        ranges().map(|range| count_bytes(data.dyn_stream_range(range))).sum::<u64>()
+    }));
+}
+
+criterion_group!(benches, many_small_entries, large_entries, deep_range, bytes_ranges);
+criterion_main!(benches);
@@ -0,0 +1,76 @@
+// © 2019 3D Robotics. License: Apache-2.0
+use hyper::{ header, Body, Method, Request, Response, StatusCode };
+use serde_derive::Deserialize;
+use crate::Config;
+use crate::redact::Redacted;
+
+#[derive(Deserialize)]
+struct LogLevelRequest {
+    level: String,
+}
+
+/// Compare without returning early, so the time taken doesn't reveal how much of a token matched
+fn token_matches(given: &[u8], expected: &[u8]) -> bool {
+    given.len() == expected.len() && given.iter().zip(expected).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Check the request's `Authorization: Bearer <token>` against `--debug-token`.
+/// Admin endpoints don't exist unless a token is configured.
+fn authorize(config: &Config, req: &Request<Body>) -> Result<(), (StatusCode, &'static str)> {
+    let expected = config.debug_token.as_ref().ok_or((StatusCode::NOT_FOUND, "Not found"))?;
+
+    let given = req.headers().get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+
+    match given {
+        Some(given) if token_matches(given.as_bytes(), expected.as_bytes()) => Ok(()),
+        _ => {
+            log::warn!("Unauthorized admin request: {} {}", req.method(), Redacted(req.uri()));
+            Err((StatusCode::UNAUTHORIZED, "Unauthorized"))
+        }
+    }
+}
+
+fn log_level_response() -> Response<Body> {
+    let body = serde_json::json!({ "level": log::max_level().to_string().to_lowercase() });
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(body.to_string().into())
+        .unwrap()
+}
+
+/// Handle a request under `/admin/`
+pub async fn handle(config: &Config, req: Request<Body>) -> Result<Response<Body>, (StatusCode, &'static str)> {
+    authorize(config, &req)?;
+
+    match (req.method(), req.uri().path()) {
+        (&Method::GET, "/admin/log-level") => Ok(log_level_response()),
+        (&Method::POST, "/admin/log-level") => {
+            let body = hyper::body::to_bytes(req.into_body()).await.map_err(|e| {
+                log::error!("Failed to read admin request body: {}", e);
+                (StatusCode::BAD_REQUEST, "Failed to read request body")
+            })?;
+
+            let level = serde_json::from_slice::<LogLevelRequest>(&body).ok()
+                .and_then(|r| r.level.parse::<log::LevelFilter>().ok())
+                .ok_or((StatusCode::BAD_REQUEST, "Expected {\"level\": \"off|error|warn|info|debug|trace\"}"))?;
+
+            // Checked by the `log` macros before every call; the logger itself passes everything
+            log::set_max_level(level);
+            log::warn!("Log level changed to {}", level);
+
+            Ok(log_level_response())
+        }
+        (_, "/admin/log-level") => Err((StatusCode::METHOD_NOT_ALLOWED, "Only GET and POST requests allowed")),
+        _ => Err((StatusCode::NOT_FOUND, "Not found")),
+    }
+}
+
+#[test]
+fn test_token_matches() {
+    assert!(token_matches(b"secret", b"secret"));
+    assert!(!token_matches(b"secreT", b"secret"));
+    assert!(!token_matches(b"secret2", b"secret"));
+    assert!(!token_matches(b"", b"secret"));
+}
@@ -0,0 +1,166 @@
+// © 2019 3D Robotics. License: Apache-2.0
+use clap::{ App, ErrorKind };
+
+/// Prefix of environment variables that set options, as `ZIPSTREAM_UPSTREAM_TIMEOUT_MS` for
+/// `--upstream-timeout-ms`
+pub const ENV_PREFIX: &str = "ZIPSTREAM_";
+
+/// Options from one source, as long option names and their values. Switches that are on have no
+/// values, and switches that are off have `None`, so that they still override lower layers.
+type Layer = Vec<(String, Option<Vec<String>>)>;
+
+/// Whether `--name` takes a value. clap has no public way to ask, so parse a value for it
+/// followed by `--help`, which only gets as far as `--help` if the value was accepted.
+fn takes_value(app: &App<'static, 'static>, name: &str) -> bool {
+    let probe = app.clone().get_matches_from_safe(vec!["zipstream".to_owned(), format!("--{}", name), "x".to_owned(), "--help".to_owned()]);
+    matches!(probe, Err(e) if e.kind == ErrorKind::HelpDisplayed)
+}
+
+/// Parse a TOML `--config` file, whose keys are long option names, as `upstream-timeout-ms = 30000`.
+/// Switches are set with `true`, and repeatable options may be given an array.
+fn file_layer(text: &str) -> Result<Layer, String> {
+    let table: toml::value::Table = toml::from_str(text).map_err(|e| e.to_string())?;
+
+    let scalar = |name: &str, value: &toml::Value| match value {
+        toml::Value::String(s) => Ok(s.clone()),
+        toml::Value::Integer(_) | toml::Value::Float(_) => Ok(value.to_string()),
+        _ => Err(format!("unsupported value for `{}`", name)),
+    };
+
+    let mut layer = Vec::new();
+    for (name, value) in table.iter().filter(|(name, _)| *name != "config") {
+        match value {
+            toml::Value::Boolean(true) => layer.push((name.clone(), Some(Vec::new()))),
+            toml::Value::Boolean(false) => layer.push((name.clone(), None)),
+            toml::Value::Array(values) => {
+                layer.push((name.clone(), Some(values.iter().map(|v| scalar(name, v)).collect::<Result<_, _>>()?)));
+            }
+            value => layer.push((name.clone(), Some(vec![scalar(name, value)?]))),
+        }
+    }
+    Ok(layer)
+}
+
+/// Options from `ZIPSTREAM_*` environment variables. Switches are set with `true`.
+fn env_layer(app: &App<'static, 'static>, vars: impl IntoIterator<Item = (String, String)>) -> Result<Layer, String> {
+    let mut layer = Vec::new();
+    for (var, value) in vars {
+        let name = match var.strip_prefix(ENV_PREFIX) {
+            Some(name) if name != "CONFIG" => name.to_ascii_lowercase().replace('_', "-"),
+            _ => continue,
+        };
+
+        if takes_value(app, &name) {
+            layer.push((name, Some(vec![value])));
+        } else {
+            match &value[..] {
+                "true" => layer.push((name, Some(Vec::new()))),
+                "false" => layer.push((name, None)),
+                _ => return Err(format!("`{}` must be `true` or `false`", var)),
+            }
+        }
+    }
+    Ok(layer)
+}
+
+/// The long option names given on the command line
+fn cli_names(cli: &[String]) -> Vec<&str> {
+    cli.iter()
+        .filter_map(|arg| arg.strip_prefix("--"))
+        .map(|arg| arg.split('=').next().unwrap())
+        .collect()
+}
+
+/// The command line `cli`, with options it doesn't set added from the environment variables `vars`,
+/// or failing that from the `--config` file, if any. Everything is then validated by parsing the
+/// result with `app` as usual.
+pub fn layered_args(app: &App<'static, 'static>, cli: Vec<String>, vars: impl IntoIterator<Item = (String, String)>) -> Result<Vec<String>, String> {
+    let config = cli.iter().enumerate()
+        .find_map(|(i, arg)| match arg.strip_prefix("--config") {
+            Some("") => cli.get(i + 1).cloned(),
+            Some(path) => path.strip_prefix('=').map(|p| p.to_owned()),
+            None => None,
+        });
+
+    let file = match config {
+        Some(path) => {
+            let text = std::fs::read_to_string(&path).map_err(|e| format!("failed to read {}: {}", path, e))?;
+            file_layer(&text).map_err(|e| format!("invalid config file {}: {}", path, e))?
+        }
+        None => Vec::new(),
+    };
+    let env = env_layer(app, vars)?;
+
+    let given = cli_names(&cli);
+    let from_env: Vec<_> = env.iter().map(|(name, _)| &name[..]).collect();
+    let layers = env.iter().chain(file.iter().filter(|(name, _)| !from_env.contains(&&name[..])));
+
+    let mut args = cli.clone();
+    for (name, values) in layers.filter(|(name, _)| !given.contains(&&name[..])) {
+        let values = match values {
+            Some(values) => values,
+            None => continue,
+        };
+        // clap ignores `=value` on a switch rather than rejecting it
+        if !values.is_empty() && !takes_value(app, name) {
+            return Err(format!("`{}` is a switch, so must be `true` or `false`", name));
+        }
+        if values.is_empty() {
+            args.push(format!("--{}", name));
+        }
+        for value in values {
+            args.push(format!("--{}={}", name, value));
+        }
+    }
+    Ok(args)
+}
+
+#[test]
+fn test_layered_args() {
+    let app = crate::app();
+    let path = std::env::temp_dir().join(format!("zipstream-test-config-{}.toml", std::process::id()));
+    std::fs::write(&path, r#"
+        upstream = ["http://file-a", "http://file-b"]
+        response-chunk-size-bytes = 1
+        retry-after-secs = 1
+        archive-root = "file"
+        redact-keys = true
+        strip-trailing-slash = false
+    "#).unwrap();
+
+    let args = |cli: &[&str], env: &[(&str, &str)]| {
+        let mut argv = vec!["zipstream".to_owned(), "--config".to_owned(), path.to_str().unwrap().to_owned()];
+        argv.extend(cli.iter().map(|&a| a.to_owned()));
+        let env = env.iter().map(|&(k, v)| (k.to_owned(), v.to_owned()));
+        layered_args(&app, argv, env).map(|args| app.clone().get_matches_from(args))
+    };
+
+    // CLI > env > file > default
+    let env = [("ZIPSTREAM_RESPONSE_CHUNK_SIZE_BYTES", "2"), ("ZIPSTREAM_RETRY_AFTER_SECS", "2"), ("HOME", "/root")];
+    let matches = args(&["--response-chunk-size-bytes", "3"], &env).unwrap();
+    assert_eq!(matches.value_of("response-chunk-size-bytes"), Some("3"));
+    assert_eq!(matches.value_of("retry-after-secs"), Some("2"));
+    assert_eq!(matches.value_of("archive-root"), Some("file"));
+    assert_eq!(matches.value_of("max-inline-bytes"), Some("65536"));
+    assert_eq!(matches.values_of("upstream").unwrap().collect::<Vec<_>>(), vec!["http://file-a", "http://file-b"]);
+    assert!(matches.is_present("redact-keys"));
+    assert!(!matches.is_present("strip-trailing-slash"));
+
+    let matches = args(&["--upstream=http://cli"], &[("ZIPSTREAM_REDACT_KEYS", "false"), ("ZIPSTREAM_STRIP_TRAILING_SLASH", "true")]).unwrap();
+    assert_eq!(matches.values_of("upstream").unwrap().collect::<Vec<_>>(), vec!["http://cli"]);
+    assert!(!matches.is_present("redact-keys"));
+    assert!(matches.is_present("strip-trailing-slash"));
+
+    // Options that take a value may be set to `true` from the environment
+    let matches = args(&[], &[("ZIPSTREAM_ARCHIVE_ROOT", "true")]).unwrap();
+    assert_eq!(matches.value_of("archive-root"), Some("true"));
+
+    assert!(args(&[], &[("ZIPSTREAM_REDACT_KEYS", "yes")]).is_err());
+
+    std::fs::write(&path, "upstream = \"http://file\"\nredact-keys = \"yes\"").unwrap();
+    assert!(args(&[], &[]).is_err());
+    std::fs::write(&path, "upstream = { url = \"http://file\" }").unwrap();
+    assert!(args(&[], &[]).is_err());
+    std::fs::remove_file(&path).unwrap();
+    assert!(args(&[], &[]).is_err());
+}
@@ -0,0 +1,119 @@
+// © 2019 3D Robotics. License: Apache-2.0
+use std::fmt;
+use std::str::FromStr;
+use glob::{ MatchOptions, Pattern };
+
+/// Comma-separated glob patterns selecting which entries to include in an archive, such as
+/// `!*.tmp,!.*`. Patterns starting with `!` exclude matching entries; if there are any other
+/// patterns, only entries matching one of them are included.
+///
+/// Patterns use the glob syntax of the `glob` crate: `*` matches any characters, `?` any one
+/// character, `[abc]` or `[!abc]` a character in or not in a set, and `**` any directories. A
+/// pattern without a `/` is also matched against the last component of the name, so `.*` excludes
+/// hidden files in any directory.
+#[derive(Clone, Debug)]
+pub struct EntryFilter {
+    source: String,
+    include: Vec<Glob>,
+    exclude: Vec<Glob>,
+}
+
+#[derive(Clone, Debug)]
+struct Glob {
+    pattern: Pattern,
+    basename: bool,
+}
+
+/// `*` matches across `/`, as in the rest of the name, and a leading `.` needs no special pattern
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: false,
+    require_literal_leading_dot: false,
+};
+
+impl Glob {
+    fn parse(pattern: &str) -> Result<Glob, String> {
+        Ok(Glob {
+            pattern: Pattern::new(pattern).map_err(|e| format!("Invalid pattern {:?}: {}", pattern, e))?,
+            basename: !pattern.contains('/'),
+        })
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        self.pattern.matches_with(name, MATCH_OPTIONS)
+            || (self.basename && self.pattern.matches_with(name.rsplit('/').next().unwrap(), MATCH_OPTIONS))
+    }
+}
+
+impl FromStr for EntryFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut filter = EntryFilter { source: s.to_owned(), include: Vec::new(), exclude: Vec::new() };
+
+        for pattern in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            match pattern.strip_prefix('!') {
+                Some(pattern) => filter.exclude.push(Glob::parse(pattern)?),
+                None => filter.include.push(Glob::parse(pattern)?),
+            }
+        }
+
+        Ok(filter)
+    }
+}
+
+impl fmt::Display for EntryFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl PartialEq for EntryFilter {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+impl EntryFilter {
+    /// Whether an entry with this name belongs in the archive
+    pub fn includes(&self, name: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|g| g.matches(name)))
+            && !self.exclude.iter().any(|g| g.matches(name))
+    }
+}
+
+#[test]
+fn test_entry_filter() {
+    let filter: EntryFilter = "!*.tmp,!.*".parse().unwrap();
+    assert!(filter.includes("report.pdf"));
+    assert!(filter.includes("dir/report.pdf"));
+    assert!(filter.includes("dir.tmp/report.pdf"));
+    assert!(!filter.includes("report.pdf.tmp"));
+    assert!(!filter.includes("dir/scratch.tmp"));
+    assert!(!filter.includes(".DS_Store"));
+    assert!(!filter.includes("dir/.hidden"));
+
+    let filter: EntryFilter = "images/*.jp?g, *.png, !images/secret*".parse().unwrap();
+    assert!(filter.includes("images/cat.jpeg"));
+    assert!(!filter.includes("images/cat.jpg"));
+    assert!(filter.includes("icons/logo.png"));
+    assert!(!filter.includes("images/secret.jpeg"));
+    assert!(!filter.includes("readme.txt"));
+
+    let filter: EntryFilter = "file[0-9].txt,!file[!0-4].txt".parse().unwrap();
+    assert!(filter.includes("file3.txt"));
+    assert!(!filter.includes("file7.txt"));
+    assert!(!filter.includes("filex.txt"));
+
+    assert!("".parse::<EntryFilter>().unwrap().includes("anything"));
+    assert!("a.(txt)+".parse::<EntryFilter>().unwrap().includes("a.(txt)+"));
+    assert!("[abc".parse::<EntryFilter>().is_err());
+    assert!("a**b".parse::<EntryFilter>().is_err());
+
+    let filter: EntryFilter = "!docs/**,!**/cache/*".parse().unwrap();
+    assert!(filter.includes("readme.txt"));
+    assert!(filter.includes("src/docs/readme.txt"));
+    assert!(!filter.includes("docs/guide/index.html"));
+    assert!(!filter.includes("cache/x"));
+    assert!(!filter.includes("build/cache/x"));
+}
@@ -0,0 +1,231 @@
+// © 2019 3D Robotics. License: Apache-2.0
+use std::future::Future;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use hyper::{ Body, Client, Request, client::connect::Connect };
+use rusoto_s3::{ S3, HeadBucketRequest };
+
+/// How `/healthz` and `/readyz` decide whether this instance is ready, chosen at startup by which
+/// of `--upstream-health-check-path`, `--s3-health-check-bucket`, and `--no-health-check` is given.
+#[derive(Clone, Debug, PartialEq)]
+pub enum HealthCheck {
+    /// Ready once the upstream server and S3 have been reached, with a warm-up probe until then.
+    /// The probe checks S3 with `HeadBucket` on this bucket, if there is one.
+    Readiness(Option<String>),
+    /// Ready if a GET of this URL returns 2xx
+    UpstreamUrl(String),
+    /// Ready if `HeadBucket` on this bucket succeeds
+    S3Bucket(String),
+    /// Always ready, for deployments whose backends are checked elsewhere
+    Disabled,
+}
+
+/// The URL of `path` on the upstream server at `upstream`
+pub fn upstream_health_check_url(upstream: &str, path: &str) -> String {
+    format!("{}/{}", upstream.trim_end_matches('/'), path.trim_start_matches('/'))
+}
+
+/// GET `url`, and check that the response has a 2xx status
+pub async fn check_upstream<C>(client: &Client<C>, url: &str) -> bool
+    where C: Connect + Clone + Send + Sync + 'static
+{
+    let req = match Request::get(url).body(Body::empty()) {
+        Ok(req) => req,
+        Err(e) => {
+            log::error!("Invalid upstream health check URL: {}", e);
+            return false;
+        }
+    };
+
+    match client.request(req).await {
+        Ok(res) if res.status().is_success() => true,
+        Ok(res) => {
+            log::warn!("Upstream health check returned {}", res.status());
+            false
+        }
+        Err(e) => {
+            log::warn!("Upstream health check failed: {}", e);
+            false
+        }
+    }
+}
+
+/// Check that `bucket` exists and the credentials can access it
+pub async fn check_s3_bucket(s3: &(dyn S3 + Send + Sync), bucket: &str) -> bool {
+    match s3.head_bucket(HeadBucketRequest { bucket: bucket.to_owned(), ..HeadBucketRequest::default() }).await {
+        Ok(()) => true,
+        Err(e) => {
+            log::warn!("S3 health check of bucket {} failed: {}", bucket, e);
+            false
+        }
+    }
+}
+
+/// Tracks whether this instance has proven it can reach its backends.
+///
+/// On cold start the process may be listening before upstream or S3 are reachable. `/healthz`
+/// reports not-ready until both the upstream server and S3 have been reached, either by serving
+/// requests or by warm-up probes of each.
+#[derive(Debug, Default)]
+pub struct Readiness {
+    upstream_ok: AtomicBool,
+    s3_ok: AtomicBool,
+}
+
+impl Readiness {
+    pub fn new() -> Readiness { Readiness::default() }
+
+    /// Record a response from the upstream server that isn't a server error
+    pub fn mark_upstream_ok(&self) {
+        self.upstream_ok.store(true, Ordering::Relaxed);
+    }
+
+    /// Record a successful S3 GetObject
+    pub fn mark_s3_ok(&self) {
+        self.s3_ok.store(true, Ordering::Relaxed);
+    }
+
+    fn upstream_ok(&self) -> bool { self.upstream_ok.load(Ordering::Relaxed) }
+
+    fn s3_ok(&self) -> bool { self.s3_ok.load(Ordering::Relaxed) }
+
+    pub fn is_ready(&self) -> bool {
+        self.upstream_ok() && self.s3_ok()
+    }
+
+    /// Warm-up probe for whichever backends haven't been reached yet: GET `upstream`, which must
+    /// return 2xx or 3xx, and run `check_s3`. With no upstream server, as with `--manifest-source`,
+    /// only S3 is needed. Returns the resulting readiness.
+    pub async fn probe<C>(&self, client: &Client<C>, upstream: Option<&str>, check_s3: impl Future<Output = bool>) -> bool
+        where C: Connect + Clone + Send + Sync + 'static
+    {
+        if let Some(upstream) = upstream.filter(|_| !self.upstream_ok()) {
+            if probe_upstream(client, upstream).await {
+                self.mark_upstream_ok();
+            }
+        }
+
+        if !self.s3_ok() && check_s3.await {
+            log::info!("Readiness probe reached S3");
+            self.mark_s3_ok();
+        }
+
+        match upstream {
+            Some(_) => self.is_ready(),
+            None => self.s3_ok(),
+        }
+    }
+}
+
+/// GET `upstream` for the warm-up probe, and check that it answers with 2xx or 3xx
+async fn probe_upstream<C>(client: &Client<C>, upstream: &str) -> bool
+    where C: Connect + Clone + Send + Sync + 'static
+{
+    let req = match Request::get(upstream).body(Body::empty()) {
+        Ok(req) => req,
+        Err(e) => {
+            log::error!("Invalid upstream URL for readiness probe: {}", e);
+            return false;
+        }
+    };
+
+    match client.request(req).await {
+        Ok(res) if res.status().is_success() || res.status().is_redirection() => {
+            log::info!("Readiness probe succeeded with status {}", res.status());
+            true
+        }
+        Ok(res) => {
+            log::warn!("Readiness probe returned {}", res.status());
+            false
+        }
+        Err(e) => {
+            log::warn!("Readiness probe failed: {}", e);
+            false
+        }
+    }
+}
+
+#[test]
+fn test_upstream_health_check_url() {
+    assert_eq!(upstream_health_check_url("http://upstream:8000", "/health"), "http://upstream:8000/health");
+    assert_eq!(upstream_health_check_url("http://upstream:8000/api/", "health"), "http://upstream:8000/api/health");
+}
+
+#[tokio::test]
+async fn test_check_upstream() {
+    use hyper::{ Response, Server, StatusCode, service::{ make_service_fn, service_fn } };
+    use std::convert::Infallible;
+
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service_fn(|_conn| async {
+        Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+            let status = if req.uri().path() == "/health" { StatusCode::NO_CONTENT } else { StatusCode::NOT_FOUND };
+            Ok::<_, Infallible>(Response::builder().status(status).body(Body::empty()).unwrap())
+        }))
+    }));
+    let upstream = format!("http://{}", server.local_addr());
+    tokio::spawn(server);
+
+    let client = Client::new();
+    assert!(check_upstream(&client, &upstream_health_check_url(&upstream, "/health")).await);
+    assert!(!check_upstream(&client, &upstream_health_check_url(&upstream, "/missing")).await);
+    assert!(!check_upstream(&client, "http://127.0.0.1:1/health").await);
+}
+
+#[test]
+fn test_ready_after_upstream_and_s3() {
+    let readiness = Readiness::new();
+    assert!(!readiness.is_ready());
+    readiness.mark_upstream_ok();
+    assert!(!readiness.is_ready());
+    readiness.mark_s3_ok();
+    assert!(readiness.is_ready());
+}
+
+/// Simulate a cold start: the probe fails while upstream is down or answering 503, and readiness
+/// flips once both upstream and S3 are reachable.
+#[tokio::test]
+async fn test_cold_start_probe() {
+    use hyper::{ Response, Server, StatusCode, service::{ make_service_fn, service_fn } };
+    use std::convert::Infallible;
+    use std::sync::Arc;
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let upstream = format!("http://{}/", addr);
+    let client = Client::new();
+    let readiness = Readiness::new();
+
+    // Nothing accepting connections yet
+    drop(listener);
+    assert!(!readiness.probe(&client, Some(&upstream), async { false }).await);
+    assert!(!readiness.is_ready());
+
+    let available = Arc::new(AtomicBool::new(false));
+    let server = Server::bind(&addr).serve(make_service_fn({
+        let available = available.clone();
+        move |_conn| {
+            let available = available.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |_req| {
+                    let status = if available.load(Ordering::SeqCst) { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+                    async move { Ok::<_, Infallible>(Response::builder().status(status).body(Body::empty()).unwrap()) }
+                }))
+            }
+        }
+    }));
+    tokio::spawn(server);
+
+    // Listening, but answering 503 until it can serve
+    assert!(!readiness.probe(&client, Some(&upstream), async { true }).await);
+    assert!(!readiness.probe(&client, Some(&upstream), async { true }).await);
+    assert!(!readiness.is_ready());
+
+    // Once reached, S3 isn't checked again
+    available.store(true, Ordering::SeqCst);
+    assert!(readiness.probe(&client, Some(&upstream), async { unreachable!() }).await);
+    assert!(readiness.is_ready());
+
+    // Without an upstream server only S3 is needed
+    let readiness = Readiness::new();
+    assert!(!readiness.probe(&client, None, async { false }).await);
+    assert!(readiness.probe(&client, None, async { true }).await);
+}
@@ -0,0 +1,145 @@
+// © 2019 3D Robotics. License: Apache-2.0
+use bytes::Bytes;
+use futures::{ future, stream, StreamExt, TryStreamExt };
+use std::hash::{ Hash, Hasher };
+use crate::stream_range::{ BoxBytesStream, DynStreamRange, Range, StreamRange };
+
+// Traditional PKWARE encryption, APPNOTE.TXT section 6.1. This cipher is broken by known-plaintext
+// attacks and is only offered for compatibility with tools that support nothing else.
+
+/// Length of the encryption header that precedes each entry's data
+pub const HEADER_LEN: u64 = 12;
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            c = if c & 1 != 0 { 0xEDB88320 ^ (c >> 1) } else { c >> 1 };
+            bit += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+}
+
+static CRC_TABLE: [u32; 256] = crc_table();
+
+fn crc32_byte(crc: u32, b: u8) -> u32 {
+    (crc >> 8) ^ CRC_TABLE[((crc ^ b as u32) & 0xff) as usize]
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Keys(u32, u32, u32);
+
+impl Keys {
+    fn new(password: &[u8]) -> Keys {
+        let mut keys = Keys(0x12345678, 0x23456789, 0x34567890);
+        for &b in password {
+            keys.update(b);
+        }
+        keys
+    }
+
+    fn update(&mut self, plain: u8) {
+        self.0 = crc32_byte(self.0, plain);
+        self.1 = self.1.wrapping_add(self.0 & 0xff).wrapping_mul(134775813).wrapping_add(1);
+        self.2 = crc32_byte(self.2, (self.1 >> 24) as u8);
+    }
+
+    fn encrypt(&mut self, plain: u8) -> u8 {
+        let temp = (self.2 | 2) as u16;
+        let cipher = plain ^ (temp.wrapping_mul(temp ^ 1) >> 8) as u8;
+        self.update(plain);
+        cipher
+    }
+}
+
+/// Implements `StreamRange` for entry data encrypted with the traditional PKWARE cipher,
+/// preceded by its encryption header.
+pub struct Encrypted {
+    data: Box<dyn DynStreamRange>,
+
+    /// Encrypted header
+    header: Bytes,
+
+    /// Cipher state after the header
+    keys: Keys,
+}
+
+impl Encrypted {
+    /// The header is normally random, but is derived from the password and entry here so that
+    /// the archive is the same for every request, as needed for Range requests.
+    pub fn new(data: Box<dyn DynStreamRange>, password: &str, archive_path: &str, crc: u32) -> Encrypted {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        (password, archive_path, crc).hash(&mut hasher);
+        let a = hasher.finish();
+        a.hash(&mut hasher);
+        let b = hasher.finish();
+
+        let mut plain = [0u8; HEADER_LEN as usize];
+        plain[..8].copy_from_slice(&a.to_le_bytes());
+        plain[8..11].copy_from_slice(&b.to_le_bytes()[..3]);
+        plain[11] = (crc >> 24) as u8; // checked by readers to detect a wrong password
+
+        let mut keys = Keys::new(password.as_bytes());
+        let header: Vec<u8> = plain.iter().map(|&b| keys.encrypt(b)).collect();
+
+        Encrypted { data, header: header.into(), keys }
+    }
+}
+
+impl StreamRange for Encrypted {
+    type Stream = BoxBytesStream;
+
+    fn len(&self) -> u64 { HEADER_LEN + self.data.len() }
+
+    fn stream_range(&self, mut range: Range) -> BoxBytesStream {
+        let header = range.take_prefix(HEADER_LEN).map(|r| Ok(self.header.slice(r.start as usize..r.end as usize)));
+
+        if range.end == 0 {
+            return Box::pin(stream::iter(header));
+        }
+
+        // Each byte's encryption depends on all the data before it, so the entry is read from the
+        // start and the part before the range discarded.
+        let mut keys = self.keys;
+        let mut skip = range.start;
+        let data = self.data.stream_range(Range { start: 0, end: range.end })
+            .map_ok(move |chunk| {
+                let encrypted: Vec<u8> = chunk.iter().map(|&b| keys.encrypt(b)).collect();
+                let start = skip.min(encrypted.len() as u64);
+                skip -= start;
+                Bytes::from(encrypted).slice(start as usize..)
+            })
+            .try_filter(|chunk| future::ready(!chunk.is_empty()));
+
+        Box::pin(stream::iter(header).chain(data))
+    }
+}
+
+#[test]
+fn test_encrypt() {
+    use futures::executor::block_on;
+
+    // Compatibility with unzip is tested in zip.rs; here, check that every range matches the whole
+    let data: Box<dyn DynStreamRange> = Box::new(Bytes::from_static(b"Hello World\n"));
+    let encrypted = Encrypted::new(data, "secret", "a.txt", 0x12345678);
+    assert_eq!(encrypted.len(), 24);
+
+    let read = |start, end| -> Vec<u8> {
+        block_on(encrypted.stream_range(Range { start, end }).map_ok(|b| b.to_vec()).try_concat()).unwrap()
+    };
+    let whole = read(0, 24);
+    assert_eq!(whole.len(), 24);
+    assert_ne!(&whole[12..], b"Hello World\n");
+
+    for start in 0..24 {
+        for end in start..=24 {
+            assert_eq!(read(start, end), &whole[start as usize..end as usize], "{} {}", start, end);
+        }
+    }
+}
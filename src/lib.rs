@@ -0,0 +1,14 @@
+// © 2019 3D Robotics. License: Apache-2.0
+//! Archive assembly: building zip and tar streams from `StreamRange` sources. The server in
+//! `main.rs` uses these modules, and the benchmarks in `benches/` drive them directly.
+
+pub mod stream_range;
+pub mod zip;
+pub mod tar;
+pub mod name_encoding;
+pub mod entry_filter;
+pub mod legacy_encryption;
+pub mod health;
+pub mod metrics;
+pub mod stats;
+pub mod redact;
@@ -0,0 +1,313 @@
+// © 2019 3D Robotics. License: Apache-2.0
+use std::future::Future;
+use std::io;
+use std::net::{ SocketAddr, TcpListener };
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::task::{ Context, Poll };
+use std::time::Duration;
+use hyper::server::accept::Accept;
+use hyper::server::conn::{ AddrIncoming, AddrStream };
+use socket2::{ Domain, Socket, Type };
+use tokio::io::{ AsyncRead, AsyncWrite, ReadBuf };
+use tokio::time::{ sleep, Instant, Sleep };
+
+/// Create the listening socket for `addr`, with room for `backlog` connections waiting to be
+/// accepted. If `device` is given, the socket only accepts connections that arrive on that
+/// network interface.
+pub fn bind(addr: SocketAddr, device: Option<&str>, backlog: i32) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+
+    if let Some(device) = device {
+        bind_device(&socket, device)?;
+    }
+
+    socket.bind(&addr.into())?;
+    socket.listen(backlog)?;
+    socket.set_nonblocking(true)?;
+
+    Ok(socket.into())
+}
+
+#[cfg(target_os = "linux")]
+fn bind_device(socket: &Socket, device: &str) -> io::Result<()> {
+    socket.bind_device(Some(device.as_bytes())).map_err(|e| {
+        io::Error::new(e.kind(), format!("Failed to bind to device {:?}: {}", device, e))
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_device(_socket: &Socket, _device: &str) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "--bind-device is only supported on Linux"))
+}
+
+/// Accept connections from `listener`, setting `TCP_NODELAY` on each if `nodelay`.
+/// Must be called within the Tokio runtime.
+pub fn incoming(listener: TcpListener, nodelay: bool) -> Result<AddrIncoming, Box<dyn std::error::Error + Send + Sync>> {
+    let mut incoming = AddrIncoming::from_listener(tokio::net::TcpListener::from_std(listener)?)?;
+    incoming.set_nodelay(nodelay);
+    Ok(incoming)
+}
+
+/// Timeouts for inbound client connections, which are closed when one passes
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ClientTimeouts {
+    /// How long a connection may go without reading or writing anything while no request on it
+    /// is being handled, such as between keep-alive requests or while a client doesn't read the
+    /// response
+    pub idle: Option<Duration>,
+    /// How long a request's headers may take to arrive, from when the connection is accepted or
+    /// the first byte of a later request is read
+    pub header_read: Option<Duration>,
+}
+
+/// The number of requests on a connection whose responses are being prepared, during which it
+/// isn't idle however long S3 or the upstream server take
+#[derive(Clone, Default)]
+pub struct ActiveRequests(Arc<AtomicUsize>);
+
+/// Counted in `ActiveRequests` until dropped
+pub struct ActiveRequest(Arc<AtomicUsize>);
+
+impl ActiveRequests {
+    pub fn begin(&self) -> ActiveRequest {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        ActiveRequest(self.0.clone())
+    }
+
+    fn busy(&self) -> bool {
+        self.0.load(Ordering::SeqCst) > 0
+    }
+}
+
+impl Drop for ActiveRequest {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Accepts connections from `AddrIncoming`, applying `ClientTimeouts` to each
+pub struct TimeoutIncoming {
+    inner: AddrIncoming,
+    timeouts: ClientTimeouts,
+}
+
+impl TimeoutIncoming {
+    pub fn new(inner: AddrIncoming, timeouts: ClientTimeouts) -> TimeoutIncoming {
+        TimeoutIncoming { inner, timeouts }
+    }
+}
+
+impl Accept for TimeoutIncoming {
+    type Conn = TimeoutStream;
+    type Error = io::Error;
+
+    fn poll_accept(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<io::Result<TimeoutStream>>> {
+        let timeouts = self.timeouts;
+        Pin::new(&mut self.inner).poll_accept(cx).map_ok(|conn| TimeoutStream::new(conn, timeouts))
+    }
+}
+
+/// A client connection that fails its reads and writes once one of its `ClientTimeouts` passes,
+/// so that hyper closes it
+pub struct TimeoutStream {
+    inner: AddrStream,
+    timeouts: ClientTimeouts,
+    active: ActiveRequests,
+    idle_deadline: Option<Pin<Box<Sleep>>>,
+    header_deadline: Option<Pin<Box<Sleep>>>,
+}
+
+impl TimeoutStream {
+    fn new(inner: AddrStream, timeouts: ClientTimeouts) -> TimeoutStream {
+        TimeoutStream {
+            inner,
+            timeouts,
+            active: ActiveRequests::default(),
+            idle_deadline: None,
+            header_deadline: timeouts.header_read.map(|t| Box::pin(sleep(t))),
+        }
+    }
+
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.inner.remote_addr()
+    }
+
+    /// To be held by the service for each request it handles on this connection
+    pub fn active_requests(&self) -> ActiveRequests {
+        self.active.clone()
+    }
+
+    /// Update the deadlines after an I/O operation, which made progress if `progress` and read
+    /// some of a request if `read`, and fail if one has passed
+    fn check_deadlines(&mut self, cx: &mut Context<'_>, progress: bool, read: bool) -> io::Result<()> {
+        let busy = self.active.busy();
+
+        match self.timeouts.header_read {
+            // The headers have been read once the service is handling the request
+            _ if busy => self.header_deadline = None,
+            Some(timeout) if read && self.header_deadline.is_none() => self.header_deadline = Some(Box::pin(sleep(timeout))),
+            _ => {}
+        }
+
+        match (self.timeouts.idle, &mut self.idle_deadline) {
+            _ if busy => self.idle_deadline = None,
+            (Some(timeout), Some(deadline)) if progress => deadline.as_mut().reset(Instant::now() + timeout),
+            (Some(timeout), None) => self.idle_deadline = Some(Box::pin(sleep(timeout))),
+            _ => {}
+        }
+
+        let mut expired = |deadline: &mut Option<Pin<Box<Sleep>>>| deadline.as_mut().is_some_and(|d| d.as_mut().poll(cx).is_ready());
+        if expired(&mut self.header_deadline) {
+            log::info!("Closing connection from {}: request headers not received in time", self.remote_addr());
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "request header read timeout"));
+        }
+        if expired(&mut self.idle_deadline) {
+            log::info!("Closing idle connection from {}", self.remote_addr());
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "idle timeout"));
+        }
+        Ok(())
+    }
+}
+
+impl AsyncRead for TimeoutStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = buf.filled().len() > filled;
+        self.check_deadlines(cx, res.is_ready(), read)?;
+        res
+    }
+}
+
+impl AsyncWrite for TimeoutStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+        self.check_deadlines(cx, res.is_ready(), false)?;
+        res
+    }
+
+    fn poll_write_vectored(mut self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[io::IoSlice<'_>]) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_write_vectored(cx, bufs);
+        self.check_deadlines(cx, res.is_ready(), false)?;
+        res
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let res = Pin::new(&mut self.inner).poll_flush(cx);
+        self.check_deadlines(cx, res.is_ready(), false)?;
+        res
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_bind_device() {
+    let listener = bind("127.0.0.1:0".parse().unwrap(), Some("lo"), 16).unwrap();
+    std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+    let err = bind("127.0.0.1:0".parse().unwrap(), Some("nonexistent0"), 16).unwrap_err();
+    assert!(err.to_string().contains("nonexistent0"));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_backlog() {
+    let listener = bind("127.0.0.1:0".parse().unwrap(), None, 77).unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    // For listening sockets, `ss` reports the backlog as Send-Q
+    let ss = std::process::Command::new("ss").arg("-ltnH").arg(format!("sport = :{}", port)).output().unwrap();
+    let columns: Vec<String> = String::from_utf8(ss.stdout).unwrap().split_whitespace().map(String::from).collect();
+    assert_eq!(columns[0], "LISTEN");
+    assert_eq!(columns[2], "77");
+}
+
+#[tokio::test]
+async fn test_nodelay() {
+    use hyper::server::accept::Accept;
+    use std::pin::Pin;
+
+    for &nodelay in &[true, false] {
+        let mut incoming = incoming(bind("127.0.0.1:0".parse().unwrap(), None, 16).unwrap(), nodelay).unwrap();
+        let _client = std::net::TcpStream::connect(incoming.local_addr()).unwrap();
+
+        let conn = futures::future::poll_fn(|cx| Pin::new(&mut incoming).poll_accept(cx)).await.unwrap().unwrap();
+        assert_eq!(conn.into_inner().nodelay().unwrap(), nodelay);
+    }
+}
+
+#[tokio::test]
+async fn test_client_timeouts() {
+    use hyper::{ Body, Response, Server };
+    use hyper::service::{ make_service_fn, service_fn };
+    use tokio::io::{ AsyncReadExt, AsyncWriteExt };
+    use tokio::net::TcpStream;
+
+    let timeouts = ClientTimeouts { idle: Some(Duration::from_millis(200)), header_read: Some(Duration::from_millis(400)) };
+    let incoming = TimeoutIncoming::new(incoming(bind("127.0.0.1:0".parse().unwrap(), None, 16).unwrap(), false).unwrap(), timeouts);
+    let addr = incoming.inner.local_addr();
+
+    let server = Server::builder(incoming).serve(make_service_fn(|conn: &TimeoutStream| {
+        let active = conn.active_requests();
+        async move {
+            Ok::<_, std::convert::Infallible>(service_fn(move |_req| {
+                let request = active.begin();
+                async move {
+                    let _request = request;
+                    // Longer than the idle timeout, which doesn't apply while a request is handled
+                    sleep(Duration::from_millis(500)).await;
+                    Ok::<_, std::convert::Infallible>(Response::new(Body::from("ok")))
+                }
+            }))
+        }
+    }));
+    tokio::spawn(server);
+
+    // Read until the server closes the connection, returning what was read and when
+    async fn read_to_close(conn: &mut TcpStream) -> (String, Duration) {
+        let start = Instant::now();
+        let mut buf = Vec::new();
+        conn.read_to_end(&mut buf).await.unwrap();
+        (String::from_utf8(buf).unwrap(), start.elapsed())
+    }
+
+    // Idle without sending anything
+    let mut conn = TcpStream::connect(addr).await.unwrap();
+    let (response, elapsed) = read_to_close(&mut conn).await;
+    assert_eq!(response, "");
+    assert!(elapsed >= Duration::from_millis(150) && elapsed < Duration::from_secs(2), "{:?}", elapsed);
+
+    // Sending the headers slowly, a byte at a time, keeps it from being idle but not from the
+    // header timeout
+    let mut conn = TcpStream::connect(addr).await.unwrap();
+    let start = Instant::now();
+    for byte in b"GET / HTTP/1.1\r\nHost: localhost\r\nX-Slow: ".iter().cycle() {
+        if conn.write_all(&[*byte]).await.is_err() || start.elapsed() > Duration::from_secs(2) {
+            break;
+        }
+        sleep(Duration::from_millis(50)).await;
+    }
+    assert!(start.elapsed() < Duration::from_secs(2), "{:?}", start.elapsed());
+
+    // A request that takes longer than the idle timeout is answered, then the connection is
+    // closed once idle
+    let mut conn = TcpStream::connect(addr).await.unwrap();
+    conn.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+    let (response, elapsed) = read_to_close(&mut conn).await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    assert!(response.ends_with("ok"), "{}", response);
+    assert!(elapsed >= Duration::from_millis(650), "{:?}", elapsed);
+}
@@ -0,0 +1,133 @@
+// © 2019 3D Robotics. License: Apache-2.0
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use bytes::Bytes;
+use futures::TryStreamExt;
+use hyper::StatusCode;
+use rusoto_core::RusotoError;
+use rusoto_s3::{ GetObjectRequest, GetObjectError };
+use crate::redact::{ s3_url, Redacted };
+use crate::s3_regions::MultiRegionS3Client;
+
+/// Where manifests are read from when not proxying to an upstream server.
+///
+/// The request path selects the manifest: `GET /foo/bar` reads `<dir>/foo/bar.json` or
+/// `s3://bucket/<prefix>/foo/bar.json`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ManifestSource {
+    Directory(PathBuf),
+    S3 { bucket: String, prefix: String },
+}
+
+impl fmt::Display for ManifestSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ManifestSource::Directory(dir) => write!(f, "{}", dir.display()),
+            ManifestSource::S3 { bucket, prefix } => write!(f, "s3://{}/{}", bucket, prefix),
+        }
+    }
+}
+
+impl FromStr for ManifestSource {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.strip_prefix("s3://") {
+            Some(rest) => {
+                let mut parts = rest.splitn(2, '/');
+                ManifestSource::S3 {
+                    bucket: parts.next().unwrap().to_owned(),
+                    prefix: parts.next().unwrap_or("").trim_end_matches('/').to_owned(),
+                }
+            }
+            None => ManifestSource::Directory(s.into()),
+        })
+    }
+}
+
+/// Validate a request path (after prefix stripping) and return its segments
+fn path_segments(path: &str) -> Result<Vec<&str>, (StatusCode, &'static str)> {
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+
+    if segments.is_empty() || segments.iter().any(|&s| s == "." || s == "..") {
+        return Err((StatusCode::BAD_REQUEST, "Invalid path"));
+    }
+
+    Ok(segments)
+}
+
+impl ManifestSource {
+    /// S3 key of the manifest for a request path
+    fn s3_key(prefix: &str, segments: &[&str]) -> String {
+        let path = segments.join("/");
+        if prefix.is_empty() { format!("{}.json", path) } else { format!("{}/{}.json", prefix, path) }
+    }
+
+    /// Read the manifest for a request path
+    pub async fn fetch(&self, s3: &MultiRegionS3Client, path: &str) -> Result<Bytes, (StatusCode, &'static str)> {
+        let segments = path_segments(path)?;
+
+        match self {
+            ManifestSource::Directory(dir) => {
+                let mut file = dir.join(segments.join("/"));
+                file.set_extension("json");
+
+                tokio::fs::read(&file).await.map(Bytes::from).map_err(|e| {
+                    if e.kind() == std::io::ErrorKind::NotFound {
+                        log::info!("Manifest {} not found", Redacted(file.display()));
+                        (StatusCode::NOT_FOUND, "Not found")
+                    } else {
+                        log::error!("Failed to read manifest {}: {}", Redacted(file.display()), e);
+                        (StatusCode::INTERNAL_SERVER_ERROR, "Failed to read manifest")
+                    }
+                })
+            }
+            ManifestSource::S3 { bucket, prefix } => {
+                let key = ManifestSource::s3_key(prefix, &segments);
+                let req = GetObjectRequest {
+                    bucket: bucket.clone(),
+                    key: key.clone(),
+                    ..GetObjectRequest::default()
+                };
+
+                let res = s3.for_bucket(bucket).await.get_object(req).await.map_err(|e| match e {
+                    RusotoError::Service(GetObjectError::NoSuchKey(_)) => {
+                        log::info!("Manifest {} not found", s3_url(bucket, &key));
+                        (StatusCode::NOT_FOUND, "Not found")
+                    }
+                    e => {
+                        log::error!("Failed to fetch manifest {}: {}", s3_url(bucket, &key), e);
+                        (StatusCode::SERVICE_UNAVAILABLE, "Failed to fetch manifest")
+                    }
+                })?;
+
+                let body = res.body.ok_or((StatusCode::SERVICE_UNAVAILABLE, "Failed to fetch manifest"))?;
+                let body = body.map_ok(|b| b.to_vec()).try_concat().await.map_err(|e| {
+                    log::error!("Failed to read manifest {}: {}", s3_url(bucket, &key), e);
+                    (StatusCode::SERVICE_UNAVAILABLE, "Failed to fetch manifest")
+                })?;
+
+                Ok(body.into())
+            }
+        }
+    }
+}
+
+#[test]
+fn test_parse_manifest_source() {
+    assert_eq!("/srv/manifests".parse::<ManifestSource>().unwrap(), ManifestSource::Directory("/srv/manifests".into()));
+    assert_eq!("s3://bucket/prefix/".parse::<ManifestSource>().unwrap(), ManifestSource::S3 { bucket: "bucket".into(), prefix: "prefix".into() });
+    assert_eq!("s3://bucket".parse::<ManifestSource>().unwrap(), ManifestSource::S3 { bucket: "bucket".into(), prefix: "".into() });
+}
+
+#[test]
+fn test_s3_manifest_key() {
+    let key = |prefix, path| ManifestSource::s3_key(prefix, &path_segments(path).unwrap());
+    assert_eq!(key("prefix", "/foo/bar"), "prefix/foo/bar.json");
+    assert_eq!(key("", "/foo/bar"), "foo/bar.json");
+    assert_eq!(key("a/b", "//foo"), "a/b/foo.json");
+
+    assert_eq!(path_segments("/foo/../../secret"), Err((StatusCode::BAD_REQUEST, "Invalid path")));
+    assert_eq!(path_segments("/"), Err((StatusCode::BAD_REQUEST, "Invalid path")));
+}
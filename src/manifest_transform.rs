@@ -0,0 +1,167 @@
+// © 2019 3D Robotics. License: Apache-2.0
+use std::sync::Arc;
+use futures::future::{ self, BoxFuture };
+use crate::upstream::ManifestEntry;
+
+/// Rewrites the entries of every manifest before they are resolved, for example to point at
+/// another environment's S3 keys or to restrict which objects can be downloaded. Transforms are
+/// registered at startup in `Config::manifest_transforms` and applied in order.
+pub trait ManifestTransform: Send + Sync {
+    fn transform(&self, entries: Vec<ManifestEntry>) -> BoxFuture<'_, Vec<ManifestEntry>>;
+
+    /// Whether to keep an object listed by a wildcard entry, given its name in the archive.
+    /// Wildcard entries pass through `transform` before they are expanded, so a transform that
+    /// filters by name also needs to filter here.
+    fn keep_expanded(&self, _archive_name: &str) -> bool {
+        true
+    }
+}
+
+/// Apply each of `transforms` in turn to `entries`
+pub async fn apply(transforms: &[Arc<dyn ManifestTransform>], mut entries: Vec<ManifestEntry>) -> Vec<ManifestEntry> {
+    for transform in transforms {
+        entries = transform.transform(entries).await;
+    }
+    entries
+}
+
+/// Whether all of `transforms` keep an object named `archive_name` expanded from a wildcard entry
+pub fn keep_expanded(transforms: &[Arc<dyn ManifestTransform>], archive_name: &str) -> bool {
+    transforms.iter().all(|transform| transform.keep_expanded(archive_name))
+}
+
+/// The keys of the S3 objects an entry is read from
+fn source_keys(entry: &mut ManifestEntry) -> Vec<&mut String> {
+    match entry {
+        ManifestEntry::File(file) => vec![&mut file.source.key],
+        ManifestEntry::Parts(parts) => parts.parts.iter_mut().map(|part| &mut part.source.key).collect(),
+        ManifestEntry::Wildcard(wildcard) => vec![&mut wildcard.source.key],
+        ManifestEntry::Inline(_) | ManifestEntry::Symlink(_) => Vec::new(),
+    }
+}
+
+/// Remove a prefix from the S3 key of entries whose key starts with it
+pub struct StripPathPrefix(pub String);
+
+impl ManifestTransform for StripPathPrefix {
+    fn transform(&self, mut entries: Vec<ManifestEntry>) -> BoxFuture<'_, Vec<ManifestEntry>> {
+        for key in entries.iter_mut().flat_map(source_keys) {
+            if let Some(rest) = key.strip_prefix(&self.0[..]) {
+                *key = rest.to_owned();
+            }
+        }
+        Box::pin(future::ready(entries))
+    }
+}
+
+/// Prepend a prefix to the S3 key of every entry read from S3
+pub struct AddPathPrefix(pub String);
+
+impl ManifestTransform for AddPathPrefix {
+    fn transform(&self, mut entries: Vec<ManifestEntry>) -> BoxFuture<'_, Vec<ManifestEntry>> {
+        for key in entries.iter_mut().flat_map(source_keys) {
+            key.insert_str(0, &self.0);
+        }
+        Box::pin(future::ready(entries))
+    }
+}
+
+/// Keep only entries whose archive name has one of these extensions, compared without case.
+/// Wildcard entries are kept, and the objects they expand to are filtered by `keep_expanded`.
+pub struct FilterByExtension(pub Vec<String>);
+
+impl FilterByExtension {
+    fn matches(&self, name: &str) -> bool {
+        let name = name.to_lowercase();
+        self.0.iter().any(|ext| name.ends_with(&format!(".{}", ext.trim_start_matches('.').to_lowercase())))
+    }
+}
+
+impl ManifestTransform for FilterByExtension {
+    fn transform(&self, mut entries: Vec<ManifestEntry>) -> BoxFuture<'_, Vec<ManifestEntry>> {
+        entries.retain(|entry| match entry {
+            ManifestEntry::File(file) => self.matches(&file.archive_name),
+            ManifestEntry::Parts(parts) => self.matches(&parts.archive_name),
+            ManifestEntry::Inline(inline) => self.matches(&inline.archive_name),
+            ManifestEntry::Symlink(link) => self.matches(&link.archive_name),
+            ManifestEntry::Wildcard(_) => true,
+        });
+        Box::pin(future::ready(entries))
+    }
+
+    fn keep_expanded(&self, archive_name: &str) -> bool {
+        self.matches(archive_name)
+    }
+}
+
+/// Add an entry to the end of every manifest
+pub struct AppendStaticEntry(pub ManifestEntry);
+
+impl ManifestTransform for AppendStaticEntry {
+    fn transform(&self, mut entries: Vec<ManifestEntry>) -> BoxFuture<'_, Vec<ManifestEntry>> {
+        entries.push(self.0.clone());
+        Box::pin(future::ready(entries))
+    }
+}
+
+/// The built-in transforms enabled by command line options, in the order they are applied:
+/// `--manifest-strip-key-prefix`, `--manifest-add-key-prefix`, `--manifest-include-extensions`,
+/// then each `--manifest-append-entry`
+pub fn builtin<'a>(
+    strip_prefix: Option<&str>,
+    add_prefix: Option<&str>,
+    extensions: Option<&str>,
+    append: impl IntoIterator<Item = &'a str>,
+) -> Result<Vec<Arc<dyn ManifestTransform>>, serde_json::Error> {
+    let mut transforms: Vec<Arc<dyn ManifestTransform>> = Vec::new();
+    if let Some(prefix) = strip_prefix {
+        transforms.push(Arc::new(StripPathPrefix(prefix.to_owned())));
+    }
+    if let Some(prefix) = add_prefix {
+        transforms.push(Arc::new(AddPathPrefix(prefix.to_owned())));
+    }
+    if let Some(extensions) = extensions {
+        let extensions = extensions.split(',').map(|v| v.trim().to_owned()).filter(|v| !v.is_empty()).collect();
+        transforms.push(Arc::new(FilterByExtension(extensions)));
+    }
+    for entry in append {
+        transforms.push(Arc::new(AppendStaticEntry(serde_json::from_str(entry)?)));
+    }
+    Ok(transforms)
+}
+
+#[tokio::test]
+async fn test_builtin_transforms() {
+    let entries: Vec<ManifestEntry> = serde_json::from_str(r#"[
+        { "archive_name": "a.txt", "source": "s3://bucket/prod/a.txt", "length": 1, "crc": 1, "last_modified": "2021-01-01T00:00:00Z" },
+        { "archive_name": "b.CSV", "source": "s3://bucket/other/b.csv", "length": 1, "crc": 1, "last_modified": "2021-01-01T00:00:00Z" },
+        { "archive_name": "logs/", "source": "s3://bucket/prod/logs/*" },
+        { "archive_name": "big.txt", "parts": [{ "source": "s3://bucket/prod/big.000", "length": 1 }], "last_modified": "2021-01-01T00:00:00Z" },
+        { "name": "c.tmp", "content_base64": "" }
+    ]"#).unwrap();
+
+    let transforms = builtin(Some("prod/"), Some("staging/"), Some("txt, .csv"), vec![r#"{ "name": "README.txt", "content_base64": "" }"#]).unwrap();
+    let entries = apply(&transforms, entries).await;
+
+    let summary: Vec<_> = entries.iter().map(|entry| match entry {
+        ManifestEntry::File(file) => format!("{} {}", file.archive_name, file.source),
+        ManifestEntry::Parts(parts) => format!("{} {}", parts.archive_name, parts.parts[0].source),
+        ManifestEntry::Wildcard(wildcard) => format!("{} {}", wildcard.archive_name, wildcard.source),
+        ManifestEntry::Inline(inline) => inline.archive_name.clone(),
+        ManifestEntry::Symlink(link) => link.archive_name.clone(),
+    }).collect();
+    assert_eq!(summary, vec![
+        "a.txt s3://bucket/staging/a.txt",
+        "b.CSV s3://bucket/staging/other/b.csv",
+        "logs/ s3://bucket/staging/logs/*",
+        "big.txt s3://bucket/staging/big.000",
+        "README.txt",
+    ]);
+
+    assert!(keep_expanded(&transforms, "logs/today.TXT"));
+    assert!(!keep_expanded(&transforms, "logs/today.gz"));
+    assert!(!keep_expanded(&transforms, "logs/txt"));
+    assert!(keep_expanded(&[], "logs/today.gz"));
+
+    assert!(builtin(None, None, None, vec!["{}"]).is_err());
+}
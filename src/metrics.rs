@@ -0,0 +1,129 @@
+// © 2019 3D Robotics. License: Apache-2.0
+use std::time::Duration;
+use prometheus::{ Encoder, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry, TextEncoder };
+use rusoto_core::RusotoError;
+use hyper::StatusCode;
+use crate::redact::RedactedIf;
+
+/// Bucket boundaries of `s3_object_size_bytes`: 1 KB to 1 GB in powers of ten
+const SIZE_BUCKETS: &[f64] = &[1e3, 1e4, 1e5, 1e6, 1e7, 1e8, 1e9];
+
+/// Bucket boundaries of `s3_fetch_latency_seconds`
+const LATENCY_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+/// Prometheus metrics served by `/metrics`, for S3 reads of archive data
+pub struct Metrics {
+    registry: Registry,
+    s3_object_size: HistogramVec,
+    s3_fetch_latency: HistogramVec,
+    s3_errors: IntCounterVec,
+    redact_keys: bool,
+}
+
+impl Metrics {
+    /// With `redact_keys`, as for `--redact-keys`, key prefixes in labels are replaced by a hash
+    pub fn new(redact_keys: bool) -> Metrics {
+        let s3_object_size = HistogramVec::new(
+            HistogramOpts::new("s3_object_size_bytes", "Size of S3 objects read for archives").buckets(SIZE_BUCKETS.to_vec()),
+            &["bucket"],
+        ).unwrap();
+        let s3_fetch_latency = HistogramVec::new(
+            HistogramOpts::new("s3_fetch_latency_seconds", "Time until S3 responds to a GetObject for archive data").buckets(LATENCY_BUCKETS.to_vec()),
+            &["bucket", "prefix"],
+        ).unwrap();
+        let s3_errors = IntCounterVec::new(
+            Opts::new("s3_error_total", "Failed S3 reads of archive data"),
+            &["bucket", "error"],
+        ).unwrap();
+
+        let registry = Registry::new();
+        registry.register(Box::new(s3_object_size.clone())).unwrap();
+        registry.register(Box::new(s3_fetch_latency.clone())).unwrap();
+        registry.register(Box::new(s3_errors.clone())).unwrap();
+
+        Metrics { registry, s3_object_size, s3_fetch_latency, s3_errors, redact_keys }
+    }
+
+    /// Record a GetObject of `key`, whose whole object is `size` bytes, that S3 answered after `latency`
+    pub fn observe_s3_fetch(&self, bucket: &str, key: &str, size: u64, latency: Duration) {
+        self.s3_object_size.with_label_values(&[bucket]).observe(size as f64);
+        let prefix = RedactedIf(s3_prefix(key), self.redact_keys).to_string();
+        self.s3_fetch_latency.with_label_values(&[bucket, &prefix]).observe(latency.as_secs_f64());
+    }
+
+    /// Count a failed S3 read, with `error` from `s3_error_type`
+    pub fn count_s3_error(&self, bucket: &str, error: &str) {
+        self.s3_errors.with_label_values(&[bucket, error]).inc();
+    }
+
+    /// The metrics in the Prometheus text format
+    pub fn render(&self) -> (String, Vec<u8>) {
+        let encoder = TextEncoder::new();
+        let mut buf = Vec::new();
+        encoder.encode(&self.registry.gather(), &mut buf).unwrap();
+        (encoder.format_type().to_owned(), buf)
+    }
+}
+
+/// The first two directories of an S3 key, such as `customers/alice` for
+/// `customers/alice/scans/1.jpg`, to group keys without a label for each object
+pub fn s3_prefix(key: &str) -> &str {
+    let dirs = key.rfind('/').map_or("", |i| &key[..i]);
+    match dirs.match_indices('/').nth(1) {
+        Some((i, _)) => &dirs[..i],
+        None => dirs,
+    }
+}
+
+/// A short, fixed name for the kind of a failed S3 request, for the `error` label
+pub fn s3_error_type<E>(err: &RusotoError<E>) -> &'static str {
+    match err {
+        RusotoError::Service(_) => "service",
+        RusotoError::HttpDispatch(_) => "dispatch",
+        RusotoError::Credentials(_) => "credentials",
+        RusotoError::Validation(_) => "validation",
+        RusotoError::ParseError(_) => "parse",
+        RusotoError::Unknown(res) if res.status == StatusCode::FORBIDDEN => "access_denied",
+        RusotoError::Unknown(res) if res.status == StatusCode::NOT_FOUND => "not_found",
+        RusotoError::Unknown(res) if res.status.is_server_error() => "server_error",
+        RusotoError::Unknown(_) => "unknown",
+        RusotoError::Blocking => "blocking",
+    }
+}
+
+#[test]
+fn test_s3_prefix() {
+    assert_eq!(s3_prefix("customers/alice/scans/1.jpg"), "customers/alice");
+    assert_eq!(s3_prefix("customers/alice/1.jpg"), "customers/alice");
+    assert_eq!(s3_prefix("customers/1.jpg"), "customers");
+    assert_eq!(s3_prefix("1.jpg"), "");
+}
+
+#[test]
+fn test_render() {
+    let metrics = Metrics::new(false);
+    metrics.observe_s3_fetch("bucket", "a/b/c/file", 5_000, Duration::from_millis(30));
+    metrics.observe_s3_fetch("bucket", "a/b/d/file", 2_000_000, Duration::from_millis(300));
+    metrics.count_s3_error("bucket", "access_denied");
+
+    let (content_type, body) = metrics.render();
+    let body = String::from_utf8(body).unwrap();
+    assert!(content_type.starts_with("text/plain"));
+    assert!(body.contains(r#"s3_object_size_bytes_bucket{bucket="bucket",le="10000"} 1"#), "{}", body);
+    assert!(body.contains(r#"s3_object_size_bytes_bucket{bucket="bucket",le="1000000000"} 2"#));
+    assert!(body.contains(r#"s3_fetch_latency_seconds_count{bucket="bucket",prefix="a/b"} 2"#));
+    assert!(body.contains(r#"s3_fetch_latency_seconds_bucket{bucket="bucket",prefix="a/b",le="0.05"} 1"#));
+    assert!(body.contains(r#"s3_error_total{bucket="bucket",error="access_denied"} 1"#));
+}
+
+#[test]
+fn test_redacted_prefix() {
+    let metrics = Metrics::new(true);
+    metrics.observe_s3_fetch("bucket", "customers/alice/scans/1.jpg", 5_000, Duration::from_millis(30));
+
+    let body = String::from_utf8(metrics.render().1).unwrap();
+    assert!(!body.contains("alice"), "{}", body);
+    let prefix = RedactedIf("customers/alice", true).to_string();
+    assert!(prefix.starts_with("<redacted:"));
+    assert!(body.contains(&format!(r#"s3_fetch_latency_seconds_count{{bucket="bucket",prefix="{}"}} 1"#, prefix)), "{}", body);
+}
@@ -0,0 +1,79 @@
+// © 2019 3D Robotics. License: Apache-2.0
+use std::fmt;
+use std::str::FromStr;
+use encoding_rs::Encoding;
+
+/// Characters 0x80-0xFF of IBM code page 437
+const CP437_HIGH: &str = "ÇüéâäàåçêëèïîìÄÅÉæÆôöòûùÿÖÜ¢£¥₧ƒáíóúñÑªº¿⌐¬½¼¡«»░▒▓│┤╡╢╖╕╣║╗╝╜╛┐\
+                          └┴┬├─┼╞╟╚╔╩╦╠═╬╧╨╤╥╙╘╒╓╫╪┘┌█▄▌▐▀αßΓπΣσµτΦΘΩδ∞φε∩≡±≥≤⌠⌡÷≈°∙·√ⁿ²■\u{a0}";
+
+/// A legacy code page for zip entry names, for readers that ignore the UTF-8 flag.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NameEncoding {
+    /// The original IBM PC code page, assumed by the zip specification
+    Cp437,
+
+    /// Any other encoding known to `encoding_rs`, such as Shift_JIS or windows-1252
+    Other(&'static Encoding),
+}
+
+impl fmt::Display for NameEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NameEncoding::Cp437 => write!(f, "cp437"),
+            NameEncoding::Other(encoding) => write!(f, "{}", encoding.name()),
+        }
+    }
+}
+
+impl FromStr for NameEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "cp437" | "ibm437" | "437" => return Ok(NameEncoding::Cp437),
+            _ => {}
+        }
+
+        // UTF-16 encoders produce UTF-8, and UTF-8 itself isn't a legacy encoding
+        match Encoding::for_label(s.as_bytes()) {
+            Some(encoding) if encoding.output_encoding() == encoding && encoding != encoding_rs::UTF_8 => Ok(NameEncoding::Other(encoding)),
+            _ => Err(format!("Unsupported entry name encoding {:?}", s)),
+        }
+    }
+}
+
+impl NameEncoding {
+    /// Encode a name, or return `None` if it contains characters not in the code page.
+    pub fn encode(&self, name: &str) -> Option<Vec<u8>> {
+        match self {
+            NameEncoding::Cp437 => name.chars().map(|c| {
+                if c.is_ascii() {
+                    Some(c as u8)
+                } else {
+                    CP437_HIGH.chars().position(|h| h == c).map(|i| 0x80 + i as u8)
+                }
+            }).collect(),
+            NameEncoding::Other(encoding) => {
+                let (bytes, _, had_errors) = encoding.encode(name);
+                if had_errors { None } else { Some(bytes.into_owned()) }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_name_encoding() {
+    assert_eq!(CP437_HIGH.chars().count(), 128);
+
+    let cp437: NameEncoding = "CP437".parse().unwrap();
+    assert_eq!(cp437.encode("Über ½.txt"), Some(b"\x9Aber \xAB.txt".to_vec()));
+    assert_eq!(cp437.encode("日本.txt"), None);
+
+    let sjis: NameEncoding = "shift_jis".parse().unwrap();
+    assert_eq!(sjis.encode("日本.txt"), Some(b"\x93\xFA\x96\x7B.txt".to_vec()));
+
+    assert!("utf-16le".parse::<NameEncoding>().is_err());
+    assert!("utf-8".parse::<NameEncoding>().is_err());
+    assert!("klingon".parse::<NameEncoding>().is_err());
+}
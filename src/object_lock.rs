@@ -0,0 +1,118 @@
+// © 2019 3D Robotics. License: Apache-2.0
+use std::fmt;
+use futures::{ StreamExt, TryStreamExt };
+use hyper::StatusCode;
+use rusoto_core::RusotoError;
+use rusoto_s3::{ HeadObjectError, HeadObjectOutput, HeadObjectRequest };
+use crate::redact::s3_url;
+use crate::s3_regions::MultiRegionS3Client;
+use crate::s3url::S3Url;
+
+/// Number of HeadObject requests in flight at once for one listing
+const HEAD_CONCURRENCY: usize = 8;
+
+/// S3 Object Lock status of an object. Objects under retention or a legal hold can be read as
+/// usual, but not deleted or overwritten, which audit tools may want to confirm.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ObjectLock {
+    /// `GOVERNANCE` or `COMPLIANCE` if the object has a retention period
+    pub mode: Option<String>,
+    /// End of the retention period
+    pub retain_until: Option<String>,
+    /// `ON` if the object has a legal hold
+    pub legal_hold: Option<String>,
+}
+
+/// Why a HeadObject request failed
+#[derive(Debug)]
+pub enum HeadObjectFailure {
+    NotFound,
+    AccessDenied,
+    Other(RusotoError<HeadObjectError>),
+}
+
+impl fmt::Display for HeadObjectFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeadObjectFailure::NotFound => f.write_str("not found"),
+            HeadObjectFailure::AccessDenied => f.write_str("access denied"),
+            HeadObjectFailure::Other(e) => e.fmt(f),
+        }
+    }
+}
+
+impl From<RusotoError<HeadObjectError>> for HeadObjectFailure {
+    fn from(e: RusotoError<HeadObjectError>) -> Self {
+        match e {
+            RusotoError::Service(HeadObjectError::NoSuchKey(_)) => HeadObjectFailure::NotFound,
+            // HEAD responses have no body to parse the error code from
+            RusotoError::Unknown(res) if res.status == StatusCode::NOT_FOUND => HeadObjectFailure::NotFound,
+            RusotoError::Unknown(res) if res.status == StatusCode::FORBIDDEN => HeadObjectFailure::AccessDenied,
+            e => HeadObjectFailure::Other(e),
+        }
+    }
+}
+
+/// Send HeadObject for an object, which returns the same metadata as GetObject without the body
+pub async fn head_object(s3: &MultiRegionS3Client, url: &S3Url) -> Result<HeadObjectOutput, HeadObjectFailure> {
+    let req = HeadObjectRequest {
+        bucket: url.bucket.clone(),
+        key: url.key.clone(),
+        ..HeadObjectRequest::default()
+    };
+
+    Ok(s3.for_bucket(&url.bucket).await.head_object(req).await?)
+}
+
+#[test]
+fn test_head_object_failure() {
+    use rusoto_core::request::BufferedHttpResponse;
+
+    let response = |status: StatusCode| RusotoError::Unknown(BufferedHttpResponse {
+        status,
+        body: bytes::Bytes::new(),
+        headers: Default::default(),
+    });
+
+    let missing = RusotoError::Service(HeadObjectError::NoSuchKey("The specified key does not exist.".into()));
+    assert!(matches!(HeadObjectFailure::from(missing), HeadObjectFailure::NotFound));
+    assert!(matches!(HeadObjectFailure::from(response(StatusCode::NOT_FOUND)), HeadObjectFailure::NotFound));
+    assert!(matches!(HeadObjectFailure::from(response(StatusCode::FORBIDDEN)), HeadObjectFailure::AccessDenied));
+    assert!(matches!(HeadObjectFailure::from(response(StatusCode::SERVICE_UNAVAILABLE)), HeadObjectFailure::Other(_)));
+    assert_eq!(HeadObjectFailure::from(response(StatusCode::FORBIDDEN)).to_string(), "access denied");
+}
+
+/// Read the Object Lock status of an object with HeadObject
+async fn object_lock(s3: &MultiRegionS3Client, url: &S3Url) -> Result<ObjectLock, (StatusCode, &'static str)> {
+    match head_object(s3, url).await {
+        Ok(res) => Ok(ObjectLock {
+            mode: res.object_lock_mode,
+            retain_until: res.object_lock_retain_until_date,
+            legal_hold: res.object_lock_legal_hold_status,
+        }),
+        Err(HeadObjectFailure::NotFound) => {
+            log::error!("S3 object {} in manifest not found", s3_url(&url.bucket, &url.key));
+            Err((StatusCode::NOT_FOUND, "S3 object not found"))
+        }
+        Err(e) => {
+            log::error!("Failed to read Object Lock status of {}: {}", s3_url(&url.bucket, &url.key), e);
+            Err((StatusCode::SERVICE_UNAVAILABLE, "Failed to read S3 object"))
+        }
+    }
+}
+
+/// The Object Lock status of each S3 object in `sources`, preserving order. Entries that aren't
+/// from S3 have none.
+pub async fn object_locks(s3: &MultiRegionS3Client, sources: &[Option<S3Url>]) -> Result<Vec<Option<ObjectLock>>, (StatusCode, &'static str)> {
+    let reads: Vec<_> = sources.iter().map(|source| async move {
+        match source {
+            Some(url) => object_lock(s3, url).await.map(Some),
+            None => Ok(None),
+        }
+    }).collect();
+
+    futures::stream::iter(reads)
+        .buffered(HEAD_CONCURRENCY)
+        .try_collect()
+        .await
+}
@@ -0,0 +1,105 @@
+// © 2019 3D Robotics. License: Apache-2.0
+use std::fs;
+use std::io;
+use std::path::{ Path, PathBuf };
+
+/// A file containing the server's PID, removed when dropped.
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Write the current process ID to `path`.
+    ///
+    /// Fails if the file already names a running process. A file left behind by a process that
+    /// is no longer running is overwritten.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<PidFile> {
+        let path = path.as_ref();
+
+        match fs::read_to_string(path) {
+            Ok(contents) => {
+                if let Ok(pid) = contents.trim().parse::<i32>() {
+                    if process_running(pid) {
+                        return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                            format!("PID file {} refers to running process {}", path.display(), pid)));
+                    }
+                }
+                log::warn!("Replacing stale PID file {}", path.display());
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(describe(e, path, "read")),
+        }
+
+        fs::write(path, format!("{}\n", std::process::id())).map_err(|e| describe(e, path, "write"))?;
+
+        Ok(PidFile { path: path.to_owned() })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            log::error!("Failed to remove PID file {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+fn describe(e: io::Error, path: &Path, action: &str) -> io::Error {
+    let reason = if e.kind() == io::ErrorKind::PermissionDenied { "permission denied".to_string() } else { e.to_string() };
+    io::Error::new(e.kind(), format!("Failed to {} PID file {}: {}", action, path.display(), reason))
+}
+
+/// Check whether a process exists by sending it signal 0.
+#[cfg(unix)]
+fn process_running(pid: i32) -> bool {
+    if pid <= 0 {
+        return false;
+    }
+
+    // EPERM means the process exists but belongs to another user
+    let alive = unsafe { libc::kill(pid, 0) } == 0;
+    alive || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn process_running(_pid: i32) -> bool { false }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("zipstream-test-{}-{}.pid", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_create_and_remove() {
+        let path = temp_path("create");
+        let pid_file = PidFile::create(&path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), format!("{}\n", std::process::id()));
+        drop(pid_file);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_refuse_running() {
+        let path = temp_path("running");
+        fs::write(&path, format!("{}\n", std::process::id())).unwrap();
+        assert_eq!(PidFile::create(&path).err().unwrap().kind(), io::ErrorKind::AlreadyExists);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_replace_stale() {
+        let path = temp_path("stale");
+        fs::write(&path, format!("{}\n", i32::MAX)).unwrap();
+        let _pid_file = PidFile::create(&path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), format!("{}\n", std::process::id()));
+    }
+
+    #[test]
+    fn test_unwritable() {
+        let err = PidFile::create("/nonexistent-dir/zipstream.pid").err().unwrap();
+        assert!(err.to_string().contains("/nonexistent-dir/zipstream.pid"));
+    }
+}
@@ -0,0 +1,84 @@
+// © 2019 3D Robotics. License: Apache-2.0
+use std::future::Future;
+use futures::StreamExt;
+use hyper::{ header, Body, Response, StatusCode };
+use crate::object_lock::head_object;
+use crate::redact::s3_url;
+use crate::s3_regions::MultiRegionS3Client;
+use crate::s3url::S3Url;
+
+/// Number of HeadObject requests in flight at once for one preflight check
+const PREFLIGHT_CONCURRENCY: usize = 16;
+
+/// Describe a failed check of `url`
+fn problem(url: &S3Url, result: Result<(), String>) -> Option<String> {
+    result.err().map(|e| format!("{}: {}", s3_url(&url.bucket, &url.key), e))
+}
+
+/// Run `checks`, at most `PREFLIGHT_CONCURRENCY` at once, returning the problems found in order
+async fn run_checks(checks: Vec<impl Future<Output = Option<String>>>) -> Vec<String> {
+    let problems: Vec<Option<String>> = futures::stream::iter(checks)
+        .buffered(PREFLIGHT_CONCURRENCY)
+        .collect()
+        .await;
+    problems.into_iter().flatten().collect()
+}
+
+/// For `--strict-preflight`: check with HeadObject that every S3 object in an archive exists and
+/// is readable before any of it is sent, so that a missing object fails the request with an error
+/// status rather than cutting off a `200 OK` response partway. Returns the problems found.
+pub async fn preflight(s3: &MultiRegionS3Client, sources: &[S3Url]) -> Vec<String> {
+    log::info!("Preflight check of {} S3 objects", sources.len());
+    let checks: Vec<_> = sources.iter().map(|url| async move { problem(url, head_object(s3, url).await.map(drop).map_err(|e| e.to_string())) }).collect();
+    let problems = run_checks(checks).await;
+    for problem in &problems {
+        log::error!("Preflight check failed for {}", problem);
+    }
+    problems
+}
+
+/// `502 Bad Gateway` listing the problems found by `preflight`, one per line or as JSON
+pub fn preflight_response(problems: &[String], json: bool) -> Response<Body> {
+    let message = "S3 objects in manifest are unavailable";
+    let res = Response::builder()
+        .status(StatusCode::BAD_GATEWAY)
+        .header(header::CONNECTION, "close");
+
+    if json {
+        let body = serde_json::json!({ "code": StatusCode::BAD_GATEWAY.as_u16(), "message": message, "problems": problems });
+        res.header(header::CONTENT_TYPE, "application/json").body(body.to_string().into()).unwrap()
+    } else {
+        res.body(format!("{}:\n{}\n", message, problems.join("\n")).into()).unwrap()
+    }
+}
+
+#[tokio::test]
+async fn test_run_checks() {
+    use std::sync::atomic::{ AtomicUsize, Ordering };
+
+    let sources: Vec<S3Url> = (0..100).map(|i| S3Url { bucket: "bucket".into(), key: format!("file{}", i) }).collect();
+    let (active, max_active) = (AtomicUsize::new(0), AtomicUsize::new(0));
+
+    let checks: Vec<_> = sources.iter().map(|url| {
+        let (active, max_active) = (&active, &max_active);
+        async move {
+            max_active.fetch_max(active.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+            tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+            active.fetch_sub(1, Ordering::SeqCst);
+            problem(url, if url.key == "file42" { Err("not found".to_owned()) } else { Ok(()) })
+        }
+    }).collect();
+    let problems = run_checks(checks).await;
+
+    assert_eq!(problems, vec!["s3://bucket/file42: not found"]);
+    assert!(max_active.load(Ordering::SeqCst) <= PREFLIGHT_CONCURRENCY);
+    assert!(max_active.load(Ordering::SeqCst) > 1);
+
+    let res = preflight_response(&problems, false);
+    assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+    assert_eq!(hyper::body::to_bytes(res.into_body()).await.unwrap(), "S3 objects in manifest are unavailable:\ns3://bucket/file42: not found\n");
+
+    let res = preflight_response(&problems, true);
+    let body: serde_json::Value = serde_json::from_slice(&hyper::body::to_bytes(res.into_body()).await.unwrap()).unwrap();
+    assert_eq!(body["problems"], serde_json::json!(["s3://bucket/file42: not found"]));
+}
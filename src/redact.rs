@@ -0,0 +1,57 @@
+// © 2019 3D Robotics. License: Apache-2.0
+use std::fmt;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use sha2::{ Digest, Sha256 };
+
+/// Set by `--redact-keys`. Global like the log level, because it applies to every log line.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Displays an S3 key or request URL in log messages, or if `--redact-keys` is set, a short hash
+/// of it. The hash is the same for the same value, so that log lines can still be correlated.
+pub struct Redacted<T>(pub T);
+
+impl<T: fmt::Display> fmt::Display for Redacted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        RedactedIf(&self.0, ENABLED.load(Ordering::Relaxed)).fmt(f)
+    }
+}
+
+/// `Redacted`, but redacted if the flag is set rather than `--redact-keys`, for values that outlive
+/// a log line or code that is passed the setting
+pub struct RedactedIf<T>(pub T, pub bool);
+
+impl<T: fmt::Display> fmt::Display for RedactedIf<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.1 {
+            let hash = Sha256::digest(self.0.to_string().as_bytes());
+            write!(f, "<redacted:{:02x}{:02x}{:02x}{:02x}>", hash[0], hash[1], hash[2], hash[3])
+        } else {
+            self.0.fmt(f)
+        }
+    }
+}
+
+/// `s3://bucket/key`, with the key redacted if enabled. Bucket names are part of the deployment's
+/// configuration rather than user data, so they're kept.
+pub fn s3_url(bucket: &str, key: &str) -> String {
+    format!("s3://{}/{}", bucket, Redacted(key))
+}
+
+#[test]
+fn test_redacted() {
+    assert_eq!(RedactedIf("customers/alice/scan.jpg", false).to_string(), "customers/alice/scan.jpg");
+
+    let redacted = RedactedIf("customers/alice/scan.jpg", true).to_string();
+    assert!(redacted.starts_with("<redacted:") && !redacted.contains("alice"), "{}", redacted);
+    assert_eq!(redacted.len(), "<redacted:>".len() + 8);
+    assert_eq!(redacted, RedactedIf("customers/alice/scan.jpg", true).to_string());
+    assert_ne!(redacted, RedactedIf("customers/bob/scan.jpg", true).to_string());
+
+    // `--redact-keys` is never set by tests, so these show the keys
+    assert_eq!(Redacted("customers/alice/scan.jpg").to_string(), "customers/alice/scan.jpg");
+    assert_eq!(s3_url("bucket", "customers/alice/scan.jpg"), "s3://bucket/customers/alice/scan.jpg");
+}
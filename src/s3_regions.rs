@@ -0,0 +1,190 @@
+// © 2019 3D Robotics. License: Apache-2.0
+use std::collections::HashMap;
+use std::sync::{ Arc, Mutex };
+use std::time::{ Duration, Instant };
+use rusoto_core::Region;
+use rusoto_s3::{ S3, GetBucketLocationRequest };
+
+pub type S3Arc = Arc<dyn S3 + Send + Sync>;
+
+/// S3 clients for each region containing buckets named in manifests. A client only reaches
+/// buckets in the region it signs requests for, so each bucket's region is looked up with
+/// `GetBucketLocation`, and a client is created for each region as it is first needed.
+///
+/// Only the bucket lookup is added here; callers use the `S3` trait on the client for a bucket.
+pub struct MultiRegionS3Client {
+    default_region: Region,
+    default: S3Arc,
+    new_client: Box<dyn Fn(Region) -> S3Arc + Send + Sync>,
+    /// False to send all requests through `default`, as for a single S3-compatible endpoint
+    detect_regions: bool,
+    location_ttl: Duration,
+    clients: Mutex<HashMap<Region, S3Arc>>,
+    locations: Mutex<HashMap<String, (Region, Instant)>>,
+}
+
+impl MultiRegionS3Client {
+    /// Clients for each region are created by `new_client`. Bucket locations are looked up again
+    /// after `location_ttl`, in case a bucket is recreated elsewhere.
+    pub fn new(default_region: Region, new_client: impl Fn(Region) -> S3Arc + Send + Sync + 'static, location_ttl: Duration) -> MultiRegionS3Client {
+        MultiRegionS3Client {
+            default: new_client(default_region.clone()),
+            default_region,
+            new_client: Box::new(new_client),
+            detect_regions: true,
+            location_ttl,
+            clients: Mutex::new(HashMap::new()),
+            locations: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Send all requests through `client`, without looking up bucket regions
+    pub fn single(client: S3Arc) -> MultiRegionS3Client {
+        MultiRegionS3Client {
+            default_region: Region::default(),
+            new_client: Box::new({
+                let client = client.clone();
+                move |_| client.clone()
+            }),
+            default: client,
+            detect_regions: false,
+            location_ttl: Duration::from_secs(0),
+            clients: Mutex::new(HashMap::new()),
+            locations: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The client for requests to `bucket`
+    pub async fn for_bucket(&self, bucket: &str) -> S3Arc {
+        if !self.detect_regions {
+            return self.default.clone();
+        }
+
+        let region = match self.cached_location(bucket, Instant::now()) {
+            Some(region) => region,
+            None => {
+                let region = self.bucket_location(bucket).await;
+                self.locations.lock().unwrap().insert(bucket.to_owned(), (region.clone(), Instant::now()));
+                region
+            }
+        };
+
+        self.client(region)
+    }
+
+    /// Record that `bucket` is in `region`, as given by a manifest, in place of looking it up
+    pub fn set_location(&self, bucket: &str, region: Region) {
+        if self.detect_regions {
+            self.locations.lock().unwrap().insert(bucket.to_owned(), (region, Instant::now()));
+        }
+    }
+
+    fn cached_location(&self, bucket: &str, now: Instant) -> Option<Region> {
+        match self.locations.lock().unwrap().get(bucket) {
+            Some((region, checked)) if now.duration_since(*checked) < self.location_ttl => Some(region.clone()),
+            _ => None,
+        }
+    }
+
+    /// Look up the region of `bucket`, falling back to the default region if that fails, such as
+    /// when the credentials lack `s3:GetBucketLocation`.
+    async fn bucket_location(&self, bucket: &str) -> Region {
+        let req = GetBucketLocationRequest {
+            bucket: bucket.to_owned(),
+            ..GetBucketLocationRequest::default()
+        };
+
+        // us-east-1 answers for buckets in every region
+        let location = self.client(Region::UsEast1).get_bucket_location(req).await
+            .map_err(|e| e.to_string())
+            .and_then(|res| region_from_location_constraint(res.location_constraint.as_deref()).map_err(|e| e.to_string()));
+
+        match location {
+            Ok(region) => {
+                log::info!("S3 bucket {} is in {}", bucket, region.name());
+                region
+            }
+            Err(e) => {
+                log::warn!("Failed to look up the region of S3 bucket {}, using {}: {}", bucket, self.default_region.name(), e);
+                self.default_region.clone()
+            }
+        }
+    }
+
+    /// The client for `region`, created if this is the first bucket in it
+    fn client(&self, region: Region) -> S3Arc {
+        if region == self.default_region {
+            return self.default.clone();
+        }
+
+        self.clients.lock().unwrap().entry(region).or_insert_with_key(|region| {
+            log::info!("Creating S3 client for {}", region.name());
+            (self.new_client)(region.clone())
+        }).clone()
+    }
+}
+
+/// `GetBucketLocation` returns no location for us-east-1, and `EU` for some old eu-west-1 buckets
+fn region_from_location_constraint(constraint: Option<&str>) -> Result<Region, rusoto_core::region::ParseRegionError> {
+    match constraint {
+        None | Some("") => Ok(Region::UsEast1),
+        Some("EU") => Ok(Region::EuWest1),
+        Some(region) => region.parse(),
+    }
+}
+
+#[test]
+fn test_region_from_location_constraint() {
+    assert_eq!(region_from_location_constraint(None).unwrap(), Region::UsEast1);
+    assert_eq!(region_from_location_constraint(Some("")).unwrap(), Region::UsEast1);
+    assert_eq!(region_from_location_constraint(Some("EU")).unwrap(), Region::EuWest1);
+    assert_eq!(region_from_location_constraint(Some("ap-southeast-2")).unwrap(), Region::ApSoutheast2);
+    assert!(region_from_location_constraint(Some("mars-north-1")).is_err());
+}
+
+#[test]
+fn test_clients_and_location_cache() {
+    use std::sync::atomic::{ AtomicUsize, Ordering };
+
+    let created = Arc::new(AtomicUsize::new(0));
+    let s3 = MultiRegionS3Client::new(Region::UsEast1, {
+        let created = created.clone();
+        move |region| {
+            created.fetch_add(1, Ordering::SeqCst);
+            Arc::new(rusoto_s3::S3Client::new(region)) as S3Arc
+        }
+    }, Duration::from_secs(3600));
+    assert_eq!(created.load(Ordering::SeqCst), 1);
+
+    // One client per region, and the default region reuses the default client
+    let eu = s3.client(Region::EuWest1);
+    assert!(Arc::ptr_eq(&eu, &s3.client(Region::EuWest1)));
+    assert!(Arc::ptr_eq(&s3.default, &s3.client(Region::UsEast1)));
+    s3.client(Region::ApSoutheast2);
+    assert_eq!(created.load(Ordering::SeqCst), 3);
+
+    let start = Instant::now();
+    s3.locations.lock().unwrap().insert("bucket".into(), (Region::EuWest1, start));
+    assert_eq!(s3.cached_location("bucket", start + Duration::from_secs(3599)), Some(Region::EuWest1));
+    assert_eq!(s3.cached_location("bucket", start + Duration::from_secs(3600)), None);
+    assert_eq!(s3.cached_location("other", start), None);
+}
+
+#[tokio::test]
+async fn test_set_location() {
+    // Without credentials a lookup would fall back to the default region, so these show the given ones are used
+    let s3 = MultiRegionS3Client::new(Region::UsEast1, |region| Arc::new(rusoto_s3::S3Client::new(region)) as S3Arc, Duration::from_secs(3600));
+    s3.set_location("bucket-eu", Region::EuWest1);
+    s3.set_location("bucket-ap", Region::ApSoutheast2);
+
+    let eu = s3.for_bucket("bucket-eu").await;
+    let ap = s3.for_bucket("bucket-ap").await;
+    assert!(Arc::ptr_eq(&eu, &s3.client(Region::EuWest1)));
+    assert!(Arc::ptr_eq(&ap, &s3.client(Region::ApSoutheast2)));
+    assert!(!Arc::ptr_eq(&eu, &ap));
+
+    // Ignored with a single endpoint
+    let single = MultiRegionS3Client::single(s3.default.clone());
+    single.set_location("bucket-eu", Region::EuWest1);
+    assert!(single.locations.lock().unwrap().is_empty());
+}
@@ -19,31 +19,98 @@ impl fmt::Display for S3Url {
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
-pub struct ParseS3UrlError;
+pub enum S3UrlError {
+    /// Not of the form `s3://bucket/key`
+    InvalidUrl,
 
-impl fmt::Display for ParseS3UrlError {
+    /// The bucket name violates S3 bucket naming rules
+    InvalidBucketName(String),
+
+    /// The URL has no object key
+    EmptyKey,
+
+    /// The key is longer than the configured limit, in bytes
+    KeyTooLong(usize),
+
+    /// The key contains a NUL byte or other control character
+    KeyControlCharacter,
+
+    /// The key has a `..` path component
+    KeyPathTraversal,
+}
+
+impl fmt::Display for S3UrlError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Invalid s3:// URL")
+        match self {
+            S3UrlError::InvalidUrl => write!(f, "Invalid s3:// URL"),
+            S3UrlError::InvalidBucketName(name) => write!(f,
+                "Invalid S3 bucket name {:?}: must be 3-63 lowercase letters, digits, hyphens, or periods, \
+                 begin and end with a letter or digit, and not be formatted as an IP address", name),
+            S3UrlError::EmptyKey => write!(f, "Missing object key in s3:// URL"),
+            S3UrlError::KeyTooLong(max) => write!(f, "Object key longer than {} bytes", max),
+            S3UrlError::KeyControlCharacter => write!(f, "Object key contains a control character"),
+            S3UrlError::KeyPathTraversal => write!(f, "Object key contains a `..` path component"),
+        }
+    }
+}
+
+/// Check a bucket name against the S3 bucket naming rules
+fn valid_bucket_name(name: &str) -> bool {
+    lazy_static! {
+        static ref BUCKET_RE: Regex = Regex::new(r"^[a-z0-9][a-z0-9.-]{1,61}[a-z0-9]$").unwrap();
+        static ref IP_RE: Regex = Regex::new(r"^\d+\.\d+\.\d+\.\d+$").unwrap();
     }
+
+    BUCKET_RE.is_match(name) && !IP_RE.is_match(name)
 }
 
 impl FromStr for S3Url {
-    type Err = ParseS3UrlError;
+    type Err = S3UrlError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         lazy_static! {
-            static ref RE: Regex = Regex::new(r"^s3://([^/]+)/(.+)$").unwrap();
+            static ref RE: Regex = Regex::new(r"^s3://([^/]+)/(.*)$").unwrap();
         }
 
-        let captures = RE.captures(s).ok_or(ParseS3UrlError)?;
+        let captures = RE.captures(s).ok_or(S3UrlError::InvalidUrl)?;
+        let bucket = captures.get(1).unwrap().as_str();
+        let key = captures.get(2).unwrap().as_str();
+
+        if !valid_bucket_name(bucket) {
+            return Err(S3UrlError::InvalidBucketName(bucket.to_owned()));
+        }
+
+        if key.is_empty() {
+            return Err(S3UrlError::EmptyKey);
+        }
 
         Ok(S3Url {
-            bucket: captures.get(1).unwrap().as_str().to_owned(),
-            key: captures.get(2).unwrap().as_str().to_owned()
+            bucket: bucket.to_owned(),
+            key: key.to_owned()
         })
     }
 }
 
+impl S3Url {
+    /// Check that the key is at most `max_len` bytes and free of control characters and `..`
+    /// components. S3 allows all of these, but legitimate manifests don't need them.
+    pub fn check_key(&self, max_len: usize) -> Result<(), S3UrlError> {
+        if self.key.len() > max_len {
+            return Err(S3UrlError::KeyTooLong(max_len));
+        }
+
+        if self.key.chars().any(char::is_control) {
+            return Err(S3UrlError::KeyControlCharacter);
+        }
+
+        if self.key.split('/').any(|component| component == "..") {
+            return Err(S3UrlError::KeyPathTraversal);
+        }
+
+        Ok(())
+    }
+}
+
 impl<'de> de::Deserialize<'de> for S3Url {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
         where D: de::Deserializer<'de>
@@ -59,6 +126,47 @@ fn test_s3url() {
     assert_eq!(parsed, Ok(S3Url { bucket: "bucketname".into(), key: "bar/baz.jpg".into() }));
     assert_eq!(parsed.unwrap().to_string(), "s3://bucketname/bar/baz.jpg");
 
-    assert_eq!("http://foo/bar".parse::<S3Url>(), Err(ParseS3UrlError));
-    assert_eq!("s3://foo".parse::<S3Url>(), Err(ParseS3UrlError));
+    assert_eq!("http://foo/bar".parse::<S3Url>(), Err(S3UrlError::InvalidUrl));
+    assert_eq!("s3://foo".parse::<S3Url>(), Err(S3UrlError::InvalidUrl));
+    assert_eq!("s3://foo/".parse::<S3Url>(), Err(S3UrlError::EmptyKey));
+}
+
+#[test]
+fn test_s3url_bucket_names() {
+    let bucket = |name: &str| format!("s3://{}/key", name).parse::<S3Url>().map(|u| u.bucket);
+    let invalid = |name: &str| Err(S3UrlError::InvalidBucketName(name.into()));
+
+    assert_eq!(bucket("abc"), Ok("abc".into()));
+    assert_eq!(bucket(&"a".repeat(63)), Ok("a".repeat(63)));
+    assert_eq!(bucket("my-bucket.logs2"), Ok("my-bucket.logs2".into()));
+    assert_eq!(bucket("1bucket"), Ok("1bucket".into()));
+
+    assert_eq!(bucket("ab"), invalid("ab"));
+    assert_eq!(bucket(&"a".repeat(64)), invalid(&"a".repeat(64)));
+    assert_eq!(bucket("192.168.5.4"), invalid("192.168.5.4"));
+    assert_eq!(bucket("MyBucket"), invalid("MyBucket"));
+    assert_eq!(bucket("-bucket"), invalid("-bucket"));
+    assert_eq!(bucket("bucket-"), invalid("bucket-"));
+    assert_eq!(bucket("my_bucket"), invalid("my_bucket"));
+}
+
+#[test]
+fn test_s3url_check_key() {
+    let check = |key: &str| S3Url { bucket: "bucket".into(), key: key.into() }.check_key(16);
+
+    assert_eq!(check("a/b.txt"), Ok(()));
+    assert_eq!(check("a/..b/c..txt"), Ok(()));
+    assert_eq!(check(&"k".repeat(16)), Ok(()));
+    assert_eq!(check(&"k".repeat(17)), Err(S3UrlError::KeyTooLong(16)));
+    assert_eq!(check(&"é".repeat(9)), Err(S3UrlError::KeyTooLong(16)));
+
+    assert_eq!(check("a\0b"), Err(S3UrlError::KeyControlCharacter));
+    assert_eq!(check("a\nb"), Err(S3UrlError::KeyControlCharacter));
+    assert_eq!(check("a\x1bb"), Err(S3UrlError::KeyControlCharacter));
+    assert_eq!(check("a\u{7f}b"), Err(S3UrlError::KeyControlCharacter));
+    assert_eq!(check("a\u{85}b"), Err(S3UrlError::KeyControlCharacter));
+
+    assert_eq!(check("../etc/passwd"), Err(S3UrlError::KeyPathTraversal));
+    assert_eq!(check("a/../../b"), Err(S3UrlError::KeyPathTraversal));
+    assert_eq!(check("a/.."), Err(S3UrlError::KeyPathTraversal));
 }
@@ -1,8 +1,9 @@
 // © 2019 3D Robotics. License: Apache-2.0
 
 use futures::stream::TryStreamExt;
-use hyper::{Request, Response, Body, StatusCode, header};
-use crate::stream_range::{ Range, StreamRange };
+use hyper::{Request, Response, Body, Method, StatusCode, header};
+use bytes::Bytes;
+use crate::stream_range::{ self, BoxBytesStream, DynStreamRange, Range, StreamRange };
 
 /// Parse an HTTP range header to a `Range`
 ///
@@ -71,23 +72,127 @@ fn test_range() {
     assert_eq!(parse_range("bytes=-b", 1000), Err("invalid range number"));
 }
 
+/// Wrap `data` as the single part of a `multipart/form-data` body, for clients that expect a
+/// form-based download. The body is served with `multipart_content_type(boundary)`.
+pub fn multipart_form_data(data: Box<dyn DynStreamRange>, boundary: &str, filename: &str, content_type: &str) -> impl StreamRange {
+    let head = format!(
+        "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\nContent-Type: {}\r\n\r\n",
+        boundary, filename, content_type,
+    );
+    let tail = format!("\r\n--{}--\r\n", boundary);
+
+    stream_range::Concatenated(vec![Box::new(Bytes::from(head)), data, Box::new(Bytes::from(tail))])
+}
+
+pub fn multipart_content_type(boundary: &str) -> String {
+    format!("multipart/form-data; boundary={}", boundary)
+}
+
+#[tokio::test]
+async fn test_multipart_form_data() {
+    let data = multipart_form_data(Box::new(Bytes::from_static(b"PK..")), "b123", "test.zip", "application/zip");
+    let body = data.stream_range(Range { start: 0, end: data.len() }).map_ok(|b| b.to_vec()).try_concat().await.unwrap();
+    assert_eq!(String::from_utf8(body).unwrap(), "--b123\r\n\
+        Content-Disposition: form-data; name=\"file\"; filename=\"test.zip\"\r\n\
+        Content-Type: application/zip\r\n\r\n\
+        PK..\r\n--b123--\r\n");
+}
+
+/// Bytes from each end of the data hashed by `compute_etag` when it's too large to hash in full
+#[cfg(test)]
+const ETAG_SAMPLE_LEN: u64 = 4096;
+
+/// Derive an ETag for `hyper_response` from the content itself. Data of up to `full_hash_limit`
+/// bytes is hashed in full. Larger data is identified by its length and its first and last 4 KiB
+/// only, so that it can be done cheaply, but changes elsewhere that preserve the length aren't
+/// detected.
+///
+/// Only tests use this, to serve fixture data under a validator that follows its content. Archive
+/// responses derive their ETag from the manifest instead, because hashing the archive would read
+/// from S3 on every request, including each Range request to resume a download.
+#[cfg(test)]
+pub async fn compute_etag(data: &impl StreamRange, full_hash_limit: u64) -> Result<String, stream_range::BoxError> {
+    use futures::StreamExt;
+
+    let len = data.len();
+    let ranges = if len <= full_hash_limit.max(2 * ETAG_SAMPLE_LEN) {
+        vec![Range { start: 0, end: len }]
+    } else {
+        vec![Range { start: 0, end: ETAG_SAMPLE_LEN }, Range { start: len - ETAG_SAMPLE_LEN, end: len }]
+    };
+
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&len.to_le_bytes());
+
+    for range in ranges {
+        let stream = data.stream_range(range);
+        futures::pin_mut!(stream);
+        while let Some(chunk) = stream.next().await {
+            hasher.update(&chunk?);
+        }
+    }
+
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+#[tokio::test]
+async fn test_compute_etag() {
+    use bytes::Bytes;
+
+    let etag = |data: Vec<u8>, limit: u64| async move { compute_etag(&Bytes::from(data), limit).await.unwrap() };
+
+    assert_eq!(etag(b"abc".to_vec(), 1000).await, etag(b"abc".to_vec(), 1000).await);
+    assert_ne!(etag(b"abc".to_vec(), 1000).await, etag(b"abd".to_vec(), 1000).await);
+    let mut expected = blake3::Hasher::new();
+    expected.update(&3u64.to_le_bytes()).update(b"abc");
+    assert_eq!(etag(b"abc".to_vec(), 1000).await, expected.finalize().to_hex().as_str());
+
+    // Above the limit, only the length and the ends are hashed
+    let large = vec![0u8; 100_000];
+    let mut middle = large.clone();
+    middle[50_000] = 1;
+    let mut end = large.clone();
+    end[99_999] = 1;
+
+    assert_eq!(etag(large.clone(), 1000).await, etag(middle.clone(), 1000).await);
+    assert_ne!(etag(large.clone(), 1000).await, etag(end, 1000).await);
+    assert_ne!(etag(large.clone(), 1000).await, etag(vec![0u8; 100_001], 1000).await);
+    assert_ne!(etag(large, 100_000).await, etag(middle, 100_000).await);
+}
+
+/// The range of `full_len` bytes that `hyper_response` serves for `req`, or `None` for all of it:
+/// the `Range` header is ignored if it's invalid or `If-Range` doesn't match `etag`.
+pub fn requested_range(req: &Request<Body>, etag: &str, full_len: u64) -> Option<Range> {
+    req.headers().get(hyper::header::RANGE)
+        .filter(|_| req.headers().get(hyper::header::IF_RANGE).map_or(true, |val| val == etag))
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| parse_range(v, full_len).ok())
+        .and_then(|x| x)
+}
+
 /// Serve a `StreamRange` in response to a `hyper` request.
 /// This handles the HTTP Range header and "206 Partial content" and associated headers if required
-pub fn hyper_response(req: &Request<Body>, content_type: &str, etag: &str, filename: &str, data: &dyn StreamRange) -> Response<Body> {
+///
+/// `X-Total-Length` is set to the length of the full data on every response, for clients that
+/// want it without parsing `Content-Range`.
+///
+/// Errors from the stream are passed on to hyper, which closes the connection (HTTP/1) or resets
+/// the stream (HTTP/2) so that a truncated body can't be mistaken for a complete one.
+///
+/// The stream is only polled as the client reads, and if the client disconnects hyper drops it,
+/// cancelling any in-flight requests for the data.
+pub fn hyper_response(req: &Request<Body>, content_type: &str, etag: &str, filename: &str, data: &impl StreamRange) -> Response<Body> {
     let full_len = data.len();
     let full_range = Range { start: 0, end: full_len };
 
-    let range = req.headers().get(hyper::header::RANGE)
-        .filter(|_| req.headers().get(hyper::header::IF_RANGE).map_or(true, |val| val == etag))
-        .and_then(|v| v.to_str().ok())
-        .and_then(|v| parse_range(v, full_len).ok())
-        .and_then(|x| x);
+    let range = requested_range(req, etag, full_len);
 
     let mut res = Response::builder()
         .header(header::CONTENT_TYPE, content_type)
         .header(header::ACCEPT_RANGES, "bytes")
         .header(header::ETAG, etag)
-        .header(header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", filename));
+        .header(header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", filename))
+        .header("X-Total-Length", full_len);
 
     if let Some(range) = range {
         res = res.status(StatusCode::PARTIAL_CONTENT)
@@ -99,6 +204,10 @@ pub fn hyper_response(req: &Request<Body>, content_type: &str, etag: &str, filen
 
     res = res.header(header::CONTENT_LENGTH, range.len());
 
+    if req.method() == Method::HEAD {
+        return res.body(Body::empty()).unwrap();
+    }
+
     let stream = data.stream_range(range).inspect_err(|err| {
         log::error!("Response stream error: {}", err);
     });
@@ -106,6 +215,27 @@ pub fn hyper_response(req: &Request<Body>, content_type: &str, etag: &str, filen
     res.body(Body::wrap_stream(stream)).unwrap()
 }
 
+/// Serve a stream whose length isn't known in advance, such as compressed data, in response to a
+/// `hyper` request. Range requests get the full body, since there is no way to seek into it, and
+/// without a `Content-Length` hyper sends it with chunked Transfer-Encoding.
+pub fn hyper_stream_response(req: &Request<Body>, content_type: &str, etag: &str, filename: &str, stream: BoxBytesStream) -> Response<Body> {
+    let res = Response::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::ACCEPT_RANGES, "none")
+        .header(header::ETAG, etag)
+        .header(header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", filename));
+
+    if req.method() == Method::HEAD {
+        return res.body(Body::empty()).unwrap();
+    }
+
+    let stream = stream.inspect_err(|err| {
+        log::error!("Response stream error: {}", err);
+    });
+
+    res.body(Body::wrap_stream(stream)).unwrap()
+}
+
 #[tokio::test]
 async fn test_base_hyper_response() {
     use { bytes::Bytes, hyper::body::to_bytes };
@@ -121,6 +251,7 @@ async fn test_base_hyper_response() {
     assert_eq!(res.headers().get(header::CONTENT_DISPOSITION), Some(&header::HeaderValue::from_static("attachment; filename=\"foo.zip\"")));
     assert_eq!(res.headers().get(header::ETAG), Some(&header::HeaderValue::from_static("ETAG")));
     assert_eq!(res.headers().get(header::CONTENT_LENGTH), Some(&header::HeaderValue::from_static("10")));
+    assert_eq!(res.headers().get("X-Total-Length"), Some(&header::HeaderValue::from_static("10")));
     assert_eq!(to_bytes(res.into_body()).await.unwrap().as_ref(), b"0123456789");
 }
 
@@ -141,9 +272,27 @@ async fn test_range_hyper_response() {
     assert_eq!(res.headers().get(header::ETAG), Some(&header::HeaderValue::from_static("ETAG")));
     assert_eq!(res.headers().get(header::CONTENT_LENGTH), Some(&header::HeaderValue::from_static("5")));
     assert_eq!(res.headers().get(header::CONTENT_RANGE), Some(&header::HeaderValue::from_static("bytes 4-8/10")));
+    assert_eq!(res.headers().get("X-Total-Length"), Some(&header::HeaderValue::from_static("10")));
     assert_eq!(to_bytes(res.into_body()).await.unwrap().as_ref(), b"45678");
 }
 
+#[tokio::test]
+async fn test_head_hyper_response() {
+    use { bytes::Bytes, hyper::body::to_bytes };
+    let req = Request::head("/")
+        .body(Body::empty()).unwrap();
+
+    let data = Bytes::from_static(b"0123456789");
+
+    let res = hyper_response(&req, "application/test", "ETAG", "foo.zip", &data);
+
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers().get(header::ETAG), Some(&header::HeaderValue::from_static("ETAG")));
+    assert_eq!(res.headers().get(header::ACCEPT_RANGES), Some(&header::HeaderValue::from_static("bytes")));
+    assert_eq!(res.headers().get(header::CONTENT_LENGTH), Some(&header::HeaderValue::from_static("10")));
+    assert_eq!(to_bytes(res.into_body()).await.unwrap().as_ref(), b"");
+}
+
 #[tokio::test]
 async fn test_bad_if_range_hyper_response() {
     use { bytes::Bytes, hyper::body::to_bytes };
@@ -162,3 +311,111 @@ async fn test_bad_if_range_hyper_response() {
     assert_eq!(res.headers().get(header::CONTENT_RANGE), None);
     assert_eq!(to_bytes(res.into_body()).await.unwrap().as_ref(), b"0123456789");
 }
+
+#[tokio::test]
+async fn test_stream_error_closes_connection() {
+    use std::convert::Infallible;
+    use std::sync::atomic::{ AtomicUsize, Ordering };
+    use std::sync::Arc;
+    use bytes::Bytes;
+    use futures::StreamExt;
+    use hyper::{ Client, Server, service::{ make_service_fn, service_fn } };
+
+    /// Fails after the first half of the data
+    struct Failing;
+    impl StreamRange for Failing {
+        type Stream = crate::stream_range::BoxBytesStream;
+        fn len(&self) -> u64 { 10 }
+        fn stream_range(&self, _range: Range) -> crate::stream_range::BoxBytesStream {
+            let error = futures::stream::once(async {
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                Err("S3 error".into())
+            });
+            Box::pin(futures::stream::once(async { Ok(Bytes::from_static(b"01234")) }).chain(error))
+        }
+    }
+
+    let connections = Arc::new(AtomicUsize::new(0));
+    let counter = connections.clone();
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service_fn(move |_conn| {
+        counter.fetch_add(1, Ordering::SeqCst);
+        async {
+            Ok::<_, Infallible>(service_fn(|req| async move {
+                Ok::<_, Infallible>(hyper_response(&req, "application/test", "ETAG", "foo.zip", &Failing))
+            }))
+        }
+    }));
+    let url: hyper::Uri = format!("http://{}/", server.local_addr()).parse().unwrap();
+    tokio::spawn(server);
+
+    let client = Client::new();
+    for _ in 0..2 {
+        let res = client.get(url.clone()).await.unwrap();
+        assert_eq!(res.headers().get(header::CONTENT_LENGTH).unwrap(), "10");
+        assert!(hyper::body::to_bytes(res.into_body()).await.is_err());
+    }
+
+    // The aborted connection was not reused
+    assert_eq!(connections.load(Ordering::SeqCst), 2);
+}
+
+/// When the client disconnects mid-download, the data source stops being read and is dropped.
+#[tokio::test]
+async fn test_client_disconnect_stops_reads() {
+    use std::convert::Infallible;
+    use std::sync::atomic::{ AtomicBool, AtomicUsize, Ordering };
+    use std::sync::Arc;
+    use std::time::Duration;
+    use bytes::Bytes;
+    use futures::StreamExt;
+    use hyper::{ Client, Server, service::{ make_service_fn, service_fn } };
+    use crate::stream_range::Concatenated;
+
+    /// Sets a flag when the stream is dropped
+    struct DropGuard(Arc<AtomicBool>);
+    impl Drop for DropGuard {
+        fn drop(&mut self) { self.0.store(true, Ordering::SeqCst); }
+    }
+
+    /// An endless source standing in for a large S3 object, counting chunks read
+    #[derive(Clone)]
+    struct Endless { reads: Arc<AtomicUsize>, dropped: Arc<AtomicBool> }
+    impl StreamRange for Endless {
+        type Stream = crate::stream_range::BoxBytesStream;
+        fn len(&self) -> u64 { 1 << 40 }
+        fn stream_range(&self, _range: Range) -> Self::Stream {
+            let guard = (self.reads.clone(), DropGuard(self.dropped.clone()));
+            Box::pin(futures::stream::unfold(guard, |guard| async move {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+                guard.0.fetch_add(1, Ordering::SeqCst);
+                Some((Ok(Bytes::from_static(&[0; 1024])), guard))
+            }))
+        }
+    }
+
+    let source = Endless { reads: Arc::new(AtomicUsize::new(0)), dropped: Arc::new(AtomicBool::new(false)) };
+    let data = source.clone();
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service_fn(move |_conn| {
+        let data = data.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let data = Concatenated(vec![Box::new(Bytes::from_static(b"header")), Box::new(data.clone())]);
+                async move { Ok::<_, Infallible>(hyper_response(&req, "application/test", "ETAG", "foo.zip", &data)) }
+            }))
+        }
+    }));
+    let url: hyper::Uri = format!("http://{}/", server.local_addr()).parse().unwrap();
+    tokio::spawn(server);
+
+    let mut body = Client::new().get(url).await.unwrap().into_body();
+    for _ in 0..10 {
+        body.next().await.unwrap().unwrap();
+    }
+    drop(body);
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(source.dropped.load(Ordering::SeqCst));
+    let reads = source.reads.load(Ordering::SeqCst);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(source.reads.load(Ordering::SeqCst), reads);
+}
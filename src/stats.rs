@@ -0,0 +1,89 @@
+// © 2019 3D Robotics. License: Apache-2.0
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::time::{ Duration, Instant };
+use futures::TryStreamExt;
+use hyper::{ Body, Response };
+
+/// Counters reported by the `/status` endpoint
+#[derive(Debug)]
+pub struct Stats {
+    started: Instant,
+    requests: AtomicU64,
+    active_streams: AtomicU64,
+    bytes_sent: AtomicU64,
+    s3_requests: AtomicU64,
+    s3_wait_micros: AtomicU64,
+}
+
+impl Default for Stats {
+    fn default() -> Stats {
+        Stats {
+            started: Instant::now(),
+            requests: AtomicU64::new(0),
+            active_streams: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            s3_requests: AtomicU64::new(0),
+            s3_wait_micros: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Decrements the active stream count when the response body is dropped
+struct ActiveStream(Arc<Stats>);
+
+impl Drop for ActiveStream {
+    fn drop(&mut self) {
+        self.0.active_streams.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Stats {
+    pub fn new() -> Stats { Stats::default() }
+
+    pub fn count_request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record the time an S3 request waited for a connection slot
+    pub fn record_s3_wait(&self, wait: Duration) {
+        self.s3_requests.fetch_add(1, Ordering::Relaxed);
+        self.s3_wait_micros.fetch_add(wait.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Wrap a zip response so that it counts as an active stream until its body is complete or
+    /// dropped, and its bytes are counted as they are sent.
+    pub fn track(self: &Arc<Self>, res: Response<Body>) -> Response<Body> {
+        self.active_streams.fetch_add(1, Ordering::Relaxed);
+        let active = ActiveStream(self.clone());
+
+        res.map(|body| Body::wrap_stream(body.inspect_ok(move |chunk| {
+            active.0.bytes_sent.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+        })))
+    }
+
+    /// Counters as JSON, combined with a summary of the configuration
+    pub fn to_json(&self, config: serde_json::Value) -> serde_json::Value {
+        serde_json::json!({
+            "uptime_secs": self.started.elapsed().as_secs(),
+            "requests": self.requests.load(Ordering::Relaxed),
+            "active_streams": self.active_streams.load(Ordering::Relaxed),
+            "bytes_sent": self.bytes_sent.load(Ordering::Relaxed),
+            "s3_requests": self.s3_requests.load(Ordering::Relaxed),
+            "s3_wait_secs_total": self.s3_wait_micros.load(Ordering::Relaxed) as f64 / 1e6,
+            "config": config,
+        })
+    }
+}
+
+#[tokio::test]
+async fn test_track() {
+    let stats = Arc::new(Stats::new());
+    let res = stats.track(Response::new(Body::from("0123456789")));
+    assert_eq!(stats.to_json(serde_json::Value::Null)["active_streams"], 1);
+
+    hyper::body::to_bytes(res.into_body()).await.unwrap();
+    let json = stats.to_json(serde_json::Value::Null);
+    assert_eq!(json["active_streams"], 0);
+    assert_eq!(json["bytes_sent"], 10);
+}
@@ -2,11 +2,19 @@
 use std::sync::Arc;
 use std::pin::Pin;
 use futures::{ future, TryFutureExt, stream, Stream, StreamExt, TryStreamExt };
-use bytes::Bytes;
+use bytes::{ Bytes, BytesMut };
 use rusoto_s3::{ S3, GetObjectRequest };
+use crate::health::Readiness;
+use crate::metrics::{ self, Metrics };
+use crate::stats::Stats;
+use std::time::Instant;
+use tokio::sync::{ Semaphore, OwnedSemaphorePermit };
+use tokio::io::AsyncRead;
+use tokio_util::io::{ ReaderStream, StreamReader };
+use async_compression::tokio::bufread::{ BrotliDecoder, GzipDecoder, ZlibDecoder };
 
-type BoxBytesStream = Pin<Box<dyn Stream<Item = Result<Bytes, BoxError>> + Send +'static>>;
-type BoxError = Box<dyn std::error::Error + 'static + Sync + Send>;
+pub type BoxBytesStream = Pin<Box<dyn Stream<Item = Result<Bytes, BoxError>> + Send +'static>>;
+pub type BoxError = Box<dyn std::error::Error + 'static + Sync + Send>;
 
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub struct Range {
@@ -26,24 +34,195 @@ impl Range {
 
     pub fn len(&self) -> u64 { self.end - self.start }
 
+    pub fn is_empty(&self) -> bool { self.start >= self.end }
+
     pub fn to_http_range_header(&self) -> String {
         format!("bytes={}-{}", self.start, self.end-1)
     }
 }
 
-/// An abstract stream of bytes that supports serving a range as a futures::Stream
+/// An abstract stream of bytes that supports serving a range as a futures::Stream.
+///
+/// Implementations produce data in chunks as it arrives, and never buffer their whole content,
+/// so that serving an archive takes memory independent of the size of its entries.
 pub trait StreamRange {
+    /// Stream returned by `stream_range`. Implementations whose stream type can be named avoid
+    /// boxing it; others use `BoxBytesStream`.
+    type Stream: Stream<Item = Result<Bytes, BoxError>> + Send + 'static;
+
     /// Total number of bytes
     fn len(&self) -> u64;
 
+    fn is_empty(&self) -> bool { self.len() == 0 }
+
     /// Create a stream that produces a range of the data
-    fn stream_range(&self, range: Range) -> BoxBytesStream;
+    fn stream_range(&self, range: Range) -> Self::Stream;
+}
+
+/// Object-safe form of `StreamRange`, implemented for every `StreamRange`, for collections of
+/// different types. `Box<dyn DynStreamRange>` implements `StreamRange` with a boxed stream.
+pub trait DynStreamRange: Send + Sync {
+    fn dyn_len(&self) -> u64;
+    fn dyn_stream_range(&self, range: Range) -> BoxBytesStream;
 }
 
+impl<T: StreamRange + Send + Sync> DynStreamRange for T {
+    fn dyn_len(&self) -> u64 { self.len() }
+    fn dyn_stream_range(&self, range: Range) -> BoxBytesStream {
+        Box::pin(self.stream_range(range))
+    }
+}
+
+impl StreamRange for Box<dyn DynStreamRange> {
+    type Stream = BoxBytesStream;
+
+    fn len(&self) -> u64 { (**self).dyn_len() }
+    fn stream_range(&self, range: Range) -> BoxBytesStream {
+        (**self).dyn_stream_range(range)
+    }
+}
+
+/// Ranges are produced with `Bytes::slice`, which shares the underlying buffer rather than
+/// copying it.
 impl StreamRange for Bytes {
+    type Stream = stream::Once<future::Ready<Result<Bytes, BoxError>>>;
+
     fn len(&self) -> u64 { Bytes::len(self) as u64 }
+    fn stream_range(&self, range: Range) -> Self::Stream {
+        stream::once(future::ok(self.slice(range.start as usize..range.end as usize)))
+    }
+}
+
+/// A `BytesMut` can only be frozen by value, so each range is copied out of it. Freeze it and use
+/// the `Bytes` impl instead where the buffer is no longer needed mutably.
+impl StreamRange for BytesMut {
+    type Stream = stream::Once<future::Ready<Result<Bytes, BoxError>>>;
+
+    fn len(&self) -> u64 { BytesMut::len(self) as u64 }
+    fn stream_range(&self, range: Range) -> Self::Stream {
+        stream::once(future::ok(Bytes::copy_from_slice(&self[range.start as usize..range.end as usize])))
+    }
+}
+
+/// Implements `StreamRange` for data held as a list of chunks, such as an S3 `GetObject` body
+/// collected as it arrived, without copying it into one contiguous `Bytes`.
+///
+/// Test-only for now: `S3Object` streams each GetObject body straight through rather than
+/// buffering it, and the only S3 body that is buffered, a `--manifest-source` manifest, has to be
+/// contiguous to be parsed.
+#[cfg(test)]
+pub struct ChunkedBytesStreamRange {
+    chunks: Vec<Bytes>,
+    /// Offset of the end of each chunk, for finding the chunks that overlap a range
+    ends: Vec<u64>,
+}
+
+#[cfg(test)]
+impl ChunkedBytesStreamRange {
+    pub fn new(chunks: Vec<Bytes>) -> ChunkedBytesStreamRange {
+        let ends = chunks.iter().scan(0, |end, chunk| {
+            *end += chunk.len() as u64;
+            Some(*end)
+        }).collect();
+        ChunkedBytesStreamRange { chunks, ends }
+    }
+}
+
+#[cfg(test)]
+impl StreamRange for ChunkedBytesStreamRange {
+    type Stream = stream::Iter<std::vec::IntoIter<Result<Bytes, BoxError>>>;
+
+    fn len(&self) -> u64 { self.ends.last().copied().unwrap_or(0) }
+    fn stream_range(&self, range: Range) -> Self::Stream {
+        let mut slices = Vec::new();
+
+        // The first chunk that ends after the start of the range
+        let first = self.ends.partition_point(|&end| end <= range.start);
+
+        for (chunk, &end) in self.chunks[first..].iter().zip(&self.ends[first..]) {
+            let start = end - chunk.len() as u64;
+            if start >= range.end { break; }
+
+            let from = range.start.saturating_sub(start) as usize;
+            let to = (range.end.min(end) - start) as usize;
+            if from < to {
+                slices.push(Ok(chunk.slice(from..to)));
+            }
+        }
+
+        stream::iter(slices)
+    }
+}
+
+/// Wraps a `StreamRange` to produce chunks of `chunk_size` bytes (except for the last): smaller
+/// chunks are coalesced and larger ones split. A `chunk_size` of 0 passes chunks through as-is.
+pub struct Rechunked<T> {
+    pub inner: T,
+    pub chunk_size: usize,
+}
+
+impl<T: StreamRange> StreamRange for Rechunked<T> {
+    type Stream = BoxBytesStream;
+
+    fn len(&self) -> u64 { self.inner.len() }
     fn stream_range(&self, range: Range) -> BoxBytesStream {
-        Box::pin(stream::once(future::ok(self.slice(range.start as usize..range.end as usize))))
+        let stream = Box::pin(self.inner.stream_range(range));
+        let chunk_size = self.chunk_size;
+
+        if chunk_size == 0 {
+            return stream;
+        }
+
+        // Data already read from `stream`, and the unused part of the last chunk read
+        let state = Some((stream, BytesMut::new(), Bytes::new()));
+
+        Box::pin(stream::unfold(state, move |state| async move {
+            let (mut stream, mut buf, mut pending) = state?;
+            loop {
+                // Split off a whole chunk without copying when possible
+                if buf.is_empty() && pending.len() >= chunk_size {
+                    let chunk = pending.split_to(chunk_size);
+                    return Some((Ok(chunk), Some((stream, buf, pending))));
+                }
+
+                let take = (chunk_size - buf.len()).min(pending.len());
+                if take > 0 && buf.capacity() == 0 {
+                    buf.reserve(chunk_size);
+                }
+                buf.extend_from_slice(&pending.split_to(take));
+                if buf.len() == chunk_size {
+                    return Some((Ok(buf.split().freeze()), Some((stream, buf, pending))));
+                }
+
+                match stream.next().await {
+                    Some(Ok(chunk)) => pending = chunk,
+                    Some(Err(e)) => return Some((Err(e), None)),
+                    None if buf.is_empty() => return None,
+                    None => return Some((Ok(buf.split().freeze()), None)),
+                }
+            }
+        }))
+    }
+}
+
+/// Bounds the number of S3 requests in flight across all responses.
+/// Requests wait for a slot rather than failing.
+pub struct ConnectionLimit {
+    semaphore: Arc<Semaphore>,
+    stats: Arc<Stats>,
+}
+
+impl ConnectionLimit {
+    pub fn new(max: usize, stats: Arc<Stats>) -> ConnectionLimit {
+        ConnectionLimit { semaphore: Arc::new(Semaphore::new(max)), stats }
+    }
+
+    /// Wait for a slot, which is released when the returned permit is dropped
+    pub async fn acquire(&self) -> OwnedSemaphorePermit {
+        let start = Instant::now();
+        let permit = self.semaphore.clone().acquire_owned().await.expect("semaphore is never closed");
+        self.stats.record_s3_wait(start.elapsed());
+        permit
     }
 }
 
@@ -53,36 +232,59 @@ pub struct S3Object {
     pub bucket: String,
     pub key: String,
     pub len: u64,
+    pub readiness: Arc<Readiness>,
+    pub limit: Option<Arc<ConnectionLimit>>,
+    pub metrics: Arc<Metrics>,
 }
 
 impl StreamRange for S3Object {
+    type Stream = BoxBytesStream;
+
     fn len(&self) -> u64 { self.len }
     fn stream_range(&self, range: Range) -> BoxBytesStream {
         let s3 = self.s3.clone();
         let bucket = self.bucket.clone();
         let key = self.key.clone();
+        let readiness = self.readiness.clone();
+        let limit = self.limit.clone();
+        let metrics = self.metrics.clone();
+        let object_len = self.len;
 
         let stream = async move {
+            // Held until the body stream is dropped
+            let permit = match limit {
+                Some(limit) => Some(limit.acquire().await),
+                None => None,
+            };
+
             let len = range.len();
-            let url = format!("s3://{}/{}", bucket, key);
+            let url = crate::redact::s3_url(&bucket, &key);
 
             let req = GetObjectRequest {
-                bucket,
-                key,
+                bucket: bucket.clone(),
+                key: key.clone(),
                 range: Some(range.to_http_range_header()),
                 ..GetObjectRequest::default()
             };
 
+            let start = Instant::now();
             let res = s3.get_object(req).await
-                .map_err(|err| { format!("S3 GetObject failed with {}", err) })?;
+                .map_err(|err| {
+                    metrics.count_s3_error(&bucket, metrics::s3_error_type(&err));
+                    format!("S3 GetObject failed with {}", err)
+                })?;
             
             log::info!("S3 get complete for {}", url);
+            metrics.observe_s3_fetch(&bucket, &key, object_len, start.elapsed());
+            readiness.mark_s3_ok();
 
             if res.content_length != Some(len as i64) {
                 log::error!("S3 file size mismatch for {}, expected {:?}, got {:?}", url, len, res.content_length)
             }
 
-            Ok(res.body.unwrap().map_err(|err| {
+            Ok(res.body.unwrap().map_err(move |err| {
+                let _permit = &permit;
+                metrics.count_s3_error(&bucket, "stream");
                 format!("S3 stream failed with {}", err).into()
             }))
         };
@@ -91,12 +293,149 @@ impl StreamRange for S3Object {
     }
 }
 
+/// A `Content-Encoding` that an S3 object may be stored with
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ContentEncoding {
+    Identity,
+    Gzip,
+    /// HTTP's `deflate`, which is the zlib format
+    Deflate,
+    Brotli,
+}
+
+impl std::str::FromStr for ContentEncoding {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<ContentEncoding, ()> {
+        match &s.trim().to_ascii_lowercase()[..] {
+            "" | "identity" => Ok(ContentEncoding::Identity),
+            "gzip" | "x-gzip" => Ok(ContentEncoding::Gzip),
+            "deflate" => Ok(ContentEncoding::Deflate),
+            "br" => Ok(ContentEncoding::Brotli),
+            _ => Err(()),
+        }
+    }
+}
+
+impl ContentEncoding {
+    /// Decode `stream` as it is read
+    pub fn decode(self, stream: BoxBytesStream) -> BoxBytesStream {
+        let reader = StreamReader::new(stream.map_err(std::io::Error::other));
+        let decoder: Pin<Box<dyn AsyncRead + Send>> = match self {
+            ContentEncoding::Identity => return Box::pin(reader.into_inner().map_err(BoxError::from)),
+            ContentEncoding::Gzip => Box::pin(GzipDecoder::new(reader)),
+            ContentEncoding::Deflate => Box::pin(ZlibDecoder::new(reader)),
+            ContentEncoding::Brotli => Box::pin(BrotliDecoder::new(reader)),
+        };
+        Box::pin(ReaderStream::new(decoder).map_err(BoxError::from))
+    }
+}
+
+/// The content of `inner` decoded from `encoding`, for S3 objects stored compressed. `len` is the
+/// decoded length, which has to be measured in advance. Compressed data can't be read from the
+/// middle, so each range is decoded from the start of `inner`, discarding what comes before it.
+pub struct Decoded<T> {
+    pub inner: T,
+    pub encoding: ContentEncoding,
+    pub len: u64,
+}
+
+impl<T: StreamRange> StreamRange for Decoded<T> {
+    type Stream = BoxBytesStream;
+
+    fn len(&self) -> u64 { self.len }
+    fn stream_range(&self, range: Range) -> BoxBytesStream {
+        let decoded = self.encoding.decode(Box::pin(self.inner.stream_range(Range { start: 0, end: self.inner.len() })));
+
+        let stream = decoded.scan(0u64, move |offset, chunk| {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => return future::ready(Some(Err(e))),
+            };
+            let chunk_start = *offset;
+            if chunk_start >= range.end {
+                return future::ready(None);
+            }
+            *offset += chunk.len() as u64;
+            let start = range.start.clamp(chunk_start, *offset) - chunk_start;
+            let end = range.end.min(*offset) - chunk_start;
+            future::ready(Some(Ok(chunk.slice(start as usize..end as usize))))
+        });
+
+        Box::pin(stream.try_filter(|chunk| future::ready(!chunk.is_empty())))
+    }
+}
+
+#[tokio::test]
+async fn test_decoded() {
+    use async_compression::tokio::bufread::GzipEncoder;
+    use tokio::io::AsyncReadExt;
+
+    let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+    let mut gzip = Vec::new();
+    GzipEncoder::new(&data[..]).read_to_end(&mut gzip).await.unwrap();
+
+    let decoded = Decoded { inner: Bytes::from(gzip), encoding: ContentEncoding::Gzip, len: data.len() as u64 };
+    for &(start, end) in &[(0, 100_000), (0, 10), (50_000, 50_001), (12_345, 99_999), (99_000, 100_000)] {
+        let buf: Vec<Bytes> = decoded.stream_range(Range { start, end }).try_collect().await.unwrap();
+        assert_eq!(buf.concat(), &data[start as usize..end as usize]);
+    }
+
+    let identity = Decoded { inner: Bytes::from_static(b"abcdef"), encoding: ContentEncoding::Identity, len: 6 };
+    let buf: Vec<Bytes> = identity.stream_range(Range { start: 2, end: 4 }).try_collect().await.unwrap();
+    assert_eq!(buf.concat(), b"cd");
+
+    let corrupt = Decoded { inner: Bytes::from_static(b"not gzip"), encoding: ContentEncoding::Gzip, len: 8 };
+    assert!(corrupt.stream_range(Range { start: 0, end: 8 }).try_collect::<Vec<_>>().await.is_err());
+
+    assert_eq!("x-gzip".parse(), Ok(ContentEncoding::Gzip));
+    assert_eq!("".parse(), Ok(ContentEncoding::Identity));
+    assert_eq!("zstd".parse::<ContentEncoding>(), Err(()));
+}
+
+/// Wraps a `StreamRange` so that each chunk is copied into a buffer that is zeroed once hyper has
+/// written it and dropped the last reference. The chunks of `inner` itself, and copies made by the
+/// kernel or TLS, aren't erased, but this shortens how long sensitive data stays in the heap.
+#[cfg(feature = "secure-erase")]
+pub struct SecureStreamRange<S>(pub S);
+
+#[cfg(feature = "secure-erase")]
+impl<S: StreamRange> StreamRange for SecureStreamRange<S> {
+    type Stream = stream::MapOk<S::Stream, fn(Bytes) -> Bytes>;
+
+    fn len(&self) -> u64 { self.0.len() }
+    fn stream_range(&self, range: Range) -> Self::Stream {
+        self.0.stream_range(range).map_ok(zeroizing_copy as fn(Bytes) -> Bytes)
+    }
+}
+
+/// Copy `chunk` into a `Bytes` whose memory is zeroed when it is dropped
+#[cfg(feature = "secure-erase")]
+fn zeroizing_copy(chunk: Bytes) -> Bytes {
+    Bytes::from_owner(zeroize::Zeroizing::new(chunk.to_vec()))
+}
+
+#[cfg(feature = "secure-erase")]
+#[tokio::test]
+async fn test_secure_stream_range() {
+    let data = Bytes::from((0..100u8).collect::<Vec<_>>());
+    let secure = SecureStreamRange(Concatenated(vec![Box::new(data.slice(..40)), Box::new(data.slice(40..))]));
+    assert_eq!(secure.len(), 100);
+
+    let chunks: Vec<Bytes> = secure.stream_range(Range { start: 30, end: 70 }).try_collect().await.unwrap();
+    assert_eq!(chunks.concat(), &data[30..70]);
+    // Each chunk is a copy, not a view of the original
+    assert_ne!(chunks[0].as_ptr(), data[30..].as_ptr());
+}
+
 /// A `StreamRange` constructed by concatentating multiple other `StreamRange` trait objects
-pub struct Concatenated(pub Vec<Box<dyn StreamRange>>);
+pub struct Concatenated(pub Vec<Box<dyn DynStreamRange>>);
 
 impl StreamRange for Concatenated {
+    type Stream = stream::Flatten<stream::Iter<std::vec::IntoIter<BoxBytesStream>>>;
+
     fn len(&self) -> u64 { self.0.iter().map(|x| x.len()).sum() }
-    fn stream_range(&self, mut range: Range) -> BoxBytesStream {
+    fn stream_range(&self, mut range: Range) -> Self::Stream {
         let mut streams = Vec::new();
         for part in &self.0 {
             if range.len() == 0 { break; }
@@ -105,6 +444,180 @@ impl StreamRange for Concatenated {
                 streams.push(part.stream_range(inner_range));
             }
         }
-        Box::pin(stream::iter(streams.into_iter()).flatten())
+        stream::iter(streams).flatten()
+    }
+}
+
+#[tokio::test]
+async fn test_connection_limit() {
+    use std::sync::atomic::{ AtomicUsize, Ordering };
+
+    let stats = Arc::new(Stats::new());
+    let limit = Arc::new(ConnectionLimit::new(3, stats.clone()));
+    let active = Arc::new(AtomicUsize::new(0));
+    let max_active = Arc::new(AtomicUsize::new(0));
+
+    let tasks: Vec<_> = (0..20).map(|_| {
+        let (limit, active, max_active) = (limit.clone(), active.clone(), max_active.clone());
+        tokio::spawn(async move {
+            let _permit = limit.acquire().await;
+            let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+            max_active.fetch_max(now, Ordering::SeqCst);
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+            active.fetch_sub(1, Ordering::SeqCst);
+        })
+    }).collect();
+
+    for task in tasks {
+        task.await.unwrap();
+    }
+
+    assert_eq!(max_active.load(Ordering::SeqCst), 3);
+    assert_eq!(stats.to_json(serde_json::Value::Null)["s3_requests"], 20);
+}
+
+#[test]
+fn test_chunked_bytes_stream_range() {
+    use futures::executor::block_on;
+
+    let data: Vec<u8> = (0..40).collect();
+    let chunks = vec![
+        Bytes::copy_from_slice(&data[0..10]),
+        Bytes::new(),
+        Bytes::copy_from_slice(&data[10..11]),
+        Bytes::copy_from_slice(&data[11..25]),
+        Bytes::copy_from_slice(&data[25..40]),
+    ];
+    let chunked = ChunkedBytesStreamRange::new(chunks.clone());
+    assert_eq!(chunked.len(), 40);
+
+    for start in 0..=40 {
+        for end in start..=40 {
+            let parts: Vec<Bytes> = block_on(chunked.stream_range(Range { start, end }).try_collect()).unwrap();
+            assert!(parts.iter().all(|p| !p.is_empty()));
+            assert_eq!(parts.concat(), &data[start as usize..end as usize]);
+        }
+    }
+
+    // Chunks are sliced rather than copied
+    let parts: Vec<Bytes> = block_on(chunked.stream_range(Range { start: 12, end: 30 }).try_collect()).unwrap();
+    assert_eq!(parts[0].as_ptr(), chunks[3][1..].as_ptr());
+    assert_eq!(parts[1].as_ptr(), chunks[4].as_ptr());
+
+    assert_eq!(ChunkedBytesStreamRange::new(Vec::new()).len(), 0);
+}
+
+#[test]
+fn test_rechunked() {
+    use futures::executor::block_on;
+
+    let data: Vec<u8> = (0..100).collect();
+    let chunks: Vec<Bytes> = [1, 2, 30, 7, 0, 45, 15].iter().scan(0, |start, &len| {
+        *start += len;
+        Some(Bytes::copy_from_slice(&data[*start - len..*start]))
+    }).collect();
+    let chunked = || ChunkedBytesStreamRange::new(chunks.clone());
+
+    for &chunk_size in &[1, 10, 64, 100, 1000] {
+        let rechunked = Rechunked { inner: chunked(), chunk_size };
+        for &(start, end) in &[(0, 100), (5, 95), (33, 34), (40, 40)] {
+            let parts: Vec<Bytes> = block_on(rechunked.stream_range(Range { start, end }).try_collect()).unwrap();
+            assert_eq!(parts.concat(), &data[start as usize..end as usize]);
+
+            // Every chunk but the last is full-size
+            let sizes: Vec<usize> = parts.iter().map(|p| p.len()).collect();
+            let len = (end - start) as usize;
+            let mut expected = vec![chunk_size; len / chunk_size];
+            expected.extend(Some(len % chunk_size).filter(|&rest| rest > 0));
+            assert_eq!(sizes, expected);
+        }
+    }
+
+    let unchanged = Rechunked { inner: chunked(), chunk_size: 0 };
+    let parts: Vec<Bytes> = block_on(unchanged.stream_range(Range { start: 0, end: 100 }).try_collect()).unwrap();
+    assert_eq!(parts.iter().map(|p| p.len()).collect::<Vec<_>>(), vec![1, 2, 30, 7, 45, 15]);
+}
+
+#[cfg(test)]
+pub(crate) mod alloc_count {
+    use std::alloc::{ GlobalAlloc, Layout, System };
+    use std::cell::Cell;
+
+    thread_local! {
+        /// Number of allocations, and size of the largest since `take_largest`
+        static ALLOCATIONS: Cell<(usize, usize)> = const { Cell::new((0, 0)) };
+    }
+
+    /// Counts allocations made by the current thread, and records the largest
+    struct Counting;
+
+    unsafe impl GlobalAlloc for Counting {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|n| {
+                let (count, largest) = n.get();
+                n.set((count + 1, largest.max(layout.size())));
+            });
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static GLOBAL: Counting = Counting;
+
+    pub fn allocations() -> usize { ALLOCATIONS.with(|n| n.get().0) }
+
+    /// Size of the largest allocation by the current thread since the last call
+    pub fn take_largest() -> usize { ALLOCATIONS.with(|n| n.replace((n.get().0, 0)).1) }
+}
+
+/// Ranges of `Bytes` point into the original buffer
+#[tokio::test]
+async fn test_bytes_stream_range_no_copy() {
+    let data = Bytes::from(vec![7u8; 1000]);
+    let chunks: Vec<Bytes> = data.stream_range(Range { start: 100, end: 600 }).try_collect().await.unwrap();
+    assert_eq!(chunks.len(), 1);
+    assert_eq!(chunks[0].len(), 500);
+    assert_eq!(chunks[0].as_ptr(), data[100..].as_ptr());
+}
+
+#[tokio::test]
+async fn test_bytes_mut_stream_range() {
+    let data = BytesMut::from(&b"hello world"[..]);
+    assert_eq!(StreamRange::len(&data), 11);
+    let chunks: Vec<Bytes> = data.stream_range(Range { start: 6, end: 11 }).try_collect().await.unwrap();
+    assert_eq!(chunks, vec![Bytes::from_static(b"world")]);
+    let chunks: Vec<Bytes> = data.stream_range(Range { start: 0, end: 11 }).try_collect().await.unwrap();
+    assert_eq!(chunks, vec![Bytes::from_static(b"hello world")]);
+}
+
+/// Streaming a range of `Bytes` through `StreamRange` doesn't box the stream
+#[test]
+fn test_bytes_stream_range_allocations() {
+    use std::task::{ Context, Poll };
+
+    let data = Bytes::from_static(&[0u8; 1000]);
+    let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+    let mut total = 0;
+
+    let before = alloc_count::allocations();
+    for i in 0..10_000 {
+        let range = Range { start: i % 500, end: 500 + i % 500 };
+        let mut stream = data.stream_range(range);
+        while let Poll::Ready(Some(chunk)) = Pin::new(&mut stream).poll_next(&mut cx) {
+            total += chunk.unwrap().len();
+        }
+    }
+    assert_eq!(alloc_count::allocations() - before, 0);
+    assert_eq!(total, 10_000 * 500);
+
+    // The boxed form used for concatenation allocates once per range
+    let before = alloc_count::allocations();
+    for _ in 0..10_000 {
+        drop(data.dyn_stream_range(Range { start: 0, end: 500 }));
     }
+    assert_eq!(alloc_count::allocations() - before, 10_000);
 }
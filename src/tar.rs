@@ -0,0 +1,241 @@
+// © 2019 3D Robotics. License: Apache-2.0
+use bytes::{Bytes, BytesMut, BufMut};
+use futures::TryStreamExt;
+use async_compression::tokio::bufread::GzipEncoder;
+use tokio_util::io::{ ReaderStream, StreamReader };
+use crate::stream_range::{ self, BoxBytesStream, BoxError, DynStreamRange, Range, StreamRange };
+use crate::zip::{ ZipEntry, ZipOptions, archive_entries, entries_before_checksums };
+
+const BLOCK: usize = 512;
+static ZEROS: [u8; 2 * BLOCK] = [0; 2 * BLOCK];
+
+/// Largest size that fits in the 11 octal digits of the ustar size field
+const MAX_USTAR_SIZE: u64 = 0o77777777777;
+
+/// Zero bytes padding `len` to a whole number of blocks
+fn padding(len: u64) -> Bytes {
+    Bytes::from_static(&ZEROS[..(BLOCK - len as usize % BLOCK) % BLOCK])
+}
+
+/// Write `value` as a NUL-terminated octal number filling `field`
+fn put_octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}\0", value, width = field.len() - 1);
+    field.copy_from_slice(digits.as_bytes());
+}
+
+/// A POSIX ustar header block
+fn ustar_header(name: &[u8], size: u64, mtime: i64, typeflag: u8, linkname: &[u8]) -> [u8; BLOCK] {
+    let mut header = [0u8; BLOCK];
+
+    header[..name.len().min(100)].copy_from_slice(&name[..name.len().min(100)]);
+    put_octal(&mut header[100..108], if typeflag == b'2' { 0o777 } else { 0o644 }); // mode
+    put_octal(&mut header[108..116], 0); // uid
+    put_octal(&mut header[116..124], 0); // gid
+    put_octal(&mut header[124..136], size.min(MAX_USTAR_SIZE)); // size
+    put_octal(&mut header[136..148], mtime.max(0) as u64); // mtime
+    header[156] = typeflag;
+    header[157..157 + linkname.len().min(100)].copy_from_slice(&linkname[..linkname.len().min(100)]);
+    header[257..263].copy_from_slice(b"ustar\0"); // magic
+    header[263..265].copy_from_slice(b"00"); // version
+
+    // Checksum is computed with the checksum field set to spaces
+    header[148..156].copy_from_slice(b"        ");
+    let checksum: u32 = header.iter().map(|&b| b as u32).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+
+    header
+}
+
+/// A pax extended header record, `"<len> <key>=<value>\n"` where `<len>` counts the whole record
+fn pax_record(key: &str, value: &str) -> String {
+    let rest = format!(" {}={}\n", key, value);
+    let mut len = rest.len();
+    while len.to_string().len() + rest.len() != len {
+        len = len.to_string().len() + rest.len();
+    }
+    format!("{}{}", len, rest)
+}
+
+/// Headers for one entry: a pax extended header if the name, link target, or size don't fit in
+/// the ustar fields, followed by the ustar header.
+fn entry_headers(file: &ZipEntry) -> Bytes {
+    let name = file.archive_path.as_str();
+    let link = file.symlink_target.as_deref().unwrap_or("");
+    let size = if file.symlink_target.is_some() { 0 } else { file.data.len() };
+
+    let mut pax = String::new();
+    if name.len() > 100 { pax.push_str(&pax_record("path", name)); }
+    if link.len() > 100 { pax.push_str(&pax_record("linkpath", link)); }
+    if size > MAX_USTAR_SIZE { pax.push_str(&pax_record("size", &size.to_string())); }
+
+    let mut buf = BytesMut::with_capacity(3 * BLOCK + pax.len());
+
+    if !pax.is_empty() {
+        buf.put_slice(&ustar_header(b"././@PaxHeader", pax.len() as u64, file.last_modified.timestamp(), b'x', b""));
+        buf.put_slice(pax.as_bytes());
+        buf.put_slice(&padding(pax.len() as u64));
+    }
+
+    let typeflag = if file.symlink_target.is_some() { b'2' } else { b'0' };
+    buf.put_slice(&ustar_header(name.as_bytes(), size, file.last_modified.timestamp(), typeflag, link.as_bytes()));
+
+    buf.freeze()
+}
+
+/// Create a `StreamRange` that produces a tar file with the passed entries.
+/// Entry names are transformed and the contents file added as for `zip_stream`.
+pub fn tar_stream(files: impl IntoIterator<Item = ZipEntry>, options: ZipOptions) -> impl StreamRange {
+    let mut parts: Vec<Box<dyn DynStreamRange>> = Vec::new();
+
+    for file in archive_entries(files, &options) {
+        parts.push(Box::new(entry_headers(&file)));
+
+        if file.symlink_target.is_none() {
+            let len = file.data.len();
+            parts.push(file.data);
+            parts.push(Box::new(padding(len)));
+        }
+    }
+
+    // End of archive
+    parts.push(Box::new(Bytes::from_static(&ZEROS)));
+
+    stream_range::Concatenated(parts)
+}
+
+/// Offset of the checksums file's headers in the tar made from `files`, as `zip_checksums_offset`
+pub fn tar_checksums_offset(files: &[ZipEntry], options: &ZipOptions) -> u64 {
+    entries_before_checksums(files, options).iter().map(|file| {
+        let data = match file.symlink_target {
+            None => file.data.len() + padding(file.data.len()).len() as u64,
+            Some(_) => 0,
+        };
+        entry_headers(file).len() as u64 + data
+    }).sum()
+}
+
+/// Create a gzip-compressed tar file, in chunks of about `chunk_size` bytes. Its length isn't
+/// known until it has been compressed, so unlike `tar_stream` it can only be streamed in full.
+pub fn tar_gz_stream(files: impl IntoIterator<Item = ZipEntry>, options: ZipOptions, chunk_size: usize) -> BoxBytesStream {
+    let tar = tar_stream(files, options);
+    let tar = tar.stream_range(Range { start: 0, end: tar.len() })
+        .map_err(std::io::Error::other);
+    let gzip = GzipEncoder::new(StreamReader::new(tar));
+    Box::pin(ReaderStream::with_capacity(gzip, chunk_size.max(1)).map_err(BoxError::from))
+}
+
+#[test]
+fn test_pax_record() {
+    assert_eq!(pax_record("path", "abc"), "12 path=abc\n");
+    assert_eq!(pax_record("path", &"a".repeat(93)), format!("103 path={}\n", "a".repeat(93)));
+}
+
+#[tokio::test]
+async fn test_tar_stream() {
+    use chrono::{ DateTime, Utc };
+    use futures::TryStreamExt;
+    use std::process::Command;
+    use crate::stream_range::Range;
+
+    let long_name = format!("{}/file.txt", "d".repeat(120));
+    let entry = |name: &str, data: &'static [u8], symlink_target: Option<&str>| ZipEntry {
+        archive_path: name.into(),
+        data: Box::new(Bytes::from_static(data)),
+        crc: 0,
+        last_modified: "2018-12-06T20:15:59Z".parse::<DateTime<Utc>>().unwrap(),
+        last_accessed: None,
+        created: None,
+        symlink_target: symlink_target.map(|t| t.into()),
+        sha256: None,
+    };
+    let entries = vec![
+        entry("foo.txt", b"xx", None),
+        entry(&long_name, b"ABC", None),
+        entry("link", b"foo.txt", Some("foo.txt")),
+    ];
+
+    let tar = tar_stream(entries, ZipOptions { archive_root: "export/".into(), ..ZipOptions::default() });
+    let buf: Vec<u8> = tar.stream_range(Range { start: 0, end: tar.len() }).map_ok(|b| b.to_vec()).try_concat().await.unwrap();
+    assert_eq!(buf.len() as u64, tar.len());
+    assert_eq!(buf.len() % BLOCK, 0);
+
+    std::fs::write("test_tar.tar", &buf).unwrap();
+    let listing = Command::new("tar").arg("-tvf").arg("test_tar.tar").output().unwrap();
+    assert!(listing.status.success());
+    let listing = String::from_utf8(listing.stdout).unwrap();
+    let lines: Vec<&str> = listing.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[0].starts_with("-rw-r--r--") && lines[0].ends_with("export/foo.txt"));
+    assert!(lines[1].ends_with(&format!("export/{}", long_name)));
+    assert!(lines[2].starts_with("lrwxrwxrwx") && lines[2].ends_with("export/link -> foo.txt"));
+
+    let contents = Command::new("tar").arg("-xOf").arg("test_tar.tar").arg("export/foo.txt").output().unwrap().stdout;
+    assert_eq!(contents, b"xx");
+}
+
+#[tokio::test]
+async fn test_tar_gz_stream() {
+    use chrono::{ DateTime, Utc };
+    use std::process::Command;
+
+    let entries = vec![ZipEntry {
+        archive_path: "dir/foo.txt".into(),
+        data: Box::new(Bytes::from("hello ".repeat(10_000))),
+        crc: 0,
+        last_modified: "2018-12-06T20:15:59Z".parse::<DateTime<Utc>>().unwrap(),
+        last_accessed: None,
+        created: None,
+        symlink_target: None,
+        sha256: None,
+    }];
+
+    let chunks: Vec<Bytes> = tar_gz_stream(entries, ZipOptions::default(), 1024).try_collect().await.unwrap();
+    assert!(chunks.iter().all(|c| c.len() <= 1024));
+    let buf: Vec<u8> = chunks.concat();
+    assert_eq!(&buf[..2], b"\x1f\x8b");
+    assert!(buf.len() < 10_000);
+
+    std::fs::write("test_tar_gz.tar.gz", &buf).unwrap();
+    let listing = Command::new("tar").arg("-tzf").arg("test_tar_gz.tar.gz").output().unwrap();
+    assert!(listing.status.success());
+    assert_eq!(String::from_utf8(listing.stdout).unwrap(), "dir/foo.txt\n");
+
+    let contents = Command::new("tar").arg("-xzOf").arg("test_tar_gz.tar.gz").arg("dir/foo.txt").output().unwrap().stdout;
+    assert_eq!(contents, "hello ".repeat(10_000).as_bytes());
+}
+
+/// Names too long for the ustar fields are read back from the pax `path` record
+#[tokio::test]
+async fn test_tar_long_name() {
+    use chrono::{ DateTime, Utc };
+    use futures::TryStreamExt;
+    use crate::stream_range::Range;
+
+    let long_name = format!("{}/file.txt", "d".repeat(503));
+    assert_eq!(long_name.len(), 512);
+    let entries = vec![ZipEntry {
+        archive_path: long_name.clone(),
+        data: Box::new(Bytes::from_static(b"ABC")),
+        crc: 0,
+        last_modified: "2018-12-06T20:15:59Z".parse::<DateTime<Utc>>().unwrap(),
+        last_accessed: None,
+        created: None,
+        symlink_target: None,
+        sha256: None,
+    }];
+
+    let tar = tar_stream(entries, ZipOptions::default());
+    let buf: Vec<u8> = tar.stream_range(Range { start: 0, end: tar.len() }).map_ok(|b| b.to_vec()).try_concat().await.unwrap();
+    assert_eq!(&buf[156], &b'x');
+
+    let mut archive = tar::Archive::new(&buf[..]);
+    let mut entries = archive.entries().unwrap();
+    let mut entry = entries.next().unwrap().unwrap();
+    assert_eq!(entry.path().unwrap().to_str().unwrap(), long_name);
+    assert_eq!(entry.header().entry_type(), tar::EntryType::Regular);
+    let mut contents = Vec::new();
+    std::io::Read::read_to_end(&mut entry, &mut contents).unwrap();
+    assert_eq!(contents, b"ABC");
+    drop(entry);
+    assert!(entries.next().is_none());
+}
@@ -0,0 +1,99 @@
+// © 2019 3D Robotics. License: Apache-2.0
+use std::error::Error;
+use std::path::Path;
+use std::time::Duration;
+use hyper::client::HttpConnector;
+use hyper_tls::HttpsConnector;
+use native_tls::{ Identity, Protocol };
+use openssl::{ pkcs12::Pkcs12, pkey::PKey, stack::Stack, x509::X509 };
+
+/// Parse a `--tls-min-version` value
+pub fn parse_min_version(s: &str) -> Result<Protocol, String> {
+    match s {
+        "1.0" => Ok(Protocol::Tlsv10),
+        "1.1" => Ok(Protocol::Tlsv11),
+        "1.2" => Ok(Protocol::Tlsv12),
+        _ => Err(format!("Unsupported TLS version {:?}, expected 1.0, 1.1, or 1.2", s)),
+    }
+}
+
+/// Load a client certificate for mutual TLS from PEM files. `cert_path` may also contain the
+/// intermediate certificates to send after the client certificate.
+pub fn load_identity(cert_path: &Path, key_path: &Path) -> Result<Identity, Box<dyn Error + Send + Sync>> {
+    let read = |path: &Path| std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e));
+
+    let mut certs = X509::stack_from_pem(&read(cert_path)?)?.into_iter();
+    let cert = certs.next().ok_or_else(|| format!("No certificate in {}", cert_path.display()))?;
+    let key = PKey::private_key_from_pem(&read(key_path)?)?;
+
+    let mut chain = Stack::new()?;
+    for cert in certs {
+        chain.push(cert)?;
+    }
+
+    // native-tls only accepts identities as PKCS #12, which is only used in memory here
+    let mut builder = Pkcs12::builder();
+    builder.ca(chain);
+    let der = builder.build("", "zipstream", &key, &cert)?.to_der()?;
+    Ok(Identity::from_pkcs12(&der, "")?)
+}
+
+/// Connector for outbound HTTPS requests to the upstream server and S3, which fails to connect to
+/// servers that don't support `min_version` or later, and presents `identity` to servers that
+/// request a client certificate. Cipher suites are chosen by the platform's TLS library according
+/// to its system-wide policy. TCP connections not established within `connect_timeout` fail.
+///
+/// With `http2`, only HTTP/2 is offered by ALPN, for a client built with `http2_only`: hyper-tls
+/// doesn't tell hyper which protocol was negotiated, so the client can't choose per connection.
+pub fn https_connector(min_version: Protocol, identity: Option<Identity>, connect_timeout: Option<Duration>, http2: bool) -> HttpsConnector<HttpConnector> {
+    let mut builder = native_tls::TlsConnector::builder();
+    builder.min_protocol_version(Some(min_version));
+    if let Some(identity) = identity {
+        builder.identity(identity);
+    }
+    if http2 {
+        builder.request_alpns(&["h2"]);
+    }
+    let tls = builder.build().expect("failed to create TLS connector");
+
+    let mut http = HttpConnector::new();
+    http.enforce_http(false);
+    http.set_connect_timeout(connect_timeout);
+    HttpsConnector::from((http, tls.into()))
+}
+
+#[test]
+fn test_parse_min_version() {
+    assert!(matches!(parse_min_version("1.2"), Ok(Protocol::Tlsv12)));
+    assert!(matches!(parse_min_version("1.0"), Ok(Protocol::Tlsv10)));
+    assert!(parse_min_version("1.3").is_err());
+    assert!(parse_min_version("tls1.2").is_err());
+}
+
+#[test]
+fn test_load_identity() {
+    use std::process::Command;
+
+    let dir = std::env::temp_dir().join(format!("zipstream-test-identity-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let generate = |name: &str| {
+        let status = Command::new("openssl")
+            .args(["req", "-x509", "-newkey", "rsa:2048", "-nodes", "-days", "1", "-subj", "/CN=zipstream"])
+            .arg("-keyout").arg(dir.join(format!("{}.key", name)))
+            .arg("-out").arg(dir.join(format!("{}.pem", name)))
+            .output().unwrap().status;
+        assert!(status.success());
+    };
+    generate("client");
+    generate("other");
+
+    let identity = load_identity(&dir.join("client.pem"), &dir.join("client.key")).unwrap();
+    https_connector(Protocol::Tlsv12, Some(identity), None, false);
+
+    assert!(load_identity(&dir.join("client.pem"), &dir.join("other.key")).is_err(), "key doesn't match certificate");
+    assert!(load_identity(&dir.join("client.key"), &dir.join("client.key")).is_err(), "not a certificate");
+    let err = load_identity(&dir.join("missing.pem"), &dir.join("client.key")).err().unwrap();
+    assert!(err.to_string().contains("missing.pem"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -1,31 +1,276 @@
 // © 2019 3D Robotics. License: Apache-2.0
-use crate::Config;
-use crate::stream_range::{ StreamRange, S3Object };
-use crate::serve_range::hyper_response;
-use crate::zip::{ ZipEntry, ZipOptions, zip_stream };
+use crate::{ Config, State };
+use crate::stream_range::{ self, ContentEncoding, Decoded, DynStreamRange, Range, StreamRange, S3Object, Rechunked };
+use crate::serve_range::{ hyper_response, hyper_stream_response, multipart_content_type, multipart_form_data, requested_range };
+use crate::zip::{ ZipEntry, ZipOptions, archive_entries, layout_copy, normalize_archive_root, zip_checksums_offset, zip_stream };
 use crate::s3url::S3Url;
+use crate::redact::{ s3_url, Redacted };
+use crate::tar::{ tar_checksums_offset, tar_gz_stream, tar_stream };
+use crate::wildcard;
+use crate::s3_regions::MultiRegionS3Client;
+use crate::entry_filter::EntryFilter;
+use crate::object_lock::{ self, ObjectLock };
+use crate::preflight;
+use crate::manifest_transform;
 
-use std::sync::Arc;
-use hyper::{header, Body, Request, Response, Uri, Method, StatusCode};
+use std::collections::HashMap;
+use hyper::{header, http, Body, Client, HeaderMap, Request, Response, Uri, Method, StatusCode, client::connect::Connect};
 use serde_derive::Deserialize;
-use rusoto_s3::S3;
 use log;
 use std::hash::{ Hash, Hasher };
-use chrono::{DateTime, Utc};
+use std::time::Duration;
+use sha2::{ Digest, Sha256 };
+use chrono::{DateTime, TimeZone, Utc};
+use bytes::{ Bytes, BytesMut };
+use base64::Engine;
+use futures::{ future, stream, StreamExt, TryStreamExt };
+use async_compression::tokio::bufread::{ BrotliDecoder, GzipDecoder, ZlibDecoder };
+use tokio::io::{ AsyncRead, AsyncReadExt };
 
 #[derive(Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-struct ZipFileDescription {
+pub struct ZipFileDescription {
+    pub archive_name: String,
+    pub source: S3Url,
+    length: u64,
+    /// Read from S3 to compute it if absent
+    #[serde(alias = "crc32")]
+    crc: Option<u32>,
+    last_modified: DateTime<Utc>,
+    #[serde(default)]
+    last_accessed: Option<DateTime<Utc>>,
+    #[serde(default)]
+    created: Option<DateTime<Utc>>,
+    /// Region of the source bucket, saving a `GetBucketLocation` lookup
+    #[serde(default)]
+    region: Option<String>,
+    /// Store the object's content decoded from its `Content-Encoding`, rather than as stored.
+    /// `length` and `crc` are then measured from the object.
+    #[serde(default)]
+    decode_s3_encoding: bool,
+}
+
+/// An entry whose source is `s3://bucket/prefix*`, expanded to every object under the prefix.
+/// `archive_name` is prepended to each object's key relative to the prefix.
+#[derive(Deserialize, Clone, Debug)]
+pub struct WildcardDescription {
+    #[serde(default)]
+    pub archive_name: String,
+    pub source: S3Url,
+    #[serde(default)]
+    region: Option<String>,
+}
+
+/// One S3 object of a `PartsDescription`
+#[derive(Deserialize, Clone, Debug)]
+pub struct PartDescription {
+    pub source: S3Url,
+    pub length: u64,
+}
+
+/// A file stored as several S3 objects, which are concatenated in order into one entry
+#[derive(Deserialize, Clone, Debug)]
+pub struct PartsDescription {
+    pub archive_name: String,
+    pub parts: Vec<PartDescription>,
+    /// Of the concatenated parts. Read from S3 to compute it if absent
+    #[serde(alias = "crc32")]
+    crc: Option<u32>,
+    last_modified: DateTime<Utc>,
+}
+
+/// A small file whose content is included in the manifest rather than fetched from S3
+#[derive(Deserialize, Clone, Debug)]
+pub struct InlineDescription {
+    #[serde(alias = "name")]
+    pub archive_name: String,
+    content_base64: String,
+    #[serde(default = "default_inline_last_modified")]
+    last_modified: DateTime<Utc>,
+}
+
+/// A symbolic link within the archive, stored with `target` as its content
+#[derive(Deserialize, Clone, Debug)]
+pub struct SymlinkDescription {
+    #[serde(alias = "name")]
+    pub archive_name: String,
+    symlink_target: String,
+    #[serde(default = "default_inline_last_modified")]
+    last_modified: DateTime<Utc>,
+}
+
+/// The earliest time representable in a zip file
+fn default_inline_last_modified() -> DateTime<Utc> {
+    Utc.ymd(1980, 1, 1).and_hms(0, 0, 0)
+}
+
+/// An entry of an upstream manifest, before wildcards are expanded
+#[derive(Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum ManifestEntry {
+    File(ZipFileDescription),
+    Parts(PartsDescription),
+    Wildcard(WildcardDescription),
+    Inline(InlineDescription),
+    Symlink(SymlinkDescription),
+}
+
+/// Archive format of the response
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+enum Format {
+    #[default]
+    Zip,
+    Tar,
+    /// A gzip-compressed tar file, whose length isn't known until it has been streamed
+    #[serde(rename = "tar.gz")]
+    TarGz,
+}
+
+impl Format {
+    const ALL: [Format; 3] = [Format::Zip, Format::Tar, Format::TarGz];
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Format::Zip => "application/zip",
+            Format::Tar => "application/x-tar",
+            Format::TarGz => "application/gzip",
+        }
+    }
+
+    /// Download filename, replacing a `.zip` extension for other formats
+    fn filename(self, filename: &str) -> String {
+        match self {
+            Format::Zip => filename.to_owned(),
+            Format::Tar => format!("{}.tar", filename.strip_suffix(".zip").unwrap_or(filename)),
+            Format::TarGz => format!("{}.tar.gz", filename.strip_suffix(".zip").unwrap_or(filename)),
+        }
+    }
+}
+
+/// Choose a format from the request's Accept header. `default` is used unless the client prefers
+/// another format, and an error is returned if the client accepts none.
+fn negotiate_format(req: &Request<Body>, default: Format) -> Result<Format, (StatusCode, &'static str)> {
+    let accept = match req.headers().get(header::ACCEPT).and_then(|v| v.to_str().ok()) {
+        Some(accept) => accept,
+        None => return Ok(default),
+    };
+
+    // Quality of the most specific media range matching each format
+    let quality = |format: Format| {
+        let (type_, _) = format.content_type().split_at(format.content_type().find('/').unwrap());
+        accept.split(',').filter_map(|range| {
+            let mut params = range.split(';').map(str::trim);
+            let media_range = params.next()?.to_ascii_lowercase();
+            let q = params.find_map(|p| p.strip_prefix("q=")).map_or(Some(1.0), |q| q.parse::<f32>().ok())?;
+
+            let specificity = if media_range == format.content_type() {
+                2
+            } else if media_range == format!("{}/*", type_) {
+                1
+            } else if media_range == "*/*" {
+                0
+            } else {
+                return None;
+            };
+            Some((specificity, q))
+        }).max_by_key(|&(specificity, _)| specificity).map_or(0.0, |(_, q)| q)
+    };
+
+    let default_q = quality(default);
+    let (other, other_q) = Format::ALL.iter().filter(|&&f| f != default)
+        .map(|&f| (f, quality(f)))
+        .fold((default, 0.0), |best, (f, q)| if q > best.1 { (f, q) } else { best });
+
+    if default_q > 0.0 && default_q >= other_q {
+        Ok(default)
+    } else if other_q > 0.0 {
+        Ok(other)
+    } else {
+        log::info!("No supported format in Accept: {}", accept);
+        Err((StatusCode::NOT_ACCEPTABLE, "Requested format not supported"))
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct UpstreamManifest {
+    filename: String,
+    entries: Vec<ManifestEntry>,
+    #[serde(default)]
+    format: Format,
+    archive_comment: Option<String>,
+    password: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum EntrySource {
+    S3(S3Url),
+    /// Objects concatenated in order, with their lengths
+    S3Parts(Vec<(S3Url, u64)>),
+    Inline(Bytes),
+}
+
+impl EntrySource {
+    /// The S3 objects the entry is read from
+    fn s3_urls(&self) -> Vec<&S3Url> {
+        match self {
+            EntrySource::S3(url) => vec![url],
+            EntrySource::S3Parts(parts) => parts.iter().map(|(url, _)| url).collect(),
+            EntrySource::Inline(_) => Vec::new(),
+        }
+    }
+}
+
+/// A zip entry after wildcards are expanded and inline content decoded
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+struct ResolvedEntry {
     archive_name: String,
-    source: S3Url,
+    source: EntrySource,
     length: u64,
     crc: u32,
     last_modified: DateTime<Utc>,
+    last_accessed: Option<DateTime<Utc>>,
+    created: Option<DateTime<Utc>>,
+    symlink: bool,
+    /// Encoding and stored length of an S3 object that is decoded into the archive
+    encoded: Option<(ContentEncoding, u64)>,
+}
+
+impl From<ZipFileDescription> for ResolvedEntry {
+    fn from(file: ZipFileDescription) -> ResolvedEntry {
+        ResolvedEntry {
+            archive_name: file.archive_name,
+            source: EntrySource::S3(file.source),
+            length: file.length,
+            crc: file.crc.unwrap_or(0), // filled in by `resolve_entries` if absent
+            last_modified: file.last_modified,
+            last_accessed: file.last_accessed,
+            created: file.created,
+            symlink: false,
+            encoded: None, // filled in by `resolve_entries` if decoded
+        }
+    }
+}
+
+impl From<PartsDescription> for ResolvedEntry {
+    fn from(file: PartsDescription) -> ResolvedEntry {
+        ResolvedEntry {
+            archive_name: file.archive_name,
+            length: file.parts.iter().map(|part| part.length).sum(),
+            source: EntrySource::S3Parts(file.parts.into_iter().map(|part| (part.source, part.length)).collect()),
+            crc: file.crc.unwrap_or(0), // filled in by `resolve_entries` if absent
+            last_modified: file.last_modified,
+            last_accessed: None,
+            created: None,
+            symlink: false,
+            encoded: None,
+        }
+    }
 }
 
-#[derive(Deserialize, Clone, Debug, Hash)]
+#[derive(Clone, Debug, Hash)]
 struct UpstreamResponse {
     filename: String,
-    entries: Vec<ZipFileDescription>,
+    entries: Vec<ResolvedEntry>,
 }
 
 static KEEP_HEADERS: &[header::HeaderName] = &[
@@ -35,67 +280,1535 @@ static KEEP_HEADERS: &[header::HeaderName] = &[
     header::REFERER,
 ];
 
-/// Modify a client request into an upstream request
-pub fn request(config: &Config, req: &Request<Body>) -> Result<Request<Body>, (StatusCode, &'static str)> {
-    if req.method() != Method::GET {
-        return Err((StatusCode::METHOD_NOT_ALLOWED, "Only GET requests allowed"))
+/// The request path used to select a manifest from `--manifest-source`, with the strip prefix removed
+pub fn manifest_path<'a>(config: &Config, req: &'a Request<Body>) -> Result<&'a str, (StatusCode, &'static str)> {
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return Err((StatusCode::METHOD_NOT_ALLOWED, "Only GET and HEAD requests allowed"))
     }
 
-    let mut new_req = Request::builder().uri({
-        let req_path = req.uri().path_and_query().expect("request URL should have path").as_str();
+    req.uri().path().strip_prefix(config.strip_prefix(req)).ok_or((StatusCode::NOT_FOUND, "Not found"))
+}
+
+/// Collapse repeated slashes and resolve `.` and `..` segments in a request path, so that it
+/// matches `--strip-prefix` and the upstream server's routes however the client wrote it.
+/// Returns `None` if a `..` segment would go above the root.
+fn normalize_path(path: &str, strip_trailing_slash: bool) -> Option<String> {
+    let mut segments = Vec::new();
+    let mut trailing_slash = false;
 
-        if !req_path.starts_with(&config.strip_prefix) {
-            return Err((StatusCode::NOT_FOUND, "Not found"))
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => { segments.pop()?; }
+            s => segments.push(s),
         }
+        trailing_slash = matches!(segment, "" | "." | "..");
+    }
+
+    let mut normalized = format!("/{}", segments.join("/"));
+    if trailing_slash && !strip_trailing_slash && !segments.is_empty() {
+        normalized.push('/');
+    }
+    Some(normalized)
+}
+
+#[test]
+fn test_normalize_path() {
+    let normalize = |path| normalize_path(path, false);
+    assert_eq!(normalize("/test.zip").as_deref(), Some("/test.zip"));
+    assert_eq!(normalize("/").as_deref(), Some("/"));
+    assert_eq!(normalize("//prefix///a//test.zip").as_deref(), Some("/prefix/a/test.zip"));
+    assert_eq!(normalize("/prefix/./a/./test.zip").as_deref(), Some("/prefix/a/test.zip"));
+    assert_eq!(normalize("/prefix/a/../b/test.zip").as_deref(), Some("/prefix/b/test.zip"));
+    assert_eq!(normalize("/prefix/dir/").as_deref(), Some("/prefix/dir/"));
+    assert_eq!(normalize("/prefix/dir/a/..").as_deref(), Some("/prefix/dir/"));
+    assert_eq!(normalize("/a/b/../../").as_deref(), Some("/"));
+
+    assert_eq!(normalize("/.."), None);
+    assert_eq!(normalize("/prefix/../../etc/passwd"), None);
+    assert_eq!(normalize("/a/./../.."), None);
+
+    assert_eq!(normalize_path("/prefix/dir/", true).as_deref(), Some("/prefix/dir"));
+    assert_eq!(normalize_path("//", true).as_deref(), Some("/"));
+}
+
+/// Query parameters that zipstream itself interprets, which `--upstream-query-passthrough` and
+/// `--upstream-pass-query-params` don't forward upstream
+const INTERNAL_QUERY_PARAMS: &[&str] = &["format", "dry_run", "prefix", "list", "mode", "filter", "expires", "signature"];
+
+/// Which query parameters of a client request are sent on to the upstream server
+#[derive(Clone, Debug, PartialEq)]
+pub enum UpstreamQuery {
+    /// The query string as received
+    Raw,
+    /// All parameters except `INTERNAL_QUERY_PARAMS`
+    Passthrough,
+    /// Only the named parameters, except `INTERNAL_QUERY_PARAMS`
+    Only(Vec<String>),
+}
+
+impl UpstreamQuery {
+    /// The query string for the upstream request, or `None` to send none
+    fn apply(&self, query: Option<&str>) -> Option<String> {
+        let query = query?;
+        let only = match self {
+            UpstreamQuery::Raw => return Some(query.to_owned()),
+            UpstreamQuery::Passthrough => None,
+            UpstreamQuery::Only(names) => Some(names),
+        };
+
+        let mut serializer = form_urlencoded::Serializer::new(String::new());
+        form_urlencoded::parse(query.as_bytes())
+            .filter(|(k, _)| !INTERNAL_QUERY_PARAMS.contains(&&**k))
+            .filter(|(k, _)| only.is_none_or(|names| names.iter().any(|n| n == k)))
+            .for_each(|(k, v)| { serializer.append_pair(&k, &v); });
+        Some(serializer.finish()).filter(|q| !q.is_empty())
+    }
+}
+
+#[test]
+fn test_upstream_query() {
+    let query = Some("dataset=foo&list=json&version=3&dry_run=true&date=2021-06-01&prefix=a%20b");
+    assert_eq!(UpstreamQuery::Raw.apply(query).as_deref(), query);
+    assert_eq!(UpstreamQuery::Passthrough.apply(query).as_deref(), Some("dataset=foo&version=3&date=2021-06-01"));
+    let only = UpstreamQuery::Only(vec!["date".into(), "version".into(), "prefix".into()]);
+    assert_eq!(only.apply(query).as_deref(), Some("version=3&date=2021-06-01"));
+    assert_eq!(only.apply(Some("list=json&dataset=foo")), None);
+    assert_eq!(UpstreamQuery::Passthrough.apply(Some("q=a+b%26c")).as_deref(), Some("q=a+b%26c"));
+    assert_eq!(UpstreamQuery::Passthrough.apply(None), None);
+}
+
+/// Modify a client request into a request to the upstream server at `upstream`.
+/// `HEAD` requests are sent upstream as `GET` so that a manifest is returned.
+pub fn request(config: &Config, upstream: &str, req: &Request<Body>) -> Result<Request<Body>, (StatusCode, &'static str)> {
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return Err((StatusCode::METHOD_NOT_ALLOWED, "Only GET and HEAD requests allowed"))
+    }
 
-        format!("{}{}", config.upstream, &req_path[config.strip_prefix.len()..]).parse::<Uri>().unwrap()
-    }).header("X-Via-Zip-Stream", config.via_zip_stream_header_value.clone());
+    let mut new_req = Request::builder().uri({
+        let path = normalize_path(req.uri().path(), config.strip_trailing_slash).ok_or_else(|| {
+            log::info!("Request path {} escapes the root", Redacted(req.uri().path()));
+            (StatusCode::BAD_REQUEST, "Invalid path")
+        })?;
+
+        let path = path.strip_prefix(config.strip_prefix(req)).ok_or((StatusCode::NOT_FOUND, "Not found"))?;
+
+        match config.upstream_query.apply(req.uri().query()) {
+            Some(query) => format!("{}{}?{}", upstream, path, query),
+            None => format!("{}{}", upstream, path),
+        }.parse::<Uri>().unwrap()
+    }).header(&config.via_zip_stream_header_name, config.via_zip_stream_header_value.clone());
 
     for header in KEEP_HEADERS {
         if let Some(value) = req.headers().get(header) {
             new_req = new_req.header(header, value);
         }
     }
+
+    if config.upstream_decompress {
+        new_req = new_req.header(header::ACCEPT_ENCODING, "gzip, deflate, br");
+    }
     
     Ok(new_req.body(Body::empty()).unwrap())
 }
 
-/// Parse an upstream JSON response and produce a streaming zip file response
-pub fn response(s3: &Arc<dyn S3 + Send + Sync>, req: &Request<Body>, response_body: &[u8]) -> Result<Response<Body>, (StatusCode, &'static str)> {
-    let mut res: UpstreamResponse = serde_json::from_slice(response_body).map_err(|e| {
-        log::error!("Invalid upstream response JSON: {}", e);
+/// Read a zip manifest response body, failing if its `Content-Length` or the bytes actually
+/// received exceed `limit`, or if no data arrives for `read_timeout`.
+pub async fn read_manifest(body: Body, headers: &HeaderMap, limit: u64, read_timeout: Duration) -> Result<Bytes, (StatusCode, &'static str)> {
+    let too_large = |len: u64| {
+        log::error!("Upstream manifest response of at least {} bytes exceeds limit of {} bytes", len, limit);
+        (StatusCode::BAD_GATEWAY, "Upstream response exceeded size limit")
+    };
+
+    let declared_len = headers.get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+
+    if let Some(len) = declared_len.filter(|&len| len > limit) {
+        return Err(too_large(len));
+    }
+
+    let mut buf = BytesMut::with_capacity(declared_len.unwrap_or(0) as usize);
+    futures::pin_mut!(body);
+    loop {
+        let chunk = match tokio::time::timeout(read_timeout, body.next()).await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(_) => {
+                log::error!("No data from upstream body for {:?}", read_timeout);
+                return Err((StatusCode::GATEWAY_TIMEOUT, "Upstream response body timed out"));
+            }
+        };
+        let chunk = chunk.map_err(|e| {
+            log::error!("Failed to read upstream body: {}", e);
+            (StatusCode::SERVICE_UNAVAILABLE, "Upstream request failed")
+        })?;
+
+        let len = (buf.len() + chunk.len()) as u64;
+        if len > limit {
+            return Err(too_large(len));
+        }
+        buf.extend_from_slice(&chunk);
+    }
+
+    Ok(buf.freeze())
+}
+
+/// Decompress a manifest sent with `Content-Encoding: gzip`, `deflate`, or `br`, failing if it
+/// expands to more than `limit` bytes.
+pub async fn decode_manifest(body: Bytes, headers: &HeaderMap, limit: u64) -> Result<Bytes, (StatusCode, &'static str)> {
+    let encoding = match headers.get(header::CONTENT_ENCODING).and_then(|v| v.to_str().ok()) {
+        Some(encoding) => encoding.trim().to_ascii_lowercase(),
+        None => return Ok(body),
+    };
+
+    let decoder: std::pin::Pin<Box<dyn AsyncRead + Send + '_>> = match encoding.as_str() {
+        "identity" => return Ok(body),
+        "gzip" | "x-gzip" => Box::pin(GzipDecoder::new(&body[..])),
+        // HTTP's `deflate` is the zlib format
+        "deflate" => Box::pin(ZlibDecoder::new(&body[..])),
+        "br" => Box::pin(BrotliDecoder::new(&body[..])),
+        _ => {
+            log::error!("Unsupported Content-Encoding {:?} on upstream manifest", encoding);
+            return Err((StatusCode::BAD_GATEWAY, "Unsupported upstream Content-Encoding"));
+        }
+    };
+
+    let mut decoded = Vec::new();
+    decoder.take(limit + 1).read_to_end(&mut decoded).await.map_err(|e| {
+        log::error!("Failed to decompress {} upstream manifest: {}", encoding, e);
+        (StatusCode::BAD_GATEWAY, "Failed to decompress upstream response")
+    })?;
+
+    if decoded.len() as u64 > limit {
+        log::error!("Upstream manifest decompresses to more than the limit of {} bytes", limit);
+        return Err((StatusCode::BAD_GATEWAY, "Upstream response exceeded size limit"));
+    }
+
+    Ok(decoded.into())
+}
+
+/// Pass a non-zip upstream response through to the client, enforcing a maximum body size.
+///
+/// A response whose `Content-Length` exceeds the limit is rejected outright; otherwise the body is
+/// counted as it streams and aborted with an error once it passes the limit.
+pub fn proxy_response(res: Response<Body>, max_body: Option<u64>) -> Result<Response<Body>, (StatusCode, &'static str)> {
+    let max_body = match max_body {
+        Some(max_body) => max_body,
+        None => return Ok(res),
+    };
+
+    let declared_len = res.headers().get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+
+    if let Some(len) = declared_len.filter(|&len| len > max_body) {
+        log::error!("Upstream response of {} bytes exceeds proxy body limit of {} bytes", len, max_body);
+        return Err((StatusCode::BAD_GATEWAY, "Upstream response too large"));
+    }
+
+    let (parts, body) = res.into_parts();
+    let mut received = 0u64;
+
+    let body = body.map_err(BoxError::from).and_then(move |chunk| {
+        received += chunk.len() as u64;
+        future::ready(if received > max_body {
+            log::error!("Upstream response exceeded proxy body limit of {} bytes, aborting", max_body);
+            Err("upstream response exceeded proxy body limit".into())
+        } else {
+            Ok(chunk)
+        })
+    });
+
+    Ok(Response::from_parts(parts, Body::wrap_stream(body)))
+}
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// State for resuming a proxied response body after a mid-body failure
+struct ResumableBody<C> {
+    client: Client<C>,
+    req: http::request::Parts,
+    validator: Option<header::HeaderValue>,
+    body: Body,
+    sent: u64,
+    retries: u32,
+}
+
+impl<C: Connect + Clone + Send + Sync + 'static> ResumableBody<C> {
+    /// Request the remainder of the body, starting at the number of bytes already sent
+    async fn resume(&self) -> Result<Body, BoxError> {
+        let mut req = Request::builder().method(Method::GET).uri(self.req.uri.clone());
+        for (name, value) in &self.req.headers {
+            req = req.header(name, value);
+        }
+        req = req.header(header::RANGE, format!("bytes={}-", self.sent));
+        if let Some(validator) = &self.validator {
+            req = req.header(header::IF_RANGE, validator);
+        }
+
+        let res = self.client.request(req.body(Body::empty()).unwrap()).await?;
+
+        let content_range_start = res.headers().get(header::CONTENT_RANGE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("bytes "))
+            .and_then(|v| v.split('-').next())
+            .and_then(|v| v.parse::<u64>().ok());
+
+        if res.status() != StatusCode::PARTIAL_CONTENT || content_range_start != Some(self.sent) {
+            return Err(format!("upstream resume request returned {} with Content-Range starting at {:?}", res.status(), content_range_start).into());
+        }
+
+        Ok(res.into_body())
+    }
+}
+
+/// Wrap a proxied upstream response so that if the upstream connection fails mid-body, the
+/// remainder is requested again with a `Range` header and spliced in, up to `retries` times.
+///
+/// Only applies to `200 OK` responses that advertise `Accept-Ranges: bytes`. The response's `ETag`
+/// or `Last-Modified` is sent as `If-Range` so that a changed resource is not spliced together.
+pub fn resumable_response<C>(client: Client<C>, upstream_req: Request<Body>, res: Response<Body>, retries: u32) -> Response<Body>
+    where C: Connect + Clone + Send + Sync + 'static
+{
+    let accepts_ranges = res.headers().get(header::ACCEPT_RANGES).and_then(|v| v.to_str().ok()) == Some("bytes");
+
+    if retries == 0 || res.status() != StatusCode::OK || !accepts_ranges {
+        return res;
+    }
+
+    let validator = res.headers().get(header::ETAG).or_else(|| res.headers().get(header::LAST_MODIFIED)).cloned();
+    let (parts, body) = res.into_parts();
+
+    let state = ResumableBody { client, req: upstream_req.into_parts().0, validator, body, sent: 0, retries };
+
+    let body = stream::unfold(Some(state), |state| async move {
+        let mut state = state?;
+        loop {
+            match state.body.next().await {
+                Some(Ok(chunk)) => {
+                    state.sent += chunk.len() as u64;
+                    return Some((Ok(chunk), Some(state)));
+                }
+                Some(Err(e)) if state.retries > 0 => {
+                    state.retries -= 1;
+                    log::warn!("Upstream body failed after {} bytes, resuming: {}", state.sent, e);
+                    match state.resume().await {
+                        Ok(body) => state.body = body,
+                        Err(e) => {
+                            log::error!("Failed to resume upstream body: {}", e);
+                            return Some((Err(e), None));
+                        }
+                    }
+                }
+                Some(Err(e)) => return Some((Err(BoxError::from(e)), None)),
+                None => return None,
+            }
+        }
+    });
+
+    Response::from_parts(parts, Body::wrap_stream(body))
+}
+
+/// An entry whose content is held in memory
+fn inline_entry(archive_name: String, content: Vec<u8>, last_modified: DateTime<Utc>, symlink: bool) -> ResolvedEntry {
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(&content);
+
+    ResolvedEntry {
+        archive_name,
+        length: content.len() as u64,
+        crc: crc.finalize(),
+        source: EntrySource::Inline(content.into()),
+        last_modified,
+        last_accessed: None,
+        created: None,
+        symlink,
+        encoded: None,
+    }
+}
+
+/// Decode the content of an inline entry, limited to `max_bytes`
+fn decode_inline(inline: InlineDescription, max_bytes: usize) -> Result<ResolvedEntry, (StatusCode, &'static str)> {
+    let content = base64::engine::general_purpose::STANDARD.decode(&inline.content_base64).map_err(|e| {
+        log::error!("Invalid base64 content for inline entry {}: {}", inline.archive_name, e);
         (StatusCode::INTERNAL_SERVER_ERROR, "Failed to parse upstream request")
     })?;
 
+    if content.len() > max_bytes {
+        log::error!("Inline entry {} is {} bytes, over the limit of {}", inline.archive_name, content.len(), max_bytes);
+        return Err((StatusCode::BAD_REQUEST, "Inline content too large"));
+    }
+
+    Ok(inline_entry(inline.archive_name, content, inline.last_modified, false))
+}
+
+/// Check S3 keys, expand wildcard entries by listing S3, decode inline entries, and compute CRCs
+/// missing from the manifest. Without `read_crcs`, as for a dry run that only needs sizes, missing
+/// CRCs are left as 0 rather than read.
+async fn resolve_entries(s3: &MultiRegionS3Client, config: &Config, entries: Vec<ManifestEntry>, read_crcs: bool) -> Result<Vec<ResolvedEntry>, (StatusCode, &'static str)> {
+    let entries = manifest_transform::apply(&config.manifest_transforms, entries).await;
+    let entries = check_keys(entries, config.max_s3_key_length, config.invalid_keys)?;
+    set_bucket_regions(s3, &entries)?;
+    let mut expanded = Vec::with_capacity(entries.len());
+    let mut missing_crc = Vec::new();
+    let mut missing_parts_crc = Vec::new();
+    let mut decode = Vec::new();
+
+    for entry in entries {
+        let WildcardDescription { archive_name, source, .. } = match entry {
+            ManifestEntry::File(file) if wildcard::wildcard_prefix(&file.source.key).is_none() => {
+                if file.decode_s3_encoding {
+                    decode.push((expanded.len(), file.source.clone()));
+                } else if file.crc.is_none() && read_crcs {
+                    missing_crc.push((expanded.len(), file.source.clone()));
+                }
+                expanded.push(file.into());
+                continue;
+            }
+            ManifestEntry::Parts(parts) => {
+                if parts.crc.is_none() && read_crcs {
+                    missing_parts_crc.push(expanded.len());
+                }
+                expanded.push(parts.into());
+                continue;
+            }
+            ManifestEntry::File(file) => WildcardDescription { archive_name: file.archive_name, source: file.source, region: file.region },
+            ManifestEntry::Wildcard(wildcard) => wildcard,
+            ManifestEntry::Inline(inline) => {
+                expanded.push(decode_inline(inline, config.max_inline_bytes)?);
+                continue;
+            }
+            ManifestEntry::Symlink(link) => {
+                expanded.push(inline_entry(link.archive_name, link.symlink_target.into_bytes(), link.last_modified, true));
+                continue;
+            }
+        };
+
+        let prefix = wildcard::wildcard_prefix(&source.key).ok_or_else(|| {
+            log::error!("Manifest entry for {} is missing length, crc, or last_modified", s3_url(&source.bucket, &source.key));
+            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to parse upstream request")
+        })?;
+
+        let objects: Vec<_> = wildcard::list_prefix(&s3.for_bucket(&source.bucket).await, &source.bucket, prefix, config.max_wildcard_expansion).await?
+            .into_iter()
+            .filter(|object| manifest_transform::keep_expanded(&config.manifest_transforms, &format!("{}{}", archive_name, &object.key[prefix.len()..])))
+            .collect();
+        let crcs = if read_crcs {
+            wildcard::object_crcs(s3, objects.iter().map(|o| (source.bucket.clone(), o.key.clone()))).await?
+        } else {
+            vec![0; objects.len()]
+        };
+        log::info!("Expanded {} to {} entries", s3_url(&source.bucket, &source.key), objects.len());
+
+        for (object, crc) in objects.into_iter().zip(crcs) {
+            expanded.push(ResolvedEntry {
+                archive_name: format!("{}{}", archive_name, &object.key[prefix.len()..]),
+                source: EntrySource::S3(S3Url { bucket: source.bucket.clone(), key: object.key }),
+                length: object.len,
+                crc,
+                last_modified: object.last_modified,
+                last_accessed: None,
+                created: None,
+                symlink: false,
+                encoded: None,
+            });
+        }
+    }
+
+    if !missing_crc.is_empty() {
+        log::info!("Reading {} objects to compute CRCs missing from the manifest", missing_crc.len());
+        let crcs = wildcard::object_crcs(s3, missing_crc.iter().map(|(_, url)| (url.bucket.clone(), url.key.clone()))).await?;
+        for ((i, _), crc) in missing_crc.into_iter().zip(crcs) {
+            expanded[i].crc = crc;
+        }
+    }
+
+    if !missing_parts_crc.is_empty() {
+        log::info!("Reading the parts of {} entries to compute CRCs missing from the manifest", missing_parts_crc.len());
+        let objects: Vec<_> = missing_parts_crc.iter()
+            .flat_map(|&i| expanded[i].source.s3_urls())
+            .map(|url| (url.bucket.clone(), url.key.clone()))
+            .collect();
+        let mut crcs = wildcard::object_crcs(s3, objects).await?.into_iter();
+        for i in missing_parts_crc {
+            if let EntrySource::S3Parts(parts) = &expanded[i].source {
+                let crc = wildcard::combine_crcs(parts.iter().map(|(_, len)| (crcs.next().unwrap(), *len)));
+                expanded[i].crc = crc;
+            }
+        }
+    }
+
+    if !decode.is_empty() {
+        log::info!("Reading {} objects to decode their Content-Encoding", decode.len());
+        let objects = wildcard::decoded_objects(s3, decode.iter().map(|(_, url)| (url.bucket.clone(), url.key.clone()))).await?;
+        for ((i, _), object) in decode.into_iter().zip(objects) {
+            let entry = &mut expanded[i];
+            entry.length = object.len;
+            entry.crc = object.crc;
+            if object.encoding != ContentEncoding::Identity {
+                entry.encoded = Some((object.encoding, object.encoded_len));
+            }
+        }
+    }
+
+    Ok(expanded)
+}
+
+/// Bytes per second at which to estimate the download time: the request's `X-Expected-Bandwidth`
+/// header, or else `--assumed-bandwidth-bps`. An invalid header is ignored, since the estimate
+/// is only informational.
+fn expected_bandwidth(config: &Config, req: &Request<Body>) -> Option<u64> {
+    req.headers().get("X-Expected-Bandwidth")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+        .filter(|&bandwidth: &u64| bandwidth > 0)
+        .or(config.assumed_bandwidth_bps)
+}
+
+/// Seconds to download `total_len` bytes at `bandwidth` bytes per second, rounded up
+fn content_duration(total_len: u64, bandwidth: u64) -> u64 {
+    total_len.div_ceil(bandwidth)
+}
+
+/// Whether to wrap the archive in a `multipart/form-data` body, by `--multipart-response` or a
+/// `mode=multipart` query parameter
+fn multipart_requested(config: &Config, req: &Request<Body>) -> bool {
+    let query = req.uri().query().unwrap_or("");
+    config.multipart_response || form_urlencoded::parse(query.as_bytes()).any(|(k, v)| k == "mode" && v == "multipart")
+}
+
+/// Whether to report the archive's size instead of serving it, by `--dry-run` or a `dry_run=true`
+/// query parameter
+fn dry_run_requested(config: &Config, req: &Request<Body>) -> bool {
+    let query = req.uri().query().unwrap_or("");
+    config.dry_run || form_urlencoded::parse(query.as_bytes()).any(|(k, v)| k == "dry_run" && v == "true")
+}
+
+/// Replace each `{name}` in `template` with the capture group of that name or number
+fn expand_template(template: &str, captures: &regex::Captures) -> String {
+    lazy_static::lazy_static! {
+        static ref PLACEHOLDER: regex::Regex = regex::Regex::new(r"\{(\w+)\}").unwrap();
+    }
+
+    PLACEHOLDER.replace_all(template, |placeholder: &regex::Captures| {
+        let key = &placeholder[1];
+        let capture = match key.parse::<usize>() {
+            Ok(i) => captures.get(i),
+            Err(_) => captures.name(key),
+        };
+        capture.map_or("", |c| c.as_str()).to_owned()
+    }).into_owned()
+}
+
+/// Directory from `--entry-prefix-template`, if the request path matches `--path-pattern`
+fn template_prefix(config: &Config, req: &Request<Body>) -> Option<String> {
+    let (template, pattern) = (config.entry_prefix_template.as_ref()?, config.path_pattern.as_ref()?);
+    let path = req.uri().path();
+    let path = path.strip_prefix(config.strip_prefix(req)).unwrap_or(path);
+
+    match pattern.captures(path) {
+        Some(captures) => Some(expand_template(template, &captures)),
+        None => {
+            log::info!("Path {} doesn't match --path-pattern, entry prefix template not applied", path);
+            None
+        }
+    }
+}
+
+/// Top-level directory for zip entries: the `prefix` query parameter if allowed and present,
+/// otherwise `--archive-root`, followed by the expanded `--entry-prefix-template`
+fn archive_root(config: &Config, req: &Request<Body>) -> Result<String, (StatusCode, &'static str)> {
+    let query_prefix = req.uri().query()
+        .filter(|_| config.allow_prefix_query_param)
+        .and_then(|q| form_urlencoded::parse(q.as_bytes()).find(|(k, _)| k == "prefix"));
+
+    let root = match query_prefix {
+        Some((_, prefix)) => normalize_archive_root(&prefix).ok_or_else(|| {
+            log::error!("Invalid prefix query parameter {:?}", prefix);
+            (StatusCode::BAD_REQUEST, "Invalid prefix")
+        })?,
+        None => config.archive_root.clone(),
+    };
+
+    match template_prefix(config, req) {
+        Some(prefix) => normalize_archive_root(&format!("{}{}", root, prefix)).ok_or_else(|| {
+            log::error!("Invalid expanded entry prefix template {:?}", prefix);
+            (StatusCode::BAD_REQUEST, "Invalid prefix")
+        }),
+        None => Ok(root),
+    }
+}
+
+/// The `?filter=` query parameter, or else `--entry-filter-pattern`
+fn entry_filter(config: &Config, req: &Request<Body>) -> Result<Option<EntryFilter>, (StatusCode, &'static str)> {
+    let query = req.uri().query().unwrap_or("");
+    match form_urlencoded::parse(query.as_bytes()).find(|(k, _)| k == "filter") {
+        Some((_, filter)) => filter.parse().map(Some).map_err(|e| {
+            log::info!("Invalid filter query parameter {:?}: {}", filter, e);
+            (StatusCode::BAD_REQUEST, "Invalid filter")
+        }),
+        None => Ok(config.entry_filter.clone()),
+    }
+}
+
+/// What to do when a manifest has more than one entry with the same name
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DuplicateEntries {
+    /// Include all of them
+    Allow,
+    /// Fail the request
+    Reject,
+    /// Add a numeric suffix to all but the first, as in `name (1).txt`
+    Rename,
+}
+
+impl std::str::FromStr for DuplicateEntries {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(DuplicateEntries::Allow),
+            "reject" => Ok(DuplicateEntries::Reject),
+            "rename" => Ok(DuplicateEntries::Rename),
+            _ => Err(format!("Expected allow, reject, or rename, got {:?}", s)),
+        }
+    }
+}
+
+/// What to do with manifest entries whose S3 key fails `S3Url::check_key`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InvalidKeys {
+    /// Fail the request
+    Reject,
+    /// Leave the entry out of the archive
+    Skip,
+}
+
+impl std::str::FromStr for InvalidKeys {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(InvalidKeys::Reject),
+            "skip" => Ok(InvalidKeys::Skip),
+            _ => Err(format!("Expected reject or skip, got {:?}", s)),
+        }
+    }
+}
+
+/// Apply the invalid key policy to the S3 entries of a manifest
+fn check_keys(entries: Vec<ManifestEntry>, max_len: usize, policy: InvalidKeys) -> Result<Vec<ManifestEntry>, (StatusCode, &'static str)> {
+    let mut checked = Vec::with_capacity(entries.len());
+
+    for entry in entries {
+        let sources = match &entry {
+            ManifestEntry::File(file) => vec![&file.source],
+            ManifestEntry::Parts(parts) => parts.parts.iter().map(|part| &part.source).collect(),
+            ManifestEntry::Wildcard(wildcard) => vec![&wildcard.source],
+            ManifestEntry::Inline(_) | ManifestEntry::Symlink(_) => {
+                checked.push(entry);
+                continue;
+            }
+        };
+
+        match sources.into_iter().try_for_each(|source| source.check_key(max_len).map_err(|e| (source, e))) {
+            Ok(()) => checked.push(entry),
+            Err((source, e)) if policy == InvalidKeys::Reject => {
+                log::error!("Invalid key in manifest entry for {}: {}", s3_url(&source.bucket, &source.key), e);
+                return Err((StatusCode::BAD_REQUEST, "Invalid S3 key in manifest"));
+            }
+            Err((source, e)) => log::warn!("Skipping manifest entry for {}: {}", s3_url(&source.bucket, &source.key), e),
+        }
+    }
+
+    Ok(checked)
+}
+
+/// Record the bucket regions given by manifest entries, so that their clients are chosen without
+/// looking the regions up
+fn set_bucket_regions(s3: &MultiRegionS3Client, entries: &[ManifestEntry]) -> Result<(), (StatusCode, &'static str)> {
+    for entry in entries {
+        let (source, region) = match entry {
+            ManifestEntry::File(ZipFileDescription { source, region: Some(region), .. }) => (source, region),
+            ManifestEntry::Wildcard(WildcardDescription { source, region: Some(region), .. }) => (source, region),
+            _ => continue,
+        };
+
+        let region = region.parse().map_err(|e| {
+            log::error!("Invalid region {:?} in manifest entry for {}: {}", region, s3_url(&source.bucket, &source.key), e);
+            (StatusCode::BAD_REQUEST, "Invalid S3 region in manifest")
+        })?;
+        s3.set_location(&source.bucket, region);
+    }
+    Ok(())
+}
+
+/// `name` with ` (n)` inserted before the extension of its last path component
+fn numbered_name(name: &str, n: usize) -> String {
+    let file_start = name.rfind('/').map_or(0, |i| i + 1);
+    match name[file_start..].rfind('.') {
+        Some(dot) if dot > 0 => format!("{} ({}){}", &name[..file_start + dot], n, &name[file_start + dot..]),
+        _ => format!("{} ({})", name, n),
+    }
+}
+
+/// Apply the duplicate entry name policy to `entries`, in order
+fn handle_duplicates(entries: &mut [ResolvedEntry], policy: DuplicateEntries, case_insensitive: bool) -> Result<(), (StatusCode, &'static str)> {
+    if policy == DuplicateEntries::Allow {
+        return Ok(());
+    }
+
+    let key = |name: &str| if case_insensitive { name.to_lowercase() } else { name.to_owned() };
+    let mut seen = std::collections::HashSet::new();
+
+    for entry in entries.iter_mut() {
+        if seen.insert(key(&entry.archive_name)) {
+            continue;
+        }
+
+        if policy == DuplicateEntries::Reject {
+            log::error!("Duplicate entry name {} in manifest", entry.archive_name);
+            return Err((StatusCode::BAD_GATEWAY, "Duplicate entry name in manifest"));
+        }
+
+        let renamed = (1..).map(|n| numbered_name(&entry.archive_name, n)).find(|name| !seen.contains(&key(name))).unwrap();
+        log::info!("Renamed duplicate entry {} to {}", entry.archive_name, renamed);
+        seen.insert(key(&renamed));
+        entry.archive_name = renamed;
+    }
+
+    Ok(())
+}
+
+/// The manifest's `archive_comment`, or else `--default-archive-comment` with `{url}` replaced by
+/// the request URL and `{timestamp}` by the newest entry's time, so the archive is reproducible.
+fn archive_comment(config: &Config, req: &Request<Body>, manifest_comment: Option<String>, last_modified: Option<DateTime<Utc>>) -> Result<String, (StatusCode, &'static str)> {
+    let comment = match (manifest_comment, &config.default_archive_comment) {
+        (Some(comment), _) => comment,
+        (None, Some(template)) => {
+            let timestamp = last_modified.unwrap_or_else(default_inline_last_modified);
+            template
+                .replace("{timestamp}", &timestamp.to_rfc3339_opts(chrono::SecondsFormat::Secs, true))
+                .replace("{url}", &req.uri().to_string())
+        }
+        (None, None) => String::new(),
+    };
+
+    if comment.contains('\0') {
+        log::error!("Archive comment contains NUL bytes");
+        return Err((StatusCode::BAD_GATEWAY, "Archive comment contains NUL bytes"));
+    }
+
+    Ok(comment)
+}
+
+/// Format of the file listing requested with `?list=`, returned instead of the archive
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ListFormat {
+    Json,
+    Text,
+}
+
+fn list_format(req: &Request<Body>) -> Result<Option<ListFormat>, (StatusCode, &'static str)> {
+    let query = req.uri().query().unwrap_or("");
+    match form_urlencoded::parse(query.as_bytes()).find(|(k, _)| k == "list") {
+        None => Ok(None),
+        Some((_, v)) if v == "json" => Ok(Some(ListFormat::Json)),
+        Some((_, v)) if v == "text" => Ok(Some(ListFormat::Text)),
+        Some((_, v)) => {
+            log::info!("Invalid list format {:?}", v);
+            Err((StatusCode::BAD_REQUEST, "Invalid list format"))
+        }
+    }
+}
+
+/// The Object Lock status of `entries`, in the same order, keyed by the names `archive_entries`
+/// gives them. They can't be matched by position, since generated files such as the manifest
+/// sidecar are added before them and excluded entries are left out.
+fn locks_by_name(entries: &[ZipEntry], locks: Vec<Option<ObjectLock>>, options: &ZipOptions) -> HashMap<String, ObjectLock> {
+    entries.iter().zip(locks)
+        .filter_map(|(entry, lock)| Some((options.entry_name(&entry.archive_path), lock?)))
+        .collect()
+}
+
+/// The names, sizes, and CRCs of the entries of the archive, as they would appear in it.
+/// `locks` is the Object Lock status of files by name, added to the JSON listing.
+fn listing_response(req: &Request<Body>, list: ListFormat, files: &[ZipEntry], locks: &HashMap<String, ObjectLock>) -> Response<Body> {
+    let (content_type, body) = match list {
+        ListFormat::Json => {
+            let entries: Vec<_> = files.iter().map(|file| {
+                let mut entry = serde_json::json!({
+                    "name": file.archive_path,
+                    "size": file.data.len(),
+                    "crc": file.crc,
+                    "last_modified": file.last_modified,
+                });
+                if let Some(lock) = locks.get(&file.archive_path) {
+                    entry["object_lock_mode"] = serde_json::json!(lock.mode);
+                    entry["object_lock_retain_until"] = serde_json::json!(lock.retain_until);
+                    entry["object_lock_legal_hold"] = serde_json::json!(lock.legal_hold);
+                }
+                entry
+            }).collect();
+            ("application/json", serde_json::json!({ "entries": entries }).to_string())
+        }
+        ListFormat::Text => {
+            let lines = files.iter().map(|file| format!("{:>12}  {:08x}  {}\n", file.data.len(), file.crc, file.archive_path));
+            ("text/plain; charset=utf-8", lines.collect())
+        }
+    };
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::CONTENT_LENGTH, body.len())
+        .body(if req.method() == Method::HEAD { Body::empty() } else { body.into() })
+        .unwrap()
+}
+
+/// The number and total size of the files that would be in the archive, and the size of the
+/// archive itself, which is exact since entries are stored uncompressed. Only the uncompressed
+/// size of a `.tar.gz` can be known without compressing it.
+fn dry_run_response(req: &Request<Body>, format: Format, entries: Vec<ZipEntry>, options: ZipOptions) -> Response<Body> {
+    let files: Vec<_> = entries.iter().filter(|entry| options.includes(&entry.archive_path)).collect();
+    let entry_count = files.len();
+    let total_bytes: u64 = files.iter().map(|file| file.data.len()).sum();
+
+    let archive_bytes = match format {
+        Format::Zip => zip_stream(entries, options).len(),
+        Format::Tar | Format::TarGz => tar_stream(entries, options).len(),
+    };
+
+    let mut body = serde_json::json!({
+        "entry_count": entry_count,
+        "total_bytes": total_bytes,
+        "estimated_zip_bytes": archive_bytes,
+    });
+    if format == Format::TarGz {
+        body["note"] = "Size of the uncompressed tar; the .tar.gz size depends on the compression ratio".into();
+    }
+    let body = body.to_string();
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::CONTENT_LENGTH, body.len())
+        .body(if req.method() == Method::HEAD { Body::empty() } else { body.into() })
+        .unwrap()
+}
+
+/// Identifies the manifest for the ETag: the upstream's `ETag` or `Last-Modified` header, so that
+/// the ETag only changes when the upstream's does, or else the manifest itself.
+fn manifest_validator<'a>(upstream_headers: &'a HeaderMap, response_body: &'a [u8]) -> &'a [u8] {
+    upstream_headers.get(header::ETAG)
+        .or_else(|| upstream_headers.get(header::LAST_MODIFIED))
+        .map_or(response_body, |v| v.as_bytes())
+}
+
+/// Convert `--manifest-json-path` to a JSON Pointer: either one already, such as `/data/files`, or
+/// dot-separated keys, such as `data.files`.
+pub fn json_pointer(path: &str) -> String {
+    if path.is_empty() || path.starts_with('/') {
+        return path.to_owned();
+    }
+
+    path.split('.').map(|key| format!("/{}", key.replace('~', "~0").replace('/', "~1"))).collect()
+}
+
+/// Parse a manifest, taking its entry list from `entries_pointer` rather than the top-level
+/// `entries` if set. The other fields are always at the top level.
+fn parse_manifest(body: &[u8], entries_pointer: Option<&str>) -> Result<UpstreamManifest, (StatusCode, &'static str)> {
+    let invalid = |e: serde_json::Error| {
+        log::error!("Invalid upstream response JSON: {}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, "Failed to parse upstream request")
+    };
+
+    let pointer = match entries_pointer {
+        Some(pointer) => pointer,
+        None => return serde_json::from_slice(body).map_err(invalid),
+    };
+
+    let mut manifest: serde_json::Value = serde_json::from_slice(body).map_err(invalid)?;
+    let entries = match manifest.pointer_mut(pointer).map(serde_json::Value::take) {
+        Some(entries @ serde_json::Value::Array(_)) => entries,
+        Some(_) => {
+            log::error!("Manifest value at {} is not an array of entries", pointer);
+            return Err((StatusCode::BAD_GATEWAY, "Manifest entry list not found"));
+        }
+        None => {
+            log::error!("Manifest has no value at {}", pointer);
+            return Err((StatusCode::BAD_GATEWAY, "Manifest entry list not found"));
+        }
+    };
+
+    if let Some(fields) = manifest.as_object_mut() {
+        fields.insert("entries".into(), entries);
+    }
+    serde_json::from_value(manifest).map_err(invalid)
+}
+
+/// Name of the checksums file added by `--include-checksums`
+const CHECKSUMS_FILE: &str = "sha256sums.txt";
+
+/// Number of entries read at once to compute checksums
+const CHECKSUM_CONCURRENCY: usize = 8;
+
+/// Whether `entry` is listed in the checksums file: files in the archive, but not symlinks or
+/// directories
+fn has_checksum(entry: &ZipEntry, options: &ZipOptions) -> bool {
+    entry.symlink_target.is_none() && !entry.archive_path.ends_with('/') && options.includes(&entry.archive_path)
+}
+
+/// Read each file that will be in the archive to compute its SHA-256 for the checksums file.
+/// The headers and central directory are laid out before anything is sent, so this can't be done
+/// as the archive streams.
+async fn entry_sha256s(entries: &mut [ZipEntry], options: &ZipOptions) -> Result<(), (StatusCode, &'static str)> {
+    let reads: Vec<_> = entries.iter().map(|entry| async move {
+        if !has_checksum(entry, options) {
+            return Ok(None);
+        }
+
+        let hasher = entry.data.stream_range(Range { start: 0, end: entry.data.len() })
+            .try_fold(Sha256::new(), |mut hasher, buf| async move {
+                hasher.update(&buf);
+                Ok(hasher)
+            }).await.map_err(|e| {
+                log::error!("Failed to read {} for checksum: {}", entry.archive_path, e);
+                (StatusCode::SERVICE_UNAVAILABLE, "Failed to read entry for checksum")
+            })?;
+
+        Ok(Some(hasher.finalize().into()))
+    }).collect();
+
+    let sums: Vec<Option<[u8; 32]>> = stream::iter(reads).buffered(CHECKSUM_CONCURRENCY).try_collect().await?;
+    for (entry, sha256) in entries.iter_mut().zip(sums) {
+        entry.sha256 = sha256;
+    }
+    Ok(())
+}
+
+/// `Hasher` for the archive ETag, so that a client's `If-Range` still matches after an upgrade.
+/// Unlike `DefaultHasher`, BLAKE3 is the same in every release, and integers are hashed as
+/// little-endian, with `usize` widened to 64 bits, so that it's the same on every architecture.
+struct EtagHasher(blake3::Hasher);
+
+impl EtagHasher {
+    /// The first 128 bits of the hash in hex, short enough to be part of a multipart boundary
+    fn hex(&self) -> String {
+        self.0.finalize().to_hex()[..32].to_owned()
+    }
+}
+
+impl Hasher for EtagHasher {
+    fn write(&mut self, bytes: &[u8]) { self.0.update(bytes); }
+    fn write_u16(&mut self, n: u16) { self.write(&n.to_le_bytes()) }
+    fn write_u32(&mut self, n: u32) { self.write(&n.to_le_bytes()) }
+    fn write_u64(&mut self, n: u64) { self.write(&n.to_le_bytes()) }
+    fn write_u128(&mut self, n: u128) { self.write(&n.to_le_bytes()) }
+    fn write_usize(&mut self, n: usize) { self.write_u64(n as u64) }
+    fn finish(&self) -> u64 {
+        let mut first = [0; 8];
+        first.copy_from_slice(&self.0.finalize().as_bytes()[..8]);
+        u64::from_le_bytes(first)
+    }
+}
+
+/// Whether the response to `req` depends on the entries' SHA-256s, which `entry_sha256s` has to
+/// read every file to compute. In an archive only the checksums file's entry and what follows it
+/// do, so HEAD requests and ranges that end before it are served with placeholders.
+fn checksums_needed(req: &Request<Body>, format: Format, listing: bool, multipart: bool, etag: &str, entries: &[ZipEntry], options: &ZipOptions) -> bool {
+    if listing {
+        // The listing includes the checksums file's CRC
+        return true;
+    }
+    if req.method() == Method::HEAD {
+        return false;
+    }
+
+    let (offset, len) = match format {
+        _ if multipart => return true,
+        Format::Zip => (zip_checksums_offset(entries, options), zip_stream(entries.iter().map(layout_copy), options.clone()).len()),
+        Format::Tar => (tar_checksums_offset(entries, options), tar_stream(entries.iter().map(layout_copy), options.clone()).len()),
+        Format::TarGz => return true,
+    };
+    requested_range(req, etag, len).is_none_or(|range| range.end > offset)
+}
+
+/// Parse an upstream JSON response and produce a streaming zip file response
+pub async fn response(state: &State, req: &Request<Body>, upstream_headers: &HeaderMap, response_body: &[u8]) -> Result<Response<Body>, (StatusCode, &'static str)> {
+    let State { s3_client: s3, config, readiness, s3_limit, metrics, .. } = state;
+
+    let manifest = parse_manifest(response_body, config.manifest_json_path.as_deref())?;
+
+    let list = list_format(req)?;
+    // A listing is the same whichever format the archive would be served in
+    let format = match list {
+        Some(_) => manifest.format,
+        None => negotiate_format(req, manifest.format)?,
+    };
+    let has_wildcards = manifest.entries.iter().any(|e| matches!(e, ManifestEntry::Wildcard(_)));
+
+    if manifest.password.is_some() {
+        if !config.zip_legacy_encryption {
+            log::error!("Manifest has a password but --zip-legacy-encryption is not enabled");
+            return Err((StatusCode::BAD_GATEWAY, "Archive encryption not enabled"));
+        }
+        if format != Format::Zip {
+            log::info!("Encrypted archive requested as {:?}", format);
+            return Err((StatusCode::NOT_ACCEPTABLE, "Encrypted archives are only available as zip"));
+        }
+    }
+
+    // Sizes don't depend on CRCs, so a dry run doesn't read objects to compute missing ones
+    let dry_run = dry_run_requested(config, req) && list.is_none();
+
+    let mut res = UpstreamResponse {
+        filename: manifest.filename,
+        entries: resolve_entries(s3, config, manifest.entries, !dry_run).await?,
+    };
+
+    if config.reproducible {
+        // Fixed, like inline entries without a time, so that the archive depends only on the
+        // manifest's names and contents and not on when the objects were written
+        for entry in &mut res.entries {
+            entry.last_modified = default_inline_last_modified();
+            entry.last_accessed = None;
+            entry.created = None;
+        }
+    }
+
     res.entries.sort();
+    handle_duplicates(&mut res.entries, config.duplicate_entries, config.case_insensitive_names)?;
+
+    let archive_root = archive_root(config, req)?;
+    let last_modified = res.entries.iter().map(|e| e.last_modified).max();
+    let comment = archive_comment(config, req, manifest.archive_comment, last_modified)?;
+    let multipart = multipart_requested(config, req);
+    if multipart && format == Format::TarGz && list.is_none() {
+        log::info!("Multipart response requested as tar.gz");
+        return Err((StatusCode::NOT_ACCEPTABLE, "Multipart responses are only available as zip or tar"));
+    }
+    let entry_filter = entry_filter(config, req)?;
 
     let etag = {
-        //TODO: use a hash function that is stable across releases and architectures
-        let mut hasher = std::collections::hash_map::DefaultHasher::new();
-        res.hash(&mut hasher);
-        format!("{:x}", hasher.finish())
+        let mut hasher = EtagHasher(blake3::Hasher::new());
+        // The multipart boundary is derived from the ETag, so with `--reproducible` it depends only
+        // on what ends up in the archive, not on upstream headers or ignored timestamps
+        if config.reproducible {
+            res.hash(&mut hasher);
+        } else {
+            manifest_validator(upstream_headers, response_body).hash(&mut hasher);
+            // Wildcard expansions depend on the bucket contents as well as the manifest
+            if has_wildcards {
+                res.hash(&mut hasher);
+            }
+        }
+        archive_root.hash(&mut hasher);
+        format.hash(&mut hasher);
+        comment.hash(&mut hasher);
+        manifest.password.hash(&mut hasher);
+        multipart.hash(&mut hasher);
+        entry_filter.as_ref().map(|f| f.to_string()).hash(&mut hasher);
+        hasher.hex()
     };
     
-    let entries: Vec<ZipEntry> = res.entries.into_iter().map(|file| {
+    let object_locks = match list {
+        Some(ListFormat::Json) if config.list_object_lock => {
+            let sources: Vec<_> = res.entries.iter().map(|entry| match &entry.source {
+                EntrySource::S3(url) => Some(url.clone()),
+                EntrySource::S3Parts(_) | EntrySource::Inline(_) => None,
+            }).collect();
+            object_lock::object_locks(s3, &sources).await?
+        }
+        _ => Vec::new(),
+    };
+
+    let mut bucket_clients = HashMap::new();
+    for source in res.entries.iter().flat_map(|entry| entry.source.s3_urls()) {
+        if !bucket_clients.contains_key(&source.bucket) {
+            bucket_clients.insert(source.bucket.clone(), s3.for_bucket(&source.bucket).await);
+        }
+    }
+
+    let preflight_sources: Vec<_> = match list {
+        None if config.strict_preflight => res.entries.iter().flat_map(|entry| {
+            entry.source.s3_urls().into_iter().map(move |url| (entry.archive_name.clone(), url.clone()))
+        }).collect(),
+        _ => Vec::new(),
+    };
+
+    let mut entries: Vec<ZipEntry> = res.entries.into_iter().map(|file| {
+        let symlink_target = match &file.source {
+            EntrySource::Inline(target) if file.symlink => Some(String::from_utf8_lossy(target).into_owned()),
+            _ => None,
+        };
+
+        let data: Box<dyn DynStreamRange> = match file.source {
+            EntrySource::S3(source) => {
+                let object = S3Object {
+                    s3: bucket_clients[&source.bucket].clone(),
+                    bucket: source.bucket,
+                    key: source.key,
+                    len: file.encoded.map_or(file.length, |(_, len)| len),
+                    readiness: readiness.clone(),
+                    limit: s3_limit.clone(),
+                    metrics: metrics.clone(),
+                };
+                match file.encoded {
+                    Some((encoding, _)) => Box::new(Decoded { inner: object, encoding, len: file.length }),
+                    None => Box::new(object),
+                }
+            }
+            EntrySource::S3Parts(parts) => Box::new(stream_range::Concatenated(parts.into_iter().map(|(source, len)| {
+                Box::new(S3Object {
+                    s3: bucket_clients[&source.bucket].clone(),
+                    bucket: source.bucket,
+                    key: source.key,
+                    len,
+                    readiness: readiness.clone(),
+                    limit: s3_limit.clone(),
+                    metrics: metrics.clone(),
+                }) as Box<dyn DynStreamRange>
+            }).collect())),
+            EntrySource::Inline(content) => Box::new(content),
+        };
+
         ZipEntry {
             archive_path: file.archive_name,
             crc: file.crc,
-            data: Box::new(S3Object { 
-                s3: s3.clone(),
-                bucket: file.source.bucket,
-                key: file.source.key,
-                len: file.length
-            }),
+            data,
             last_modified: file.last_modified,
+            last_accessed: file.last_accessed,
+            created: file.created,
+            symlink_target,
+            sha256: None,
         }
     }).collect();
 
     let num_entries = entries.len();
 
-    let stream = zip_stream(entries, ZipOptions::default());
+    let options = ZipOptions {
+        strip_entry_prefix: config.strip_entry_prefix.clone(),
+        strip_path_components: config.strip_path_components,
+        archive_root,
+        contents_file: config.contents_file.clone(),
+        checksums_file: if config.include_checksums { CHECKSUMS_FILE.into() } else { String::new() },
+        manifest_sidecar: if config.include_manifest_sidecar { Some(Bytes::copy_from_slice(response_body)) } else { None },
+        name_encoding: config.entry_name_encoding,
+        comment,
+        legacy_encryption_password: manifest.password,
+        entry_filter,
+        ..ZipOptions::default()
+    };
+
+    if config.strict_preflight && list.is_none() && !dry_run {
+        let sources: Vec<_> = preflight_sources.into_iter()
+            .filter(|(name, _)| options.includes(name))
+            .map(|(_, url)| url)
+            .collect();
+        let problems = preflight::preflight(s3, &sources).await;
+        if !problems.is_empty() {
+            return Ok(preflight::preflight_response(&problems, config.json_errors));
+        }
+    }
+
+    if config.include_checksums {
+        // The checksums file's length depends only on which entries are listed, not their contents,
+        // so placeholders lay out the archive the same way until the real SHA-256s are needed
+        for entry in entries.iter_mut().filter(|entry| has_checksum(entry, &options)) {
+            entry.sha256 = Some([0; 32]);
+        }
+        if !dry_run && checksums_needed(req, format, list.is_some(), multipart, &etag, &entries, &options) {
+            entry_sha256s(&mut entries, &options).await?;
+        }
+    }
+
+    let filename = format.filename(&res.filename);
+
+    let mut response = if dry_run {
+        log::info!("Dry run of {:?} file {}: {} entries", format, filename, num_entries);
+        dry_run_response(req, format, entries, options)
+    } else if let (None, Format::TarGz) = (list, format) {
+        log::info!("Streaming {:?} file {}: {} entries", format, filename, num_entries);
+        hyper_stream_response(req, format.content_type(), &etag, &filename, tar_gz_stream(entries, options, config.response_chunk_size))
+    } else if let Some(list) = list {
+        log::info!("Listing {} entries of {}", num_entries, filename);
+        let locks = locks_by_name(&entries, object_locks, &options);
+        listing_response(req, list, &archive_entries(entries, &options), &locks)
+    } else {
+        let archive: Box<dyn DynStreamRange> = match format {
+            Format::Zip => Box::new(zip_stream(entries, options)),
+            Format::Tar | Format::TarGz => Box::new(tar_stream(entries, options)),
+        };
+        let archive: Box<dyn DynStreamRange> = Box::new(Rechunked { inner: archive, chunk_size: config.response_chunk_size });
+        #[cfg(feature = "secure-erase")]
+        let archive: Box<dyn DynStreamRange> = Box::new(crate::stream_range::SecureStreamRange(archive));
+        log::info!("Streaming {:?} file {}: {} entries, {} bytes", format, filename, num_entries, archive.len());
+
+        let (mut response, total_len) = if multipart {
+            // Derived from the ETag so that the body is the same for Range requests
+            let boundary = format!("zipstream-{}", etag);
+            let body = multipart_form_data(archive, &boundary, &filename, format.content_type());
+            (hyper_response(req, &multipart_content_type(&boundary), &etag, &filename, &body), body.len())
+        } else {
+            (hyper_response(req, format.content_type(), &etag, &filename, &archive), archive.len())
+        };
+
+        if let Some(bandwidth) = expected_bandwidth(config, req) {
+            response.headers_mut().insert("X-Content-Duration", content_duration(total_len, bandwidth).into());
+        }
+
+        // Without a Content-Length, hyper sends the body with chunked Transfer-Encoding
+        if config.chunked_response && response.status() == StatusCode::OK {
+            response.headers_mut().remove(header::CONTENT_LENGTH);
+        }
+        response
+    };
+
+    response.headers_mut().insert(header::VARY, header::HeaderValue::from_static("Accept"));
+
+    // Assembled zips are expensive to reproduce, so they aren't cached unless the upstream or
+    // operator says so
+    let cache_control = config.cache_control.as_ref()
+        .or_else(|| upstream_headers.get(header::CACHE_CONTROL))
+        .unwrap_or(&config.default_cache_control);
+    if response.status().is_success() {
+        response.headers_mut().insert(header::CACHE_CONTROL, cache_control.clone());
+    }
+
+    if let Some(last_modified) = last_modified {
+        let value = last_modified.format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+        response.headers_mut().insert(header::LAST_MODIFIED, value.parse().unwrap());
+    }
+
+    Ok(response)
+}
 
-    log::info!("Streaming zip file {}: {} entries, {} bytes", res.filename, num_entries, stream.len());
 
-    Ok(hyper_response(&req, "application/zip", &etag, &res.filename, &stream))
+#[test]
+fn test_parse_wildcard_manifest() {
+    let manifest: UpstreamManifest = serde_json::from_str(r#"{
+        "filename": "test.zip",
+        "entries": [
+            { "archive_name": "a.txt", "source": "s3://bucket/a.txt", "length": 3, "crc": 1, "last_modified": "2020-04-24T19:12:24.268Z" },
+            { "source": "s3://bucket/logs/2024-01-15/*" },
+            { "archive_name": "logs/", "source": "s3://bucket/other/*" },
+            { "name": "README.txt", "content_base64": "SGVsbG8gV29ybGQK" },
+            { "archive_name": "latest", "symlink_target": "a.txt" },
+            { "archive_name": "b.txt", "source": "s3://bucket/b.txt", "length": 3, "last_modified": "2020-04-24T19:12:24.268Z" }
+        ]
+    }"#).unwrap();
+
+    assert!(matches!(&manifest.entries[0], ManifestEntry::File(f) if f.archive_name == "a.txt"));
+    assert!(matches!(&manifest.entries[1], ManifestEntry::Wildcard(w) if w.archive_name.is_empty() && w.source.key == "logs/2024-01-15/*"));
+    assert!(matches!(&manifest.entries[2], ManifestEntry::Wildcard(w) if w.archive_name == "logs/"));
+    assert!(matches!(&manifest.entries[3], ManifestEntry::Inline(i) if i.archive_name == "README.txt"));
+    assert!(matches!(&manifest.entries[4], ManifestEntry::Symlink(l) if l.symlink_target == "a.txt"));
+    assert!(matches!(&manifest.entries[5], ManifestEntry::File(f) if f.crc.is_none()));
 }
 
+#[test]
+fn test_parse_manifest_json_path() {
+    assert_eq!(json_pointer("data.files"), "/data/files");
+    assert_eq!(json_pointer("/data/files"), "/data/files");
+    assert_eq!(json_pointer("a/b.c~d.0"), "/a~1b/c~0d/0");
+
+    let entry = r#"{ "name": "a.txt", "content_base64": "" }"#;
+    let parse = |body: String, pointer: &str| parse_manifest(body.as_bytes(), Some(pointer)).map(|m| m.entries.len());
+
+    assert_eq!(parse(format!(r#"{{ "filename": "t.zip", "data": {{ "files": [{}] }} }}"#, entry), "/data/files"), Ok(1));
+    assert_eq!(parse(format!(r#"{{ "filename": "t.zip", "a": {{ "b": {{ "c": [{0}, {0}] }} }} }}"#, entry), "/a/b/c"), Ok(2));
+    assert_eq!(parse(format!(r#"{{ "filename": "t.zip", "results": [{{ "files": [{}] }}] }}"#, entry), "/results/0/files"), Ok(1));
+
+    let not_found = Err((StatusCode::BAD_GATEWAY, "Manifest entry list not found"));
+    assert_eq!(parse(r#"{ "filename": "t.zip", "data": {} }"#.into(), "/data/files"), not_found);
+    assert_eq!(parse(r#"{ "filename": "t.zip", "data": { "files": {} } }"#.into(), "/data/files"), not_found);
+    assert_eq!(parse(r#"{ "filename": "t.zip", "entries": [] }"#.into(), "/data/files"), not_found);
+}
+
+#[test]
+fn test_handle_duplicates() {
+    let entries = |names: &[&str]| names.iter().map(|&name| inline_entry(name.into(), vec![], default_inline_last_modified(), false)).collect::<Vec<_>>();
+    let names = |entries: Vec<ResolvedEntry>| entries.into_iter().map(|e| e.archive_name).collect::<Vec<_>>();
+
+    let mut e = entries(&["a.txt", "a.txt", "a (1).txt", "dir.d/b", "dir.d/b", ".env", ".env"]);
+    handle_duplicates(&mut e, DuplicateEntries::Rename, false).unwrap();
+    assert_eq!(names(e), vec!["a.txt", "a (1).txt", "a (1) (1).txt", "dir.d/b", "dir.d/b (1)", ".env", ".env (1)"]);
+
+    let mut e = entries(&["A.txt", "a.txt"]);
+    handle_duplicates(&mut e, DuplicateEntries::Reject, false).unwrap();
+    assert_eq!(handle_duplicates(&mut e, DuplicateEntries::Reject, true), Err((StatusCode::BAD_GATEWAY, "Duplicate entry name in manifest")));
+    handle_duplicates(&mut e, DuplicateEntries::Rename, true).unwrap();
+    assert_eq!(names(e), vec!["A.txt", "a (1).txt"]);
+
+    let mut e = entries(&["a.txt", "a.txt"]);
+    handle_duplicates(&mut e, DuplicateEntries::Allow, false).unwrap();
+    assert_eq!(names(e), vec!["a.txt", "a.txt"]);
+}
+
+#[test]
+fn test_set_bucket_regions() {
+    let manifest: UpstreamManifest = serde_json::from_str(r#"{
+        "filename": "test.zip",
+        "entries": [
+            { "archive_name": "a.txt", "source": "s3://bucket-a/a.txt", "length": 3, "crc": 1, "last_modified": "2020-04-24T19:12:24.268Z", "region": "eu-west-1" },
+            { "source": "s3://bucket-b/logs/*", "region": "mars-north-1" }
+        ]
+    }"#).unwrap();
+    let s3 = MultiRegionS3Client::single(std::sync::Arc::new(rusoto_s3::S3Client::new(rusoto_core::Region::UsEast1)));
+
+    assert!(set_bucket_regions(&s3, &manifest.entries[..1]).is_ok());
+    assert_eq!(set_bucket_regions(&s3, &manifest.entries).unwrap_err(), (StatusCode::BAD_REQUEST, "Invalid S3 region in manifest"));
+}
+
+#[test]
+fn test_check_keys() {
+    let manifest: UpstreamManifest = serde_json::from_str(r#"{
+        "filename": "test.zip",
+        "entries": [
+            { "archive_name": "a.txt", "source": "s3://bucket/a.txt", "length": 3, "crc": 1, "last_modified": "2020-04-24T19:12:24.268Z" },
+            { "archive_name": "b.txt", "source": "s3://bucket/../b.txt", "length": 3, "crc": 1, "last_modified": "2020-04-24T19:12:24.268Z" },
+            { "source": "s3://bucket/logs\u0000/*" },
+            { "name": "c.txt", "content_base64": "" },
+            { "archive_name": "d.txt", "parts": [
+                { "source": "s3://bucket/d.000", "length": 3 },
+                { "source": "s3://bucket/../d.001", "length": 3 }
+            ], "last_modified": "2020-04-24T19:12:24.268Z" }
+        ]
+    }"#).unwrap();
+    let kinds = |entries: Vec<ManifestEntry>| entries.iter().map(|e| match e {
+        ManifestEntry::File(f) => f.archive_name.clone(),
+        ManifestEntry::Parts(p) => p.archive_name.clone(),
+        ManifestEntry::Wildcard(w) => w.source.key.clone(),
+        ManifestEntry::Inline(i) => i.archive_name.clone(),
+        ManifestEntry::Symlink(l) => l.archive_name.clone(),
+    }).collect::<Vec<_>>();
+
+    assert_eq!(kinds(check_keys(manifest.entries.clone(), 1024, InvalidKeys::Skip).unwrap()), vec!["a.txt", "c.txt"]);
+    assert_eq!(check_keys(manifest.entries.clone(), 1024, InvalidKeys::Reject).unwrap_err(), (StatusCode::BAD_REQUEST, "Invalid S3 key in manifest"));
+    assert_eq!(kinds(check_keys(manifest.entries[..1].to_vec(), 5, InvalidKeys::Skip).unwrap()), vec!["a.txt"]);
+    assert_eq!(kinds(check_keys(manifest.entries[..1].to_vec(), 4, InvalidKeys::Skip).unwrap()), Vec::<String>::new());
+}
+
+#[tokio::test]
+async fn test_listing_object_lock() {
+    let entry = |name: &str| ZipEntry {
+        archive_path: name.into(),
+        data: Box::new(Bytes::from_static(b"abc")),
+        crc: 0,
+        last_modified: default_inline_last_modified(),
+        last_accessed: None,
+        created: None,
+        symlink_target: None,
+        sha256: None,
+    };
+    let lock = ObjectLock { mode: Some("COMPLIANCE".into()), retain_until: Some("2030-01-01T00:00:00.000Z".into()), legal_hold: None };
+    let files = vec![entry("a.txt"), entry("b.txt"), entry("c.txt")];
+    // With `--include-manifest-sidecar` two generated files come first, and the filter leaves out `b.txt`
+    let options = ZipOptions {
+        archive_root: "root".into(),
+        contents_file: "CONTENTS.txt".into(),
+        manifest_sidecar: Some(Bytes::from_static(b"{}")),
+        entry_filter: Some("!b.txt".parse().unwrap()),
+        ..ZipOptions::default()
+    };
+
+    let locks = locks_by_name(&files, vec![None, Some(lock.clone()), Some(lock)], &options);
+    let res = listing_response(&Request::get("/").body(Body::empty()).unwrap(), ListFormat::Json, &archive_entries(files, &options), &locks);
+    let json: serde_json::Value = serde_json::from_slice(&hyper::body::to_bytes(res.into_body()).await.unwrap()).unwrap();
+    let entries = json["entries"].as_array().unwrap();
+    let names: Vec<_> = entries.iter().map(|e| e["name"].as_str().unwrap()).collect();
+    assert_eq!(names, ["root/_zipstream_manifest.json", "root/_zipstream_manifest.json.sha256", "root/a.txt", "root/c.txt", "root/CONTENTS.txt"]);
+    assert_eq!(entries[3]["object_lock_mode"], "COMPLIANCE");
+    assert_eq!(entries[3]["object_lock_retain_until"], "2030-01-01T00:00:00.000Z");
+    assert_eq!(entries[3]["object_lock_legal_hold"], serde_json::Value::Null);
+    for i in [0, 1, 2, 4] {
+        assert!(entries[i].get("object_lock_mode").is_none(), "{}", names[i]);
+    }
+}
+
+#[test]
+fn test_etag_hasher() {
+    let mut hasher = EtagHasher(blake3::Hasher::new());
+    "abc".hash(&mut hasher);
+    3usize.hash(&mut hasher);
+    0x0102u16.hash(&mut hasher);
+
+    let mut expected = blake3::Hasher::new();
+    expected.update(b"abc\xff").update(&[3, 0, 0, 0, 0, 0, 0, 0]).update(&[2, 1]);
+    assert_eq!(hasher.hex(), &expected.finalize().to_hex()[..32]);
+}
+
+#[tokio::test]
+async fn test_checksums_needed() {
+    let entries = || vec![
+        ZipEntry {
+            archive_path: "a.txt".into(),
+            data: Box::new(Bytes::from_static(b"Hello World\n")),
+            crc: 0xb095e5e3,
+            last_modified: default_inline_last_modified(),
+            last_accessed: None,
+            created: None,
+            symlink_target: None,
+            sha256: Some([0; 32]),
+        },
+        ZipEntry {
+            archive_path: "latest".into(),
+            data: Box::new(Bytes::new()),
+            crc: 0,
+            last_modified: default_inline_last_modified(),
+            last_accessed: None,
+            created: None,
+            symlink_target: Some("a.txt".into()),
+            sha256: None,
+        },
+    ];
+    let options = ZipOptions {
+        contents_file: "CONTENTS.txt".into(),
+        checksums_file: CHECKSUMS_FILE.into(),
+        manifest_sidecar: Some(Bytes::from_static(b"{}")),
+        ..ZipOptions::default()
+    };
+    let read = |archive: &dyn DynStreamRange| archive.dyn_stream_range(Range { start: 0, end: archive.dyn_len() }).map_ok(|b| b.to_vec()).try_concat();
+    let get = |range: Option<String>| {
+        let req = Request::get("/test.zip");
+        let req = match range { Some(range) => req.header(header::RANGE, range), None => req };
+        req.body(Body::empty()).unwrap()
+    };
+
+    // Offsets of the checksums file's local header and tar header, found in the archives
+    let zip = read(&zip_stream(entries(), options.clone())).await.unwrap();
+    let name = zip.windows(CHECKSUMS_FILE.len()).position(|w| w == CHECKSUMS_FILE.as_bytes()).unwrap() as u64;
+    let zip_offset = name - 30;
+    assert_eq!(&zip[zip_offset as usize..][..4], b"PK\x03\x04");
+    assert_eq!(zip_checksums_offset(&entries(), &options), zip_offset);
+
+    let tar = read(&tar_stream(entries(), options.clone())).await.unwrap();
+    let tar_offset = tar.windows(CHECKSUMS_FILE.len()).position(|w| w == CHECKSUMS_FILE.as_bytes()).unwrap() as u64;
+    assert_eq!(tar_checksums_offset(&entries(), &options), tar_offset);
+
+    let needed = |req: &Request<Body>, format: Format, listing: bool, multipart: bool| checksums_needed(req, format, listing, multipart, "etag", &entries(), &options);
+    let range = |end: u64| Some(format!("bytes=0-{}", end - 1));
+    let head = Request::head("/test.zip").body(Body::empty()).unwrap();
+
+    assert!(!needed(&head, Format::Zip, false, false));
+    assert!(!needed(&get(range(zip_offset)), Format::Zip, false, false));
+    assert!(needed(&get(range(zip_offset + 1)), Format::Zip, false, false));
+    assert!(needed(&get(Some(format!("bytes={}-", zip_offset))), Format::Zip, false, false));
+    assert!(needed(&get(None), Format::Zip, false, false));
+    assert!(!needed(&get(range(tar_offset)), Format::Tar, false, false));
+    assert!(needed(&get(range(tar_offset + 1)), Format::Tar, false, false));
+    assert!(needed(&get(range(10)), Format::TarGz, false, false));
+    assert!(needed(&get(range(10)), Format::Zip, false, true));
+    assert!(needed(&head, Format::Zip, true, false));
+}
+
+#[tokio::test]
+async fn test_proxy_body_limit() {
+    use hyper::body::to_bytes;
+
+    let res = Response::new(Body::from(vec![0u8; 100]));
+    assert_eq!(to_bytes(proxy_response(res, None).unwrap().into_body()).await.unwrap().len(), 100);
+
+    let res = Response::new(Body::from(vec![0u8; 100]));
+    assert_eq!(to_bytes(proxy_response(res, Some(100)).unwrap().into_body()).await.unwrap().len(), 100);
+
+    // No Content-Length: streamed until the limit is exceeded
+    let res = Response::new(Body::from(vec![0u8; 100]));
+    assert!(to_bytes(proxy_response(res, Some(50)).unwrap().into_body()).await.is_err());
+
+    // Declared Content-Length over the limit is rejected before streaming
+    let res = Response::builder().header(header::CONTENT_LENGTH, "100").body(Body::from(vec![0u8; 100])).unwrap();
+    assert_eq!(proxy_response(res, Some(50)).unwrap_err().0, StatusCode::BAD_GATEWAY);
+}
+
+#[tokio::test]
+async fn test_read_manifest_limit() {
+    let headers = |len: &str| {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_LENGTH, len.parse().unwrap());
+        headers
+    };
+    let exceeded = Err((StatusCode::BAD_GATEWAY, "Upstream response exceeded size limit"));
+
+    assert_eq!(read_manifest(Body::from(vec![0u8; 100]), &HeaderMap::new(), 100, Duration::from_secs(10)).await.unwrap().len(), 100);
+    assert_eq!(read_manifest(Body::from(vec![0u8; 101]), &HeaderMap::new(), 100, Duration::from_secs(10)).await, exceeded);
+
+    // Declared length over the limit, or a body longer than declared
+    assert_eq!(read_manifest(Body::from(vec![0u8; 10]), &headers("1000"), 100, Duration::from_secs(10)).await, exceeded);
+    assert_eq!(read_manifest(Body::from(vec![0u8; 1000]), &headers("10"), 100, Duration::from_secs(10)).await, exceeded);
+}
+
+/// Compressed manifests are decoded within the size limit, and bad or unknown encodings are rejected.
+#[tokio::test]
+async fn test_decode_manifest() {
+    use async_compression::tokio::bufread::{ BrotliEncoder, GzipEncoder, ZlibEncoder };
+
+    let manifest = Bytes::from(format!(r#"{{ "filename": "test.zip", "entries": [], "padding": "{}" }}"#, "x".repeat(1000)));
+    let encoded = |encoding: &str| {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_ENCODING, encoding.parse().unwrap());
+        headers
+    };
+    async fn compress(mut encoder: std::pin::Pin<Box<dyn AsyncRead + Send + '_>>) -> Bytes {
+        let mut buf = Vec::new();
+        encoder.read_to_end(&mut buf).await.unwrap();
+        Bytes::from(buf)
+    }
+
+    let gzip = compress(Box::pin(GzipEncoder::new(&manifest[..]))).await;
+    let zlib = compress(Box::pin(ZlibEncoder::new(&manifest[..]))).await;
+    let brotli = compress(Box::pin(BrotliEncoder::new(&manifest[..]))).await;
+    assert!(gzip.len() < 200);
+
+    assert_eq!(decode_manifest(gzip.clone(), &encoded("gzip"), 10_000).await.unwrap(), manifest);
+    assert_eq!(decode_manifest(zlib, &encoded("deflate"), 10_000).await.unwrap(), manifest);
+    assert_eq!(decode_manifest(brotli, &encoded("br"), 10_000).await.unwrap(), manifest);
+    assert_eq!(decode_manifest(manifest.clone(), &HeaderMap::new(), 10_000).await.unwrap(), manifest);
+    assert_eq!(decode_manifest(manifest.clone(), &encoded("identity"), 10_000).await.unwrap(), manifest);
+
+    assert_eq!(decode_manifest(gzip, &encoded("gzip"), 1000).await, Err((StatusCode::BAD_GATEWAY, "Upstream response exceeded size limit")));
+    assert_eq!(decode_manifest(manifest.clone(), &encoded("gzip"), 10_000).await, Err((StatusCode::BAD_GATEWAY, "Failed to decompress upstream response")));
+    assert_eq!(decode_manifest(manifest, &encoded("zstd"), 10_000).await, Err((StatusCode::BAD_GATEWAY, "Unsupported upstream Content-Encoding")));
+}
+
+/// The upstream connection drops mid-body, and the remainder is served in response to a ranged request.
+#[tokio::test]
+async fn test_resume_proxied_body() {
+    let upstream = crate::test::spawn_upstream(|req| {
+        match req.headers().get(header::RANGE) {
+            None => {
+                let (mut sender, body) = Body::channel();
+                tokio::spawn(async move {
+                    sender.send_data("01234".into()).await.unwrap();
+                    sender.abort();
+                });
+                Response::builder()
+                    .header(header::ACCEPT_RANGES, "bytes")
+                    .header(header::CONTENT_LENGTH, "10")
+                    .header(header::ETAG, "\"v1\"")
+                    .body(body).unwrap()
+            }
+            Some(range) => {
+                // The connection may fail before or after the first chunk reaches the client
+                let start: usize = range.to_str().unwrap().strip_prefix("bytes=").unwrap().trim_end_matches('-').parse().unwrap();
+                assert_eq!(req.headers()[header::IF_RANGE], "\"v1\"");
+                Response::builder()
+                    .status(StatusCode::PARTIAL_CONTENT)
+                    .header(header::CONTENT_RANGE, format!("bytes {}-9/10", start))
+                    .body(Body::from(&"0123456789"[start..])).unwrap()
+            }
+        }
+    });
+
+    let client = Client::new();
+    let req = || Request::get(format!("{}/file", upstream)).body(Body::empty()).unwrap();
+
+    let res = client.request(req()).await.unwrap();
+    let res = resumable_response(client.clone(), req(), res, 1);
+    assert_eq!(hyper::body::to_bytes(res.into_body()).await.unwrap(), "0123456789");
+
+    // Without retries the truncated body is an error
+    let res = client.request(req()).await.unwrap();
+    let res = resumable_response(client.clone(), req(), res, 0);
+    assert!(hyper::body::to_bytes(res.into_body()).await.is_err());
+}
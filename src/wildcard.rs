@@ -0,0 +1,308 @@
+// © 2019 3D Robotics. License: Apache-2.0
+use std::sync::Arc;
+use chrono::{DateTime, Utc};
+use futures::{ StreamExt, TryStreamExt };
+use hyper::StatusCode;
+use crate::redact::s3_url;
+use crate::s3_regions::MultiRegionS3Client;
+use crate::stream_range::{ BoxError, ContentEncoding };
+use rusoto_core::RusotoError;
+use rusoto_s3::{ S3, GetObjectError, GetObjectRequest, ListObjectsV2Request };
+
+/// Number of objects read concurrently to compute CRCs of expanded entries
+const CRC_CONCURRENCY: usize = 8;
+
+/// An S3 object found by expanding a `s3://bucket/prefix*` manifest entry
+#[derive(Clone, Debug, PartialEq)]
+pub struct ListedObject {
+    pub key: String,
+    pub len: u64,
+    pub last_modified: DateTime<Utc>,
+}
+
+/// Returns the prefix of a wildcard key (`logs/2024-01-15/*` => `logs/2024-01-15/`), or None if
+/// the key is not a wildcard.
+pub fn wildcard_prefix(key: &str) -> Option<&str> {
+    key.strip_suffix('*')
+}
+
+#[test]
+fn test_wildcard_prefix() {
+    assert_eq!(wildcard_prefix("logs/2024-01-15/*"), Some("logs/2024-01-15/"));
+    assert_eq!(wildcard_prefix("logs/2024-01-1*"), Some("logs/2024-01-1"));
+    assert_eq!(wildcard_prefix("*"), Some(""));
+    assert_eq!(wildcard_prefix("logs/file.txt"), None);
+}
+
+/// List all objects under `prefix`, failing if there are more than `limit`.
+/// "Directory" placeholder keys ending in `/` are skipped.
+pub async fn list_prefix(s3: &Arc<dyn S3 + Send + Sync>, bucket: &str, prefix: &str, limit: usize) -> Result<Vec<ListedObject>, (StatusCode, &'static str)> {
+    let mut objects = Vec::new();
+    let mut continuation_token = None;
+
+    loop {
+        let req = ListObjectsV2Request {
+            bucket: bucket.to_owned(),
+            prefix: Some(prefix.to_owned()),
+            continuation_token: continuation_token.take(),
+            ..ListObjectsV2Request::default()
+        };
+
+        let res = s3.list_objects_v2(req).await.map_err(|e| {
+            log::error!("S3 ListObjectsV2 failed for {}*: {}", s3_url(bucket, prefix), e);
+            if is_access_denied(&e) {
+                (StatusCode::BAD_GATEWAY, "Permission denied listing S3 objects")
+            } else {
+                (StatusCode::SERVICE_UNAVAILABLE, "Failed to list S3 objects")
+            }
+        })?;
+
+        for object in res.contents.unwrap_or_default() {
+            let key = match object.key {
+                Some(key) if !key.ends_with('/') => key,
+                _ => continue,
+            };
+
+            let last_modified = object.last_modified.as_deref()
+                .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+                .map(|t| t.with_timezone(&Utc))
+                .ok_or_else(|| {
+                    log::error!("S3 listing for {} has missing or invalid LastModified", s3_url(bucket, &key));
+                    (StatusCode::SERVICE_UNAVAILABLE, "Invalid S3 listing")
+                })?;
+
+            objects.push(ListedObject {
+                len: object.size.unwrap_or(0) as u64,
+                key,
+                last_modified,
+            });
+        }
+
+        if objects.len() > limit {
+            log::error!("Wildcard {}* matches more than {} objects", s3_url(bucket, prefix), limit);
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, "Wildcard expansion limit exceeded"));
+        }
+
+        match res.next_continuation_token {
+            Some(token) if res.is_truncated == Some(true) => continuation_token = Some(token),
+            _ => break,
+        }
+    }
+
+    Ok(objects)
+}
+
+/// S3 returns `AccessDenied` as a 403 when the credentials lack permission for an object or
+/// bucket. Rusoto doesn't model that error, so it arrives unparsed.
+fn is_access_denied<E>(e: &RusotoError<E>) -> bool {
+    matches!(e, RusotoError::Unknown(res) if res.status == StatusCode::FORBIDDEN)
+}
+
+/// Convert a failed GetObject to the response status, distinguishing a missing object from one
+/// zipstream isn't allowed to read. The response body never includes the bucket or key.
+fn get_object_error(e: RusotoError<GetObjectError>, bucket: &str, key: &str) -> (StatusCode, &'static str) {
+    match e {
+        RusotoError::Service(GetObjectError::NoSuchKey(_)) => {
+            log::error!("S3 object {} in manifest not found", s3_url(bucket, key));
+            (StatusCode::NOT_FOUND, "S3 object not found")
+        }
+        e if is_access_denied(&e) => {
+            log::error!("Access denied reading {}; check the IAM policy for zipstream's credentials", s3_url(bucket, key));
+            (StatusCode::BAD_GATEWAY, "Permission denied reading S3 object")
+        }
+        e => {
+            log::error!("Failed to read {} for CRC: {}", s3_url(bucket, key), e);
+            (StatusCode::SERVICE_UNAVAILABLE, "Failed to read S3 object")
+        }
+    }
+}
+
+#[test]
+fn test_get_object_error() {
+    use rusoto_core::request::BufferedHttpResponse;
+
+    let response = |status: StatusCode, body: &'static str| RusotoError::Unknown(BufferedHttpResponse {
+        status,
+        body: bytes::Bytes::from_static(body.as_bytes()),
+        headers: Default::default(),
+    });
+
+    let denied = response(StatusCode::FORBIDDEN, "<Error><Code>AccessDenied</Code><Message>Access Denied</Message></Error>");
+    assert_eq!(get_object_error(denied, "bucket", "secret/key"), (StatusCode::BAD_GATEWAY, "Permission denied reading S3 object"));
+
+    let missing = RusotoError::Service(GetObjectError::NoSuchKey("The specified key does not exist.".into()));
+    assert_eq!(get_object_error(missing, "bucket", "secret/key"), (StatusCode::NOT_FOUND, "S3 object not found"));
+
+    let unavailable = response(StatusCode::SERVICE_UNAVAILABLE, "<Error><Code>SlowDown</Code></Error>");
+    assert_eq!(get_object_error(unavailable, "bucket", "secret/key").0, StatusCode::SERVICE_UNAVAILABLE);
+}
+
+/// The key is logged through `s3_url`, so that `--redact-keys` applies; redaction itself is
+/// tested with the flag passed explicitly in `redact::test_redacted`
+#[test]
+fn test_get_object_error_log() {
+    let missing = RusotoError::Service(GetObjectError::NoSuchKey("The specified key does not exist.".into()));
+
+    let logs = crate::test::capture_logs(|| { get_object_error(missing, "bucket", "customers/alice/scan.jpg"); });
+    assert_eq!(logs, vec![format!("S3 object {} in manifest not found", s3_url("bucket", "customers/alice/scan.jpg"))]);
+}
+
+/// Read an S3 object in full to compute its CRC32, which S3 listings don't provide and manifests
+/// may omit.
+async fn object_crc(s3: Arc<dyn S3 + Send + Sync>, bucket: String, key: String) -> Result<u32, (StatusCode, &'static str)> {
+    let req = GetObjectRequest {
+        bucket: bucket.clone(),
+        key: key.clone(),
+        ..GetObjectRequest::default()
+    };
+
+    let fail = |e: &dyn std::fmt::Display| {
+        log::error!("Failed to read {} for CRC: {}", s3_url(&bucket, &key), e);
+        (StatusCode::SERVICE_UNAVAILABLE, "Failed to read S3 object")
+    };
+
+    let res = s3.get_object(req).await.map_err(|e| get_object_error(e, &bucket, &key))?;
+    let body = res.body.ok_or_else(|| fail(&"missing body"))?;
+
+    let hasher = body.try_fold(crc32fast::Hasher::new(), |mut hasher, buf| async move {
+        hasher.update(&buf);
+        Ok(hasher)
+    }).await.map_err(|e| fail(&e))?;
+
+    Ok(hasher.finalize())
+}
+
+/// Multiply the 32x32 bit matrix `mat` over GF(2) by `vec`
+fn gf2_matrix_times(mat: &[u32; 32], mut vec: u32) -> u32 {
+    let mut sum = 0;
+    for row in mat {
+        if vec & 1 != 0 {
+            sum ^= row;
+        }
+        vec >>= 1;
+    }
+    sum
+}
+
+fn gf2_matrix_square(mat: &[u32; 32]) -> [u32; 32] {
+    let mut square = [0; 32];
+    for (s, &row) in square.iter_mut().zip(mat) {
+        *s = gf2_matrix_times(mat, row);
+    }
+    square
+}
+
+/// The CRC32 of two blocks of data concatenated, from the CRC32 of each and the length of the
+/// second, as zlib's `crc32_combine`
+fn crc32_combine(mut crc1: u32, crc2: u32, mut len2: u64) -> u32 {
+    // Operator for one zero bit, then squared for two and four
+    let mut odd = [0; 32];
+    odd[0] = 0xedb88320; // CRC-32 polynomial
+    for (n, row) in odd.iter_mut().enumerate().skip(1) {
+        *row = 1 << (n - 1);
+    }
+    let mut even = gf2_matrix_square(&odd);
+    odd = gf2_matrix_square(&even);
+
+    // Apply `len2` zero bytes to `crc1`, squaring the operator for each bit of the length
+    while len2 != 0 {
+        even = gf2_matrix_square(&odd);
+        if len2 & 1 != 0 {
+            crc1 = gf2_matrix_times(&even, crc1);
+        }
+        len2 >>= 1;
+        if len2 == 0 {
+            break;
+        }
+        odd = gf2_matrix_square(&even);
+        if len2 & 1 != 0 {
+            crc1 = gf2_matrix_times(&odd, crc1);
+        }
+        len2 >>= 1;
+    }
+
+    crc1 ^ crc2
+}
+
+/// The CRC32 of parts concatenated in order, from the `(crc, length)` of each part, so that parts
+/// read separately needn't be read again in order
+pub fn combine_crcs(parts: impl IntoIterator<Item = (u32, u64)>) -> u32 {
+    parts.into_iter().fold(0, |crc, (part_crc, len)| crc32_combine(crc, part_crc, len))
+}
+
+#[test]
+fn test_combine_crcs() {
+    let crc = |data: &[u8]| { let mut hasher = crc32fast::Hasher::new(); hasher.update(data); hasher.finalize() };
+    let parts: [&[u8]; 4] = [b"Hello ", b"", b"World", &[0xA5; 100_000]];
+
+    let whole: Vec<u8> = parts.concat();
+    assert_eq!(combine_crcs(parts.iter().map(|part| (crc(part), part.len() as u64))), crc(&whole));
+    assert_eq!(combine_crcs(vec![]), 0);
+}
+
+/// An S3 object stored with a `Content-Encoding`, as it is once decoded
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DecodedObject {
+    pub encoding: ContentEncoding,
+    /// Length as stored
+    pub encoded_len: u64,
+    pub len: u64,
+    pub crc: u32,
+}
+
+/// Read an S3 object in full and decode it by its `Content-Encoding` to find the length and CRC32
+/// of its decoded content.
+async fn decoded_object(s3: Arc<dyn S3 + Send + Sync>, bucket: String, key: String) -> Result<DecodedObject, (StatusCode, &'static str)> {
+    let req = GetObjectRequest {
+        bucket: bucket.clone(),
+        key: key.clone(),
+        ..GetObjectRequest::default()
+    };
+
+    let fail = |e: &dyn std::fmt::Display| {
+        log::error!("Failed to decode {}: {}", s3_url(&bucket, &key), e);
+        (StatusCode::SERVICE_UNAVAILABLE, "Failed to read S3 object")
+    };
+
+    let res = s3.get_object(req).await.map_err(|e| get_object_error(e, &bucket, &key))?;
+    let encoding = res.content_encoding.as_deref().unwrap_or("").parse::<ContentEncoding>().map_err(|_| {
+        log::error!("Unsupported Content-Encoding {:?} on {}", res.content_encoding, s3_url(&bucket, &key));
+        (StatusCode::BAD_GATEWAY, "Unsupported S3 object Content-Encoding")
+    })?;
+    let encoded_len = res.content_length.ok_or_else(|| fail(&"missing Content-Length"))? as u64;
+    let body = res.body.ok_or_else(|| fail(&"missing body"))?;
+
+    let (len, hasher) = encoding.decode(Box::pin(body.map_err(BoxError::from))).try_fold((0u64, crc32fast::Hasher::new()), |(len, mut hasher), buf| async move {
+        hasher.update(&buf);
+        Ok((len + buf.len() as u64, hasher))
+    }).await.map_err(|e| fail(&e))?;
+
+    Ok(DecodedObject {
+        encoding,
+        encoded_len,
+        len,
+        crc: hasher.finalize(),
+    })
+}
+
+/// Read and decode each `(bucket, key)` object, preserving order.
+pub async fn decoded_objects(s3: &MultiRegionS3Client, objects: impl IntoIterator<Item = (String, String)>) -> Result<Vec<DecodedObject>, (StatusCode, &'static str)> {
+    let reads: Vec<_> = objects.into_iter().map(|(bucket, key)| async move {
+        decoded_object(s3.for_bucket(&bucket).await, bucket, key).await
+    }).collect();
+    futures::stream::iter(reads)
+        .buffered(CRC_CONCURRENCY)
+        .try_collect()
+        .await
+}
+
+/// Compute the CRC32 of each `(bucket, key)` object, preserving order.
+pub async fn object_crcs(s3: &MultiRegionS3Client, objects: impl IntoIterator<Item = (String, String)>) -> Result<Vec<u32>, (StatusCode, &'static str)> {
+    let reads: Vec<_> = objects.into_iter().map(|(bucket, key)| async move {
+        object_crc(s3.for_bucket(&bucket).await, bucket, key).await
+    }).collect();
+    futures::stream::iter(reads)
+        .buffered(CRC_CONCURRENCY)
+        .try_collect()
+        .await
+}
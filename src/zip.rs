@@ -1,7 +1,15 @@
 // © 2019 3D Robotics. License: Apache-2.0
 use bytes::{Bytes, BytesMut, BufMut};
-use crate::stream_range::{ self, StreamRange };
-use chrono::{DateTime, Utc, Datelike, Timelike};
+use crate::stream_range::{ self, BoxError, DynStreamRange, Range, StreamRange };
+use futures::{ future, stream };
+use chrono::{DateTime, Utc, TimeZone, Datelike, Timelike};
+use crate::name_encoding::NameEncoding;
+use crate::entry_filter::EntryFilter;
+use crate::legacy_encryption::{ self, Encrypted };
+use sha2::{ Digest, Sha256 };
+use std::io;
+use std::time::SystemTime;
+use tokio::io::{ AsyncWrite, AsyncWriteExt };
 
 /// A file to be included in a zip archive.
 pub struct ZipEntry {
@@ -9,7 +17,7 @@ pub struct ZipEntry {
     pub archive_path: String,
 
     /// Contents of file.
-    pub data: Box<dyn StreamRange>,
+    pub data: Box<dyn DynStreamRange>,
 
     /// CRC32 checksum of the file contents.
     /// This must be precomputed because it's included in the file header.
@@ -19,6 +27,17 @@ pub struct ZipEntry {
     /// If you want the zip file to be reproducible for Range requests, do
     /// not default to the current time.
     pub last_modified: DateTime<Utc>,
+
+    /// Last access and creation times, stored in the local header's extended timestamp field
+    /// if present.
+    pub last_accessed: Option<DateTime<Utc>>,
+    pub created: Option<DateTime<Utc>>,
+
+    /// Store the entry as a Unix symbolic link to this target. `data` must contain the target.
+    pub symlink_target: Option<String>,
+
+    /// SHA-256 of the file contents, listed in the checksums file if enabled.
+    pub sha256: Option<[u8; 32]>,
 }
 
 /// Options passed to `zip_stream`
@@ -27,6 +46,130 @@ pub struct ZipOptions {
     /// Create a zip file using zip64 extensions even if the file will be under 2^32 bytes.
     /// Otherwise, zip64 will be used only if necessary.
     pub force_zip64: bool,
+
+    /// Number of leading `/`-separated components removed from each entry's name. Names with no
+    /// more components than this are reduced to their last component.
+    pub strip_path_components: usize,
+
+    /// Prefix removed from the start of each entry's name, if present.
+    pub strip_entry_prefix: String,
+
+    /// Top-level directory that all entries are placed under. Empty for none.
+    pub archive_root: String,
+
+    /// Name of a generated text file listing the size and name of every entry, added as the last
+    /// entry of the archive. Empty for none.
+    pub contents_file: String,
+
+    /// Legacy encoding for entry names. Names are stored as UTF-8 if `None`, or if they can't be
+    /// represented in the encoding.
+    pub name_encoding: Option<NameEncoding>,
+
+    /// Name of a generated file listing the SHA-256 and name of every entry in the format of
+    /// `sha256sum`, added after all other entries (including the contents file). Entries without
+    /// a `sha256` are left out of it. Empty for none.
+    pub checksums_file: String,
+
+    /// Manifest the archive was built from, added as `_zipstream_manifest.json` together with its
+    /// SHA-256 in `_zipstream_manifest.json.sha256`, as the first two entries.
+    pub manifest_sidecar: Option<Bytes>,
+
+    /// Archive comment, stored in the end of central directory record. Truncated to 65535 bytes.
+    pub comment: String,
+
+    /// Encrypt every entry with this password using the traditional PKWARE cipher, which is weak
+    /// and only suitable for compatibility with legacy tools.
+    pub legacy_encryption_password: Option<String>,
+
+    /// Only entries whose names, after `strip_path_components` and `strip_entry_prefix`, are
+    /// included by this filter are added to the archive.
+    pub entry_filter: Option<EntryFilter>,
+}
+
+impl ZipOptions {
+    /// Apply `strip_path_components`, `strip_entry_prefix`, and then `archive_root` to an entry name.
+    pub fn entry_name(&self, archive_path: &str) -> String {
+        let name = self.stripped_name(archive_path);
+        let root = self.archive_root.trim_end_matches('/');
+
+        if root.is_empty() {
+            name.to_owned()
+        } else {
+            format!("{}/{}", root, name)
+        }
+    }
+
+    /// Whether the entry filter, if any, includes an entry with this name
+    pub fn includes(&self, archive_path: &str) -> bool {
+        self.entry_filter.as_ref().map(|f| f.includes(self.stripped_name(archive_path))).unwrap_or(true)
+    }
+
+    /// Apply `strip_path_components` and `strip_entry_prefix` to an entry name.
+    fn stripped_name<'a>(&self, archive_path: &'a str) -> &'a str {
+        let name = strip_path_components(archive_path, self.strip_path_components);
+        name.strip_prefix(self.strip_entry_prefix.as_str()).unwrap_or(name)
+    }
+}
+
+/// Remove the first `n` components of `path`, or all but the last if it has no more than `n`.
+fn strip_path_components(path: &str, n: usize) -> &str {
+    if n == 0 {
+        return path;
+    }
+
+    match path.splitn(n + 1, '/').nth(n) {
+        Some(rest) => rest,
+        None => path.rsplit('/').next().unwrap_or(path),
+    }
+}
+
+#[test]
+fn test_strip_path_components() {
+    let path = "org/project/2024/data/file.csv";
+    assert_eq!(strip_path_components(path, 0), path);
+    assert_eq!(strip_path_components(path, 3), "data/file.csv");
+    assert_eq!(strip_path_components(path, 4), "file.csv");
+    assert_eq!(strip_path_components(path, 5), "file.csv");
+    assert_eq!(strip_path_components(path, 10), "file.csv");
+    assert_eq!(strip_path_components("file.csv", 1), "file.csv");
+    assert_eq!(strip_path_components("dir/", 1), "");
+}
+
+/// Normalize a directory prefix for `ZipOptions::archive_root`: leading and repeated slashes are
+/// removed and a trailing slash added. Returns `None` if it contains `.` or `..` components.
+pub fn normalize_archive_root(prefix: &str) -> Option<String> {
+    let segments: Vec<&str> = prefix.split('/').filter(|s| !s.is_empty()).collect();
+
+    if segments.iter().any(|&s| s == "." || s == "..") {
+        return None;
+    }
+
+    Some(segments.iter().map(|s| format!("{}/", s)).collect())
+}
+
+#[test]
+fn test_normalize_archive_root() {
+    assert_eq!(normalize_archive_root("myproject-v1.2").as_deref(), Some("myproject-v1.2/"));
+    assert_eq!(normalize_archive_root("/a//b/").as_deref(), Some("a/b/"));
+    assert_eq!(normalize_archive_root("").as_deref(), Some(""));
+    assert_eq!(normalize_archive_root("a/../../b"), None);
+    assert_eq!(normalize_archive_root("./a"), None);
+}
+
+#[test]
+fn test_entry_name() {
+    let options = ZipOptions { strip_entry_prefix: "data/".into(), archive_root: "export".into(), ..ZipOptions::default() };
+    assert_eq!(options.entry_name("data/a.txt"), "export/a.txt");
+    assert_eq!(options.entry_name("other/a.txt"), "export/other/a.txt");
+
+    let options = ZipOptions { archive_root: "export/".into(), ..ZipOptions::default() };
+    assert_eq!(options.entry_name("a.txt"), "export/a.txt");
+
+    assert_eq!(ZipOptions::default().entry_name("data/a.txt"), "data/a.txt");
+
+    let options = ZipOptions { strip_path_components: 1, strip_entry_prefix: "data/".into(), archive_root: "export".into(), ..ZipOptions::default() };
+    assert_eq!(options.entry_name("org/data/a.txt"), "export/a.txt");
+    assert_eq!(options.entry_name("a.txt"), "export/a.txt");
 }
 
 // Zip format spec:
@@ -56,13 +199,90 @@ fn test_zip_date_time() {
     assert_eq!(zip_date(t), 0x354b);
 }
 
-fn local_file_header(file: &ZipEntry, force_zip64: bool) -> Bytes {
-    let needs_zip64 = file.data.len() >= 0xFFFFFFFF || force_zip64;
-    let mut buf = BytesMut::with_capacity(30 + file.archive_path.len() + if needs_zip64 { 20 } else { 0 } + 9);
+/// An entry name as stored in the file headers
+struct EncodedName {
+    bytes: Vec<u8>,
+
+    /// General purpose bit flag
+    flags: u16,
+
+    /// Info-ZIP Unicode Path extra field, included when `bytes` are in a legacy encoding
+    unicode_path: Bytes,
+}
+
+fn encode_name(name: &str, encoding: Option<NameEncoding>) -> EncodedName {
+    let utf8 = || EncodedName { bytes: name.as_bytes().to_vec(), flags: 0, unicode_path: Bytes::new() };
+
+    let encoding = match encoding {
+        Some(encoding) if !name.is_ascii() => encoding,
+        _ => return utf8(),
+    };
+
+    match encoding.encode(name) {
+        Some(bytes) => {
+            let mut crc = crc32fast::Hasher::new();
+            crc.update(&bytes);
+
+            let mut extra = BytesMut::with_capacity(9 + name.len());
+            extra.put_u16_le(0x7075); // Info-ZIP Unicode Path
+            extra.put_u16_le(5 + name.len() as u16); // Length
+            extra.put_u8(1); // version
+            extra.put_u32_le(crc.finalize()); // CRC-32 of the encoded name
+            extra.put_slice(name.as_bytes());
+
+            EncodedName { bytes, flags: 0, unicode_path: extra.freeze() }
+        }
+        None => {
+            log::warn!("Entry name {:?} can't be represented in {}, using UTF-8", name, encoding);
+            EncodedName { flags: 1 << 11, ..utf8() } // language encoding flag (EFS)
+        }
+    }
+}
+
+/// Info-ZIP extended timestamp extra field (0x5455), holding Unix times with one-second precision.
+/// The flags byte tells which times are present in the local header; the central directory copy
+/// has the same flags but only holds the modification time.
+fn extended_timestamp(file: &ZipEntry, central: bool) -> Bytes {
+    extended_timestamp_field([Some(file.last_modified), file.last_accessed, file.created], central)
+}
+
+/// `extended_timestamp` for modification, access, and creation times
+fn extended_timestamp_field(times: [Option<DateTime<Utc>>; 3], central: bool) -> Bytes {
+    let flags = times.iter().enumerate().fold(0, |flags, (i, t)| if t.is_some() { flags | 1 << i } else { flags });
+    let included = if central { &times[..1] } else { &times[..] };
+    let included: Vec<DateTime<Utc>> = included.iter().flatten().copied().collect();
+
+    let mut buf = BytesMut::with_capacity(5 + 4 * included.len());
+    buf.put_u16_le(0x5455); // UT
+    buf.put_u16_le(1 + 4 * included.len() as u16); // Length
+    buf.put_u8(flags); // bit 0: modification, bit 1: access, bit 2: creation time present
+    for time in included {
+        // Signed seconds since the Unix epoch
+        buf.put_i32_le(time.timestamp().clamp(i32::MIN as i64, i32::MAX as i64) as i32);
+    }
+    buf.freeze()
+}
+
+/// Size of an entry's data as stored, including the encryption header if encrypted
+fn compressed_size(file: &ZipEntry, encrypted: bool) -> u64 {
+    file.data.len() + if encrypted { legacy_encryption::HEADER_LEN } else { 0 }
+}
+
+/// General purpose bit flag
+fn flags(name: &EncodedName, encrypted: bool) -> u16 {
+    name.flags | if encrypted { 1 } else { 0 }
+}
+
+fn local_file_header(file: &ZipEntry, name: &EncodedName, encrypted: bool, force_zip64: bool) -> Bytes {
+    let compressed_size = compressed_size(file, encrypted);
+    let needs_zip64 = compressed_size >= 0xFFFFFFFF || force_zip64;
+    let timestamp = extended_timestamp(file, false);
+    let extra_len = if needs_zip64 { 20 } else { 0 } + timestamp.len() + name.unicode_path.len();
+    let mut buf = BytesMut::with_capacity(30 + name.bytes.len() + extra_len);
 
     buf.put_u32_le(0x04034b50); // local file header signature
     buf.put_u16_le(if needs_zip64 { ZIP64_VERSION } else { BASE_VERSION } as u16); //  version needed to extract
-    buf.put_u16_le(0); // general purpose bit flag
+    buf.put_u16_le(flags(name, encrypted)); // general purpose bit flag
     buf.put_u16_le(0); // compression method
     buf.put_u16_le(zip_time(file.last_modified)); // last mod file time
     buf.put_u16_le(zip_date(file.last_modified)); // last mod file date
@@ -72,41 +292,41 @@ fn local_file_header(file: &ZipEntry, force_zip64: bool) -> Bytes {
         buf.put_u32_le(0xFFFFFFFF); // compressed size
         buf.put_u32_le(0xFFFFFFFF); // uncompressed size
     } else {
-        buf.put_u32_le(file.data.len() as u32); // compressed size
+        buf.put_u32_le(compressed_size as u32); // compressed size
         buf.put_u32_le(file.data.len() as u32); // uncompressed size
     }
 
-    buf.put_u16_le(file.archive_path.len() as u16); // file name length
-    buf.put_u16_le(if needs_zip64 { 20 } else { 0 } + 9); // extra field length
+    buf.put_u16_le(name.bytes.len() as u16); // file name length
+    buf.put_u16_le(extra_len as u16); // extra field length
 
     // file name
-    buf.put_slice(file.archive_path.as_bytes());
+    buf.put_slice(&name.bytes);
 
     if needs_zip64 {
         buf.put_u16_le(0x0001); // Zip64 extended information
         buf.put_u16_le(16); // Size of this "extra" block
         buf.put_u64_le(file.data.len()); // Original uncompressed file size
-        buf.put_u64_le(file.data.len()); // Size of compressed data
+        buf.put_u64_le(compressed_size); // Size of compressed data
     }
 
-    // Extended timestamp header
-    buf.put_u16_le(0x5455); // UT
-    buf.put_u16_le(5); // Length
-    buf.put_u8(1); // last modified date present
-    buf.put_u32_le(file.last_modified.timestamp() as u32); // last modified timestamp
+    buf.put_slice(&timestamp);
+    buf.put_slice(&name.unicode_path);
 
     buf.freeze()
 }
 
-fn central_directory_file_header(file: &ZipEntry, offset: u64, force_zip64: bool) -> Bytes {
-    let needs_zip64 = file.data.len() >= 0xFFFFFFFF || offset >= 0xFFFFFFFF || force_zip64;
-    let mut buf = BytesMut::with_capacity(46 + file.archive_path.len() + if needs_zip64 { 28 } else { 0 } + 9);
+fn central_directory_file_header(file: &ZipEntry, name: &EncodedName, offset: u64, encrypted: bool, force_zip64: bool) -> Bytes {
+    let compressed_size = compressed_size(file, encrypted);
+    let needs_zip64 = compressed_size >= 0xFFFFFFFF || offset >= 0xFFFFFFFF || force_zip64;
+    let timestamp = extended_timestamp(file, true);
+    let extra_len = if needs_zip64 { 28 } else { 0 } + timestamp.len() + name.unicode_path.len();
+    let mut buf = BytesMut::with_capacity(46 + name.bytes.len() + extra_len);
 
     buf.put_u32_le(0x02014b50); // central file header signature
     buf.put_u8(BASE_VERSION); // version made by = zip spec 4.5
     buf.put_u8(3); // version made by = unix
     buf.put_u16_le(if needs_zip64 { ZIP64_VERSION } else { BASE_VERSION } as u16); //  version needed to extract
-    buf.put_u16_le(0); // general purpose bit flag
+    buf.put_u16_le(flags(name, encrypted)); // general purpose bit flag
     buf.put_u16_le(0); // compression method
     buf.put_u16_le(zip_time(file.last_modified)); // last mod file time
     buf.put_u16_le(zip_date(file.last_modified)); // last mod file date
@@ -116,16 +336,20 @@ fn central_directory_file_header(file: &ZipEntry, offset: u64, force_zip64: bool
         buf.put_u32_le(0xFFFFFFFF); // compressed size
         buf.put_u32_le(0xFFFFFFFF); // uncompressed size
     } else {
-        buf.put_u32_le(file.data.len() as u32); // compressed size
+        buf.put_u32_le(compressed_size as u32); // compressed size
         buf.put_u32_le(file.data.len() as u32); // uncompressed size
     }
     
-    buf.put_u16_le(file.archive_path.len() as u16); // file name length
-    buf.put_u16_le(if needs_zip64 { 28 } else { 0 } + 9); // extra field length
+    buf.put_u16_le(name.bytes.len() as u16); // file name length
+    buf.put_u16_le(extra_len as u16); // extra field length
     buf.put_u16_le(0); // file comment length
     buf.put_u16_le(0); // disk number start
     buf.put_u16_le(0); // internal file attributes
-    buf.put_u32_le(0x81A40000); // external file attributes (-rw-r--r--)
+    if file.symlink_target.is_some() {
+        buf.put_u32_le(0xA1FF0000); // external file attributes (lrwxrwxrwx)
+    } else {
+        buf.put_u32_le(0x81A40000); // external file attributes (-rw-r--r--)
+    }
 
     if needs_zip64 {
         buf.put_u32_le(0xFFFFFFFF);
@@ -133,27 +357,35 @@ fn central_directory_file_header(file: &ZipEntry, offset: u64, force_zip64: bool
         buf.put_u32_le(offset as u32); // relative offset of local header
     }
 
-    buf.extend(file.archive_path.as_bytes());
+    buf.extend(&name.bytes);
 
     if needs_zip64 {
         buf.put_u16_le(0x0001); // Zip64 extended information
         buf.put_u16_le(24); // Size of this "extra" block
         buf.put_u64_le(file.data.len()); // Original uncompressed file size
-        buf.put_u64_le(file.data.len()); // Size of compressed data
+        buf.put_u64_le(compressed_size); // Size of compressed data
         buf.put_u64_le(offset); // Offset of local header record
     }
 
-    // Extended timestamp header
-    buf.put_u16_le(0x5455); // UT
-    buf.put_u16_le(5); // Length
-    buf.put_u8(1); // last modified date present
-    buf.put_u32_le(file.last_modified.timestamp() as u32); // last modified timestamp
+    buf.put_slice(&timestamp);
+    buf.put_slice(&name.unicode_path);
 
     buf.freeze()
 }
 
-fn end_of_central_directory(central_directory_offset: u64, size_of_central_directory: u64, num_entries: u64, force_zip64: bool) -> Bytes {
-    let mut buf = BytesMut::with_capacity(56 + 20 + 22);
+/// Truncate a comment to the maximum length of the zip file comment, at a character boundary.
+fn truncate_comment(comment: &str) -> &str {
+    if comment.len() <= 0xFFFF {
+        return comment;
+    }
+
+    let end = (0..=0xFFFF).rev().find(|&i| comment.is_char_boundary(i)).unwrap_or(0);
+    log::warn!("Archive comment is {} bytes, truncating to {}", comment.len(), end);
+    &comment[..end]
+}
+
+fn end_of_central_directory(central_directory_offset: u64, size_of_central_directory: u64, num_entries: u64, force_zip64: bool, comment: &str) -> Bytes {
+    let mut buf = BytesMut::with_capacity(56 + 20 + 22 + comment.len());
 
     if num_entries >= 0xFFFF || size_of_central_directory >= 0xFFFFFFFF || central_directory_offset >= 0xFFFFFFFF || force_zip64 {
         // Zip64 end of central directory record
@@ -187,25 +419,135 @@ fn end_of_central_directory(central_directory_offset: u64, size_of_central_direc
     buf.put_u16_le(num_entries_16); // total number of entries in the central directory
     buf.put_u32_le(size_of_central_directory_32); // size of the central directory
     buf.put_u32_le(central_directory_offset_32); // offset of start of central directory with respect to the starting disk number
-    buf.put_u16_le(0); //  .ZIP file comment length
+    buf.put_u16_le(comment.len() as u16); //  .ZIP file comment length
+    buf.put_slice(comment.as_bytes()); // .ZIP file comment
 
     buf.freeze()
 }
 
+/// Name of the entry holding `ZipOptions::manifest_sidecar`
+pub const MANIFEST_SIDECAR: &str = "_zipstream_manifest.json";
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Generate a file listing the size and name of each entry, one per line.
+fn contents_listing(name: String, files: &[ZipEntry]) -> ZipEntry {
+    let mut listing = String::new();
+    for file in files {
+        listing.push_str(&format!("{:>12}  {}\n", file.data.len(), file.archive_path));
+    }
+    let listing = Bytes::from(listing);
+
+    generated_entry(name, listing, files)
+}
+
+/// Generate a file listing the SHA-256 and name of each entry that has one, one per line, which
+/// `sha256sum -c` can check.
+fn checksums_listing(name: String, files: &[ZipEntry]) -> ZipEntry {
+    let mut listing = String::new();
+    for file in files {
+        if let Some(sha256) = &file.sha256 {
+            listing.push_str(&format!("{}  {}\n", hex(sha256), file.archive_path));
+        }
+    }
+
+    generated_entry(name, Bytes::from(listing), files)
+}
+
+/// An entry with `content` generated from the other entries of the archive.
+/// Its timestamp is that of the newest entry so that the archive is reproducible.
+fn generated_entry(name: String, content: Bytes, files: &[ZipEntry]) -> ZipEntry {
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(&content);
+
+    ZipEntry {
+        archive_path: name,
+        crc: crc.finalize(),
+        sha256: Some(Sha256::digest(&content).into()),
+        data: Box::new(content),
+        last_modified: files.iter().map(|f| f.last_modified).max().unwrap_or_else(|| Utc.timestamp(0, 0)),
+        last_accessed: None,
+        created: None,
+        symlink_target: None,
+    }
+}
+
+/// Apply the entry filter and name options to `files`, and add the manifest sidecar, contents, and
+/// checksums files if enabled.
+pub fn archive_entries(files: impl IntoIterator<Item = ZipEntry>, options: &ZipOptions) -> Vec<ZipEntry> {
+    let mut names = std::collections::HashSet::new();
+    let mut excluded = 0;
+    let mut files: Vec<ZipEntry> = files.into_iter().filter(|file| {
+        let included = options.includes(&file.archive_path);
+        if !included {
+            excluded += 1;
+        }
+        included
+    }).map(|mut file| {
+        let name = options.entry_name(&file.archive_path);
+
+        if options.strip_path_components > 0 {
+            if name.ends_with('/') || name.is_empty() {
+                log::warn!("Stripping path components from {:?} leaves an empty entry name", file.archive_path);
+            } else if !names.insert(name.clone()) {
+                log::warn!("Stripping path components from {:?} duplicates entry name {:?}", file.archive_path, name);
+            }
+        }
+
+        file.archive_path = name;
+        file
+    }).collect();
+
+    if let Some(filter) = &options.entry_filter {
+        log::debug!("Entry filter {:?} excluded {} entries", filter.to_string(), excluded);
+    }
+
+    if let Some(manifest) = &options.manifest_sidecar {
+        // Named relative to the `.sha256` file so that `sha256sum -c` works in the extracted directory
+        let checksum = format!("{}  {}\n", hex(&Sha256::digest(manifest)), MANIFEST_SIDECAR);
+        let sidecar = vec![
+            generated_entry(options.entry_name(MANIFEST_SIDECAR), manifest.clone(), &files),
+            generated_entry(options.entry_name(&format!("{}.sha256", MANIFEST_SIDECAR)), Bytes::from(checksum), &files),
+        ];
+        files.splice(0..0, sidecar);
+    }
+
+    if !options.contents_file.is_empty() {
+        let listing = contents_listing(options.entry_name(&options.contents_file), &files);
+        files.push(listing);
+    }
+
+    if !options.checksums_file.is_empty() {
+        let listing = checksums_listing(options.entry_name(&options.checksums_file), &files);
+        files.push(listing);
+    }
+
+    files
+}
+
 /// Create a `StreamRange` that produces a ZIP file with the passed entries.
 pub fn zip_stream(files: impl IntoIterator<Item = ZipEntry>, options: ZipOptions) -> impl StreamRange {
-    let mut data_parts: Vec<Box<dyn StreamRange>> = Vec::new();
-    let mut central_directory_parts: Vec<Box<dyn StreamRange>> = Vec::new();
+    let mut data_parts: Vec<Box<dyn DynStreamRange>> = Vec::new();
+    let mut central_directory_parts: Vec<Box<dyn DynStreamRange>> = Vec::new();
     let mut offset = 0;
 
-    for file in files {
-        let local_header = local_file_header(&file, options.force_zip64);
-        let central_header = central_directory_file_header(&file, offset, options.force_zip64);
+    for file in archive_entries(files, &options) {
+        let name = encode_name(&file.archive_path, options.name_encoding);
+        let password = options.legacy_encryption_password.as_deref();
+        let local_header = local_file_header(&file, &name, password.is_some(), options.force_zip64);
+        let central_header = central_directory_file_header(&file, &name, offset, password.is_some(), options.force_zip64);
+
+        let data: Box<dyn DynStreamRange> = match password {
+            Some(password) => Box::new(Encrypted::new(file.data, password, &file.archive_path, file.crc)),
+            None => file.data,
+        };
 
-        offset += local_header.len() as u64 + file.data.len() as u64;
+        offset += local_header.len() as u64 + data.len() as u64;
 
         data_parts.push(Box::new(local_header));
-        data_parts.push(file.data);
+        data_parts.push(data);
 
         central_directory_parts.push(Box::new(central_header));
     }
@@ -214,11 +556,257 @@ pub fn zip_stream(files: impl IntoIterator<Item = ZipEntry>, options: ZipOptions
     let size_of_central_directory = central_directory_parts.iter().map(|x| x.len() as u64).sum();
 
     data_parts.extend(central_directory_parts.into_iter());
-    data_parts.push(Box::new(end_of_central_directory(offset, size_of_central_directory, num_entries, options.force_zip64)));
+    let comment = truncate_comment(&options.comment);
+    data_parts.push(Box::new(end_of_central_directory(offset, size_of_central_directory, num_entries, options.force_zip64, comment)));
 
     stream_range::Concatenated(data_parts)
 }
 
+/// Stands in for the data of an entry copied by `layout_copy`: it has the same length, but can't be read
+struct Unread(u64);
+
+impl StreamRange for Unread {
+    type Stream = stream::Once<future::Ready<Result<Bytes, BoxError>>>;
+
+    fn len(&self) -> u64 { self.0 }
+    fn stream_range(&self, _range: Range) -> Self::Stream {
+        stream::once(future::err("entry copied for its layout has no data".into()))
+    }
+}
+
+/// A copy of `file` without its data, to lay out an archive without reading anything
+pub fn layout_copy(file: &ZipEntry) -> ZipEntry {
+    ZipEntry {
+        archive_path: file.archive_path.clone(),
+        data: Box::new(Unread(file.data.len())),
+        crc: file.crc,
+        last_modified: file.last_modified,
+        last_accessed: file.last_accessed,
+        created: file.created,
+        symlink_target: file.symlink_target.clone(),
+        sha256: file.sha256,
+    }
+}
+
+/// The entries that `archive_entries` places before the checksums file, without their data
+pub(crate) fn entries_before_checksums(files: &[ZipEntry], options: &ZipOptions) -> Vec<ZipEntry> {
+    let options = ZipOptions { checksums_file: String::new(), ..options.clone() };
+    archive_entries(files.iter().map(layout_copy), &options)
+}
+
+/// Offset of the checksums file's local header in the zip made from `files`. Only that entry and
+/// the central directory after it depend on the other entries' SHA-256s.
+pub fn zip_checksums_offset(files: &[ZipEntry], options: &ZipOptions) -> u64 {
+    let encrypted = options.legacy_encryption_password.is_some();
+    entries_before_checksums(files, options).iter().map(|file| {
+        let name = encode_name(&file.archive_path, options.name_encoding);
+        local_file_header(file, &name, encrypted, options.force_zip64).len() as u64 + compressed_size(file, encrypted)
+    }).sum()
+}
+
+/// Compression method of an entry written by `StreamingZipWriter`. Like `zip_stream`, it only
+/// stores data uncompressed.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CompressionMethod {
+    Stored,
+}
+
+impl CompressionMethod {
+    fn code(self) -> u16 {
+        match self {
+            CompressionMethod::Stored => 0,
+        }
+    }
+}
+
+/// An entry written by `StreamingZipWriter`, to be listed in the central directory
+struct WrittenEntry {
+    name: EncodedName,
+    method: CompressionMethod,
+    last_modified: DateTime<Utc>,
+    offset: u64,
+    crc: u32,
+    size: u64,
+}
+
+/// General purpose bit flag of a `WrittenEntry`: its CRC and sizes are in the data descriptor
+fn streaming_flags(entry: &WrittenEntry) -> u16 {
+    entry.name.flags | 1 << 3
+}
+
+fn streaming_local_file_header(entry: &WrittenEntry) -> Bytes {
+    let timestamp = extended_timestamp_field([Some(entry.last_modified), None, None], false);
+    let extra_len = 20 + timestamp.len() + entry.name.unicode_path.len();
+    let mut buf = BytesMut::with_capacity(30 + entry.name.bytes.len() + extra_len);
+
+    buf.put_u32_le(0x04034b50); // local file header signature
+    buf.put_u16_le(ZIP64_VERSION as u16); //  version needed to extract
+    buf.put_u16_le(streaming_flags(entry)); // general purpose bit flag
+    buf.put_u16_le(entry.method.code()); // compression method
+    buf.put_u16_le(zip_time(entry.last_modified)); // last mod file time
+    buf.put_u16_le(zip_date(entry.last_modified)); // last mod file date
+    buf.put_u32_le(0); // crc-32, in data descriptor
+    buf.put_u32_le(0xFFFFFFFF); // compressed size, in data descriptor
+    buf.put_u32_le(0xFFFFFFFF); // uncompressed size, in data descriptor
+    buf.put_u16_le(entry.name.bytes.len() as u16); // file name length
+    buf.put_u16_le(extra_len as u16); // extra field length
+
+    // file name
+    buf.put_slice(&entry.name.bytes);
+
+    // The size isn't known yet, so always use Zip64 so that the data descriptor has 8-byte sizes
+    buf.put_u16_le(0x0001); // Zip64 extended information
+    buf.put_u16_le(16); // Size of this "extra" block
+    buf.put_u64_le(0); // Original uncompressed file size
+    buf.put_u64_le(0); // Size of compressed data
+
+    buf.put_slice(&timestamp);
+    buf.put_slice(&entry.name.unicode_path);
+
+    buf.freeze()
+}
+
+fn data_descriptor(entry: &WrittenEntry) -> Bytes {
+    let mut buf = BytesMut::with_capacity(24);
+    buf.put_u32_le(0x08074b50); // data descriptor signature
+    buf.put_u32_le(entry.crc); // crc-32
+    buf.put_u64_le(entry.size); // compressed size
+    buf.put_u64_le(entry.size); // uncompressed size
+    buf.freeze()
+}
+
+fn streaming_central_directory_file_header(entry: &WrittenEntry) -> Bytes {
+    let needs_zip64 = entry.size >= 0xFFFFFFFF || entry.offset >= 0xFFFFFFFF;
+    let timestamp = extended_timestamp_field([Some(entry.last_modified), None, None], true);
+    let extra_len = if needs_zip64 { 28 } else { 0 } + timestamp.len() + entry.name.unicode_path.len();
+    let mut buf = BytesMut::with_capacity(46 + entry.name.bytes.len() + extra_len);
+
+    buf.put_u32_le(0x02014b50); // central file header signature
+    buf.put_u8(ZIP64_VERSION); // version made by = zip spec 4.5
+    buf.put_u8(3); // version made by = unix
+    buf.put_u16_le(ZIP64_VERSION as u16); //  version needed to extract
+    buf.put_u16_le(streaming_flags(entry)); // general purpose bit flag
+    buf.put_u16_le(entry.method.code()); // compression method
+    buf.put_u16_le(zip_time(entry.last_modified)); // last mod file time
+    buf.put_u16_le(zip_date(entry.last_modified)); // last mod file date
+    buf.put_u32_le(entry.crc); // crc-32
+
+    if needs_zip64 {
+        buf.put_u32_le(0xFFFFFFFF); // compressed size
+        buf.put_u32_le(0xFFFFFFFF); // uncompressed size
+    } else {
+        buf.put_u32_le(entry.size as u32); // compressed size
+        buf.put_u32_le(entry.size as u32); // uncompressed size
+    }
+
+    buf.put_u16_le(entry.name.bytes.len() as u16); // file name length
+    buf.put_u16_le(extra_len as u16); // extra field length
+    buf.put_u16_le(0); // file comment length
+    buf.put_u16_le(0); // disk number start
+    buf.put_u16_le(0); // internal file attributes
+    buf.put_u32_le(0x81A40000); // external file attributes (-rw-r--r--)
+
+    if needs_zip64 {
+        buf.put_u32_le(0xFFFFFFFF);
+    } else {
+        buf.put_u32_le(entry.offset as u32); // relative offset of local header
+    }
+
+    buf.extend(&entry.name.bytes);
+
+    if needs_zip64 {
+        buf.put_u16_le(0x0001); // Zip64 extended information
+        buf.put_u16_le(24); // Size of this "extra" block
+        buf.put_u64_le(entry.size); // Original uncompressed file size
+        buf.put_u64_le(entry.size); // Size of compressed data
+        buf.put_u64_le(entry.offset); // Offset of local header record
+    }
+
+    buf.put_slice(&timestamp);
+    buf.put_slice(&entry.name.unicode_path);
+
+    buf.freeze()
+}
+
+/// Writes a ZIP file to any `AsyncWrite`, such as a `tokio::fs::File`, as its entries' data
+/// arrives. Unlike `zip_stream`, the CRCs and sizes don't need to be known up front: each entry's
+/// data is followed by a data descriptor holding them. The output can't be served by range
+/// because its layout isn't known in advance.
+pub struct StreamingZipWriter<W> {
+    writer: W,
+    offset: u64,
+    entries: Vec<WrittenEntry>,
+    current: Option<(WrittenEntry, crc32fast::Hasher)>,
+}
+
+impl<W: AsyncWrite + Unpin> StreamingZipWriter<W> {
+    pub fn new(writer: W) -> Self {
+        StreamingZipWriter { writer, offset: 0, entries: Vec::new(), current: None }
+    }
+
+    async fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.writer.write_all(buf).await?;
+        self.offset += buf.len() as u64;
+        Ok(())
+    }
+
+    /// Start an entry named `name`, finishing the current entry if there is one
+    pub async fn begin_entry(&mut self, name: &str, method: CompressionMethod, timestamp: SystemTime) -> io::Result<()> {
+        self.finish_entry().await?;
+
+        let entry = WrittenEntry {
+            name: encode_name(name, None),
+            method,
+            last_modified: timestamp.into(),
+            offset: self.offset,
+            crc: 0,
+            size: 0,
+        };
+        self.write(&streaming_local_file_header(&entry)).await?;
+        self.current = Some((entry, crc32fast::Hasher::new()));
+        Ok(())
+    }
+
+    /// Append `data` to the current entry
+    pub async fn write_entry_data(&mut self, data: &[u8]) -> io::Result<()> {
+        let (entry, crc) = self.current.as_mut()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no ZIP entry has been begun"))?;
+        crc.update(data);
+        entry.size += data.len() as u64;
+        self.write(data).await
+    }
+
+    /// End the current entry, if any, by writing its data descriptor
+    pub async fn finish_entry(&mut self) -> io::Result<()> {
+        if let Some((mut entry, crc)) = self.current.take() {
+            entry.crc = crc.finalize();
+            self.write(&data_descriptor(&entry)).await?;
+            self.entries.push(entry);
+        }
+        Ok(())
+    }
+
+    /// Finish the current entry and write the central directory, completing the file. Nothing more
+    /// should be written after this.
+    pub async fn finish(&mut self) -> io::Result<()> {
+        self.finish_entry().await?;
+
+        let central_directory_offset = self.offset;
+        let entries = std::mem::take(&mut self.entries);
+        for entry in &entries {
+            self.write(&streaming_central_directory_file_header(entry)).await?;
+        }
+        let size_of_central_directory = self.offset - central_directory_offset;
+        self.write(&end_of_central_directory(central_directory_offset, size_of_central_directory, entries.len() as u64, false, "")).await?;
+        self.writer.flush().await
+    }
+
+    /// The wrapped writer
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -227,7 +815,8 @@ mod test {
     use crate::stream_range::{ Range, StreamRange,  };
     use std::process::Command;
 
-    async fn concat<E>(mut stream: impl Stream<Item = Result<Bytes, E>> + Unpin) -> Result<Vec<u8>, E> {
+    async fn concat<E>(stream: impl Stream<Item = Result<Bytes, E>>) -> Result<Vec<u8>, E> {
+        futures::pin_mut!(stream);
         let mut v = Vec::new();
         while let Some(buf) = stream.next().await {
             v.put(buf?);
@@ -242,12 +831,20 @@ mod test {
                 data: Box::new(Bytes::from_static(&b"xx"[..])),
                 crc: 0xf8e1180f,
                 last_modified: "2006-11-10T15:40:56Z".parse::<DateTime<Utc>>().unwrap(),
+                last_accessed: None,
+                created: None,
+                symlink_target: None,
+                sha256: None,
             },
             ZipEntry {
                 archive_path: "bar.txt".into(),
                 data: Box::new(Bytes::from_static(&b"ABC"[..])),
                 crc: 0xa3830348,
                 last_modified: "2018-12-06T20:15:59Z".parse::<DateTime<Utc>>().unwrap(),
+                last_accessed: None,
+                created: None,
+                symlink_target: None,
+                sha256: None,
             }
         ]
     }
@@ -269,10 +866,356 @@ mod test {
         }
     }
 
+    /// Read the file names from the local and central directory headers of a zip file
+    fn header_names(buf: &[u8]) -> (Vec<String>, Vec<String>) {
+        let u16_at = |pos: usize| u16::from_le_bytes([buf[pos], buf[pos + 1]]) as usize;
+        let names = |sig: u32, len_offset: usize, name_offset: usize| {
+            let sig = sig.to_le_bytes();
+            (0..buf.len() - 4).filter(|&i| buf[i..i + 4] == sig)
+                .map(|i| String::from_utf8(buf[i + name_offset..i + name_offset + u16_at(i + len_offset)].to_vec()).unwrap())
+                .collect::<Vec<_>>()
+        };
+        (names(0x04034b50, 26, 30), names(0x02014b50, 28, 46))
+    }
+
+    #[tokio::test]
+    async fn test_archive_root() {
+        let zip = zip_stream(test_entries(), ZipOptions { archive_root: "export".into(), ..ZipOptions::default() });
+        let buf = concat(zip.stream_range(Range { start: 0, end: zip.len() })).await.unwrap();
+        let expected = vec!["export/foo.txt".to_string(), "export/bar.txt".to_string()];
+        assert_eq!(header_names(&buf), (expected.clone(), expected));
+    }
+
+    #[tokio::test]
+    async fn test_strip_entry_prefix() {
+        let mut entries = test_entries();
+        entries[0].archive_path = "data/foo.txt".into();
+        entries[1].archive_path = "data/sub/bar.txt".into();
+
+        let zip = zip_stream(entries, ZipOptions { strip_entry_prefix: "data/".into(), ..ZipOptions::default() });
+        let buf = concat(zip.stream_range(Range { start: 0, end: zip.len() })).await.unwrap();
+        let expected = vec!["foo.txt".to_string(), "sub/bar.txt".to_string()];
+        assert_eq!(header_names(&buf), (expected.clone(), expected));
+
+        std::fs::write("test_strip.zip", &buf).unwrap();
+        assert!(Command::new("unzip").arg("-t").arg("test_strip.zip").status().unwrap().success());
+    }
+
+    #[tokio::test]
+    async fn test_contents_file() {
+        let zip = zip_stream(test_entries(), ZipOptions { contents_file: "CONTENTS.txt".into(), ..ZipOptions::default() });
+        let buf = concat(zip.stream_range(Range { start: 0, end: zip.len() })).await.unwrap();
+
+        let (local_names, central_names) = header_names(&buf);
+        assert_eq!(local_names, vec!["foo.txt", "bar.txt", "CONTENTS.txt"]);
+        assert_eq!(central_names, local_names);
+
+        std::fs::write("test_contents.zip", &buf).unwrap();
+        assert!(Command::new("unzip").arg("-t").arg("test_contents.zip").status().unwrap().success());
+
+        let listing = Command::new("unzip").arg("-p").arg("test_contents.zip").arg("CONTENTS.txt").output().unwrap().stdout;
+        assert_eq!(String::from_utf8(listing).unwrap(), "           2  foo.txt\n           3  bar.txt\n");
+    }
+
+    #[tokio::test]
+    async fn test_checksums_file() {
+        let mut entries = test_entries();
+        entries[0].sha256 = Some(Sha256::digest(b"xx").into());
+        let options = ZipOptions { contents_file: "CONTENTS.txt".into(), checksums_file: "sha256sums.txt".into(), ..ZipOptions::default() };
+        let zip = zip_stream(entries, options);
+        let buf = concat(zip.stream_range(Range { start: 0, end: zip.len() })).await.unwrap();
+
+        // Last, and covering the contents file but not bar.txt, which has no checksum
+        let (local_names, _) = header_names(&buf);
+        assert_eq!(local_names, vec!["foo.txt", "bar.txt", "CONTENTS.txt", "sha256sums.txt"]);
+
+        std::fs::write("test_checksums.zip", &buf).unwrap();
+        let listing = Command::new("unzip").arg("-p").arg("test_checksums.zip").arg("sha256sums.txt").output().unwrap().stdout;
+        let listing = String::from_utf8(listing).unwrap();
+        assert!(listing.starts_with("5dde896887f6754c9b15bfe3a441ae4806df2fde94001311e08bf110622e0bbe  foo.txt\n"), "{}", listing);
+        assert!(listing.ends_with("  CONTENTS.txt\n"), "{}", listing);
+        assert_eq!(listing.lines().count(), 2);
+
+        let _ = std::fs::remove_dir_all("test_checksums");
+        assert!(Command::new("unzip").arg("-q").arg("-d").arg("test_checksums").arg("test_checksums.zip").status().unwrap().success());
+        assert!(Command::new("sha256sum").arg("-c").arg("--quiet").arg("sha256sums.txt").current_dir("test_checksums").status().unwrap().success());
+    }
+
+    #[tokio::test]
+    async fn test_decoded_entry() {
+        use async_compression::tokio::bufread::GzipEncoder;
+        use tokio::io::AsyncReadExt;
+        use crate::stream_range::{ ContentEncoding, Decoded };
+
+        let content = b"Hello World\n".repeat(1000);
+        let mut gzip = Vec::new();
+        GzipEncoder::new(&content[..]).read_to_end(&mut gzip).await.unwrap();
+
+        let entry = ZipEntry {
+            archive_path: "hello.txt".into(),
+            data: Box::new(Decoded { inner: Bytes::from(gzip), encoding: ContentEncoding::Gzip, len: content.len() as u64 }),
+            crc: { let mut crc = crc32fast::Hasher::new(); crc.update(&content); crc.finalize() },
+            ..test_entries().remove(0)
+        };
+        let zip = zip_stream(vec![entry], ZipOptions::default());
+        let buf = concat(zip.stream_range(Range { start: 0, end: zip.len() })).await.unwrap();
+
+        std::fs::write("test_decoded.zip", &buf).unwrap();
+        assert!(Command::new("unzip").arg("-t").arg("test_decoded.zip").status().unwrap().success());
+        let extracted = Command::new("unzip").arg("-p").arg("test_decoded.zip").arg("hello.txt").output().unwrap().stdout;
+        assert_eq!(extracted, content);
+    }
+
+    #[tokio::test]
+    async fn test_manifest_sidecar() {
+        let manifest = Bytes::from_static(br#"{ "filename": "test.zip", "entries": [] }"#);
+        let options = ZipOptions { manifest_sidecar: Some(manifest.clone()), archive_root: "export".into(), ..ZipOptions::default() };
+        let zip = zip_stream(test_entries(), options);
+        let buf = concat(zip.stream_range(Range { start: 0, end: zip.len() })).await.unwrap();
+
+        let (local_names, central_names) = header_names(&buf);
+        assert_eq!(local_names, vec![
+            "export/_zipstream_manifest.json", "export/_zipstream_manifest.json.sha256", "export/foo.txt", "export/bar.txt",
+        ]);
+        assert_eq!(central_names, local_names);
+
+        std::fs::write("test_sidecar.zip", &buf).unwrap();
+        assert!(Command::new("unzip").arg("-t").arg("test_sidecar.zip").status().unwrap().success());
+        let extracted = Command::new("unzip").arg("-p").arg("test_sidecar.zip").arg("export/_zipstream_manifest.json").output().unwrap().stdout;
+        assert_eq!(extracted, manifest);
+
+        let _ = std::fs::remove_dir_all("test_sidecar");
+        assert!(Command::new("unzip").arg("-q").arg("-d").arg("test_sidecar").arg("test_sidecar.zip").status().unwrap().success());
+        assert!(Command::new("sha256sum").arg("-c").arg("--quiet").arg("_zipstream_manifest.json.sha256")
+            .current_dir("test_sidecar/export").status().unwrap().success());
+    }
+
+    #[tokio::test]
+    async fn test_streaming_zip_writer() {
+        let time = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_500_000_000);
+        let mut zip = StreamingZipWriter::new(Vec::new());
+        assert!(zip.write_entry_data(b"no entry").await.is_err());
+
+        zip.begin_entry("hello.txt", CompressionMethod::Stored, time).await.unwrap();
+        zip.write_entry_data(b"Hello ").await.unwrap();
+        zip.write_entry_data(b"World\n").await.unwrap();
+        zip.finish_entry().await.unwrap();
+        zip.begin_entry("empty.txt", CompressionMethod::Stored, time).await.unwrap();
+        zip.begin_entry("dir/large.txt", CompressionMethod::Stored, time).await.unwrap();
+        zip.write_entry_data(&b"x".repeat(100_000)).await.unwrap();
+        zip.finish().await.unwrap();
+        let buf = zip.into_inner();
+
+        let (local_names, central_names) = header_names(&buf);
+        assert_eq!(local_names, vec!["hello.txt", "empty.txt", "dir/large.txt"]);
+        assert_eq!(central_names, local_names);
+
+        std::fs::write("test_streaming.zip", &buf).unwrap();
+        assert!(Command::new("unzip").arg("-t").arg("test_streaming.zip").status().unwrap().success());
+        assert!(Command::new("python3").arg("-m").arg("zipfile").arg("-t").arg("test_streaming.zip").status().unwrap().success());
+        let extracted = Command::new("unzip").arg("-p").arg("test_streaming.zip").arg("hello.txt").output().unwrap().stdout;
+        assert_eq!(extracted, b"Hello World\n");
+        let extracted = Command::new("unzip").arg("-p").arg("test_streaming.zip").arg("dir/large.txt").output().unwrap().stdout;
+        assert_eq!(extracted.len(), 100_000);
+    }
+
+    #[tokio::test]
+    async fn test_name_encoding() {
+        let mut entries = test_entries();
+        entries[0].archive_path = "Über.txt".into();
+        entries[1].archive_path = "日本.txt".into();
+
+        let zip = zip_stream(entries, ZipOptions { name_encoding: Some("cp437".parse().unwrap()), ..ZipOptions::default() });
+        let buf = concat(zip.stream_range(Range { start: 0, end: zip.len() })).await.unwrap();
+        let u16_at = |pos: usize| u16::from_le_bytes([buf[pos], buf[pos + 1]]);
+
+        // Encoded in CP437 with the Unicode Path extra field, after the UT extra field
+        assert_eq!(u16_at(6), 0);
+        assert_eq!(&buf[30..38], b"\x9Aber.txt");
+        assert_eq!(u16_at(28), 9 + 9 + "Über.txt".len() as u16);
+        assert_eq!(u16_at(47), 0x7075);
+        assert_eq!(&buf[56..65], "Über.txt".as_bytes());
+
+        // Not representable in CP437, so stored as UTF-8 with the flag set
+        let second = 65 + 2;
+        assert_eq!(u16_at(second + 6), 1 << 11);
+        assert_eq!(&buf[second + 30..second + 40], "日本.txt".as_bytes());
+
+        std::fs::write("test_name_encoding.zip", &buf).unwrap();
+        assert!(Command::new("unzip").arg("-t").arg("test_name_encoding.zip").status().unwrap().success());
+    }
+
+    #[tokio::test]
+    async fn test_symlink() {
+        let mut entries = test_entries();
+        entries[1].symlink_target = Some("foo.txt".into());
+        entries[1].archive_path = "link".into();
+        entries[1].data = Box::new(Bytes::from_static(b"foo.txt"));
+        entries[1].crc = 0x3451a5e8;
+
+        let zip = zip_stream(entries, ZipOptions::default());
+        let buf = concat(zip.stream_range(Range { start: 0, end: zip.len() })).await.unwrap();
+
+        let central: Vec<usize> = (0..buf.len() - 4).filter(|&i| buf[i..i + 4] == 0x02014b50u32.to_le_bytes()).collect();
+        let external_attributes = |i: usize| u32::from_le_bytes([buf[i + 38], buf[i + 39], buf[i + 40], buf[i + 41]]);
+        assert_eq!(external_attributes(central[0]) >> 16, 0o100644);
+        assert_eq!(external_attributes(central[1]) >> 16, 0o120777);
+
+        std::fs::write("test_symlink.zip", &buf).unwrap();
+        let listing = Command::new("unzip").arg("-Z").arg("test_symlink.zip").output().unwrap().stdout;
+        assert!(String::from_utf8(listing).unwrap().lines().any(|l| l.starts_with("lrwxrwxrwx") && l.ends_with("link")));
+    }
+
+    #[test]
+    fn test_comment() {
+        let read_comment = |comment: &str| {
+            let zip = zip_stream(test_entries(), ZipOptions { comment: comment.into(), ..ZipOptions::default() });
+            let buf = futures::executor::block_on(concat(zip.stream_range(Range { start: 0, end: zip.len() }))).unwrap();
+            std::fs::write("test_comment.zip", &buf).unwrap();
+            assert!(Command::new("unzip").arg("-tq").arg("test_comment.zip").status().unwrap().success());
+            let output = Command::new("python3").arg("-c")
+                .arg("import sys, zipfile; sys.stdout.buffer.write(zipfile.ZipFile('test_comment.zip').comment)")
+                .output().unwrap();
+            String::from_utf8(output.stdout).unwrap()
+        };
+
+        assert_eq!(read_comment("sha256=abc"), "sha256=abc");
+
+        // Truncated without splitting the three-byte character that crosses the limit
+        let long = format!("{}日本", "x".repeat(0xFFFF - 4));
+        assert_eq!(read_comment(&long), format!("{}日", "x".repeat(0xFFFF - 4)));
+    }
+
+    #[tokio::test]
+    async fn test_legacy_encryption() {
+        let options = ZipOptions { legacy_encryption_password: Some("secret".into()), ..ZipOptions::default() };
+        let zip = zip_stream(test_entries(), options);
+        let buf = concat(zip.stream_range(Range { start: 0, end: zip.len() })).await.unwrap();
+        assert_eq!(u16::from_le_bytes([buf[6], buf[7]]) & 1, 1);
+
+        // Ranges starting within an entry agree with the whole archive
+        for start in 30..60 {
+            assert_eq!(concat(zip.stream_range(Range { start, end: start + 20 })).await.unwrap(), &buf[start as usize..start as usize + 20]);
+        }
+
+        std::fs::write("test_encrypted.zip", &buf).unwrap();
+        let unzip = |password: &str| Command::new("unzip").arg("-P").arg(password).arg("-p").arg("test_encrypted.zip").arg("bar.txt").output().unwrap();
+        assert_eq!(unzip("secret").stdout, b"ABC");
+        assert!(!unzip("wrong").status.success());
+
+        let python = Command::new("python3").arg("-c")
+            .arg("import zipfile; print(zipfile.ZipFile('test_encrypted.zip').read('foo.txt', pwd=b'secret').decode())")
+            .output().unwrap();
+        assert_eq!(python.stdout, b"xx\n");
+    }
+
+    /// A large entry streamed in chunks, standing in for an S3 object
+    struct Chunked(u64);
+
+    impl StreamRange for Chunked {
+        type Stream = stream_range::BoxBytesStream;
+
+        fn len(&self) -> u64 { self.0 }
+        fn stream_range(&self, range: Range) -> Self::Stream {
+            static CHUNK: [u8; 1 << 16] = [0x5a; 1 << 16];
+            let chunks = (range.start..range.end).step_by(CHUNK.len()).map(move |start| {
+                Ok(Bytes::from_static(&CHUNK[..(range.end - start).min(CHUNK.len() as u64) as usize]))
+            });
+            Box::pin(futures::stream::iter(chunks))
+        }
+    }
+
+    /// Assembling an archive never buffers a whole entry, however large
+    #[test]
+    fn test_constant_memory() {
+        use crate::stream_range::alloc_count;
+        use crate::tar::tar_stream;
+        use futures::TryStreamExt;
+
+        let entry = |len: u64| {
+            let mut entries = test_entries();
+            entries[0].data = Box::new(Chunked(len));
+            entries
+        };
+        let drain = |archive: &dyn DynStreamRange| {
+            let len = futures::executor::block_on(archive.dyn_stream_range(Range { start: 0, end: archive.dyn_len() })
+                .try_fold(0, |n, chunk| futures::future::ok(n + chunk.len() as u64))).unwrap();
+            assert_eq!(len, archive.dyn_len());
+        };
+
+        alloc_count::take_largest();
+        drain(&zip_stream(entry(300 << 20), ZipOptions::default()));
+        drain(&tar_stream(entry(300 << 20), ZipOptions::default()));
+        drain(&zip_stream(entry(16 << 20), ZipOptions { legacy_encryption_password: Some("secret".into()), ..ZipOptions::default() }));
+        assert!(alloc_count::take_largest() <= 1 << 16, "allocated a buffer proportional to the entry size");
+    }
+
+    /// Check that each entry's `last_modified` is stored as the MS-DOS time and date in both the
+    /// local and central directory file headers.
+    #[tokio::test]
+    async fn test_last_modified_in_headers() {
+        let entries = test_entries();
+        let expected: Vec<_> = entries.iter().map(|e| (zip_time(e.last_modified), zip_date(e.last_modified))).collect();
+
+        let zip = zip_stream(entries, ZipOptions::default());
+        let buf = concat(zip.stream_range(Range { start: 0, end: zip.len() })).await.unwrap();
+
+        let u16_at = |pos: usize| u16::from_le_bytes([buf[pos], buf[pos + 1]]);
+        let find_all = |sig: u32| {
+            let sig = sig.to_le_bytes();
+            (0..buf.len() - 4).filter(|&i| buf[i..i + 4] == sig).collect::<Vec<_>>()
+        };
+
+        let local: Vec<_> = find_all(0x04034b50).into_iter().map(|i| (u16_at(i + 10), u16_at(i + 12))).collect();
+        let central: Vec<_> = find_all(0x02014b50).into_iter().map(|i| (u16_at(i + 12), u16_at(i + 14))).collect();
+
+        assert_eq!(local, expected);
+        assert_eq!(central, expected);
+
+        // 2006-11-10T15:40:56Z
+        assert_eq!(local[0], ((15 << 11) | (40 << 5) | (56 / 2), ((2006 - 1980) << 9) | (11 << 5) | 10));
+    }
+
+    /// Decode the extended timestamp extra field from the local and central directory headers
+    #[tokio::test]
+    async fn test_extended_timestamp() {
+        let mut entries = test_entries();
+        entries[1].last_accessed = Some("2019-01-02T03:04:05Z".parse().unwrap());
+        entries[1].created = Some("1969-07-20T20:17:40Z".parse().unwrap());
+
+        let zip = zip_stream(entries, ZipOptions::default());
+        let buf = concat(zip.stream_range(Range { start: 0, end: zip.len() })).await.unwrap();
+
+        let u16_at = |pos: usize| u16::from_le_bytes([buf[pos], buf[pos + 1]]);
+        let i32_at = |pos: usize| i32::from_le_bytes([buf[pos], buf[pos + 1], buf[pos + 2], buf[pos + 3]]);
+        let find_all = |sig: u32| {
+            let sig = sig.to_le_bytes();
+            (0..buf.len() - 4).filter(|&i| buf[i..i + 4] == sig).collect::<Vec<_>>()
+        };
+
+        // Returns (flags, times) of the UT field in the header at `pos`
+        let decode = |pos: usize, header_len: usize, name_len_at: usize| {
+            let extra = pos + header_len + u16_at(pos + name_len_at) as usize;
+            assert_eq!(u16_at(extra), 0x5455);
+            let len = u16_at(extra + 2) as usize;
+            let times: Vec<i32> = (0..(len - 1) / 4).map(|i| i32_at(extra + 5 + 4 * i)).collect();
+            (buf[extra + 4], times)
+        };
+
+        let local: Vec<_> = find_all(0x04034b50).into_iter().map(|i| decode(i, 30, 26)).collect();
+        let central: Vec<_> = find_all(0x02014b50).into_iter().map(|i| decode(i, 46, 28)).collect();
+
+        assert_eq!(local, vec![(1, vec![1163173256]), (7, vec![1544127359, 1546398245, -14182940])]);
+        assert_eq!(central, vec![(1, vec![1163173256]), (7, vec![1544127359])]);
+
+        std::fs::write("test_extended_timestamp.zip", &buf).unwrap();
+        assert!(Command::new("unzip").arg("-t").arg("test_extended_timestamp.zip").status().unwrap().success());
+    }
+
     /// Generate a 32-bit zip file and check it with zipinfo, unzip, and python.
     #[tokio::test]
     async fn test_zip32() {
-        let zip = zip_stream(test_entries(), ZipOptions { force_zip64: false });
+        let zip = zip_stream(test_entries(), ZipOptions { force_zip64: false, ..ZipOptions::default() });
 
         let buf = concat(zip.stream_range(Range { start: 0, end: zip.len() })).await.unwrap();
         std::fs::write("test.zip", &buf).unwrap();
@@ -285,7 +1228,7 @@ mod test {
     /// Generate a 64-bit zip file and check it with zipinfo, unzip, and python.
     #[tokio::test]
     async fn test_zip64() {
-        let zip = zip_stream(test_entries(), ZipOptions { force_zip64: true });
+        let zip = zip_stream(test_entries(), ZipOptions { force_zip64: true, ..ZipOptions::default() });
 
         let buf = concat(zip.stream_range(Range { start: 0, end: zip.len() })).await.unwrap();
         std::fs::write("test64.zip", &buf).unwrap();
@@ -0,0 +1,219 @@
+// © 2019 3D Robotics. License: Apache-2.0
+//! End-to-end tests of the zipstream binary, with `wiremock` as the upstream manifest server and
+//! S3 objects stored in a MinIO container. Requires Docker; run with
+//! `cargo test --features integration-tests --test integration`.
+#![cfg(feature = "integration-tests")]
+
+use std::io::{ Cursor, Read };
+use std::net::{ SocketAddr, TcpListener, TcpStream };
+use std::process::{ Child, Command };
+use std::time::{ Duration, Instant };
+
+use hyper::{ Body, Client, Request, StatusCode };
+use rusoto_core::{ HttpClient, Region, credential::StaticProvider };
+use rusoto_s3::{ CreateBucketRequest, PutObjectRequest, S3, S3Client };
+use serde_json::json;
+use testcontainers::{ clients, core::WaitFor, GenericImage, RunnableImage };
+use wiremock::{ Mock, MockServer, ResponseTemplate, matchers::{ method, path } };
+
+const ACCESS_KEY: &str = "zipstream";
+const SECRET_KEY: &str = "zipstream-secret";
+const BUCKET: &str = "zipstream-test";
+
+/// Kills the zipstream process when dropped, so that a failed assertion doesn't leak it
+struct Zipstream {
+    process: Child,
+    addr: SocketAddr,
+}
+
+impl Zipstream {
+    fn start(upstream: &str, s3_endpoint: &str) -> Zipstream {
+        // Pick a free port; the listener is closed before zipstream binds it
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+        let process = Command::new(env!("CARGO_BIN_EXE_zipstream"))
+            .arg("--upstream").arg(upstream)
+            .arg("--s3-endpoint").arg(s3_endpoint)
+            .arg("--listen").arg(addr.to_string())
+            .env("AWS_ACCESS_KEY_ID", ACCESS_KEY)
+            .env("AWS_SECRET_ACCESS_KEY", SECRET_KEY)
+            .env("AWS_DEFAULT_REGION", "us-east-1")
+            .spawn()
+            .expect("failed to start zipstream");
+
+        let started = Instant::now();
+        while TcpStream::connect(addr).is_err() {
+            assert!(started.elapsed() < Duration::from_secs(10), "zipstream didn't start listening");
+            std::thread::sleep(Duration::from_millis(50));
+        }
+
+        Zipstream { process, addr }
+    }
+
+    async fn get(&self, uri_path: &str, range: Option<&str>) -> (StatusCode, Vec<u8>) {
+        let mut req = Request::get(format!("http://{}{}", self.addr, uri_path));
+        if let Some(range) = range {
+            req = req.header(hyper::header::RANGE, range);
+        }
+
+        let res = Client::new().request(req.body(Body::empty()).unwrap()).await.unwrap();
+        let status = res.status();
+        (status, hyper::body::to_bytes(res.into_body()).await.unwrap().to_vec())
+    }
+}
+
+impl Drop for Zipstream {
+    fn drop(&mut self) {
+        self.process.kill().ok();
+        self.process.wait().ok();
+    }
+}
+
+fn minio_image() -> RunnableImage<GenericImage> {
+    let image = GenericImage::new("minio/minio", "RELEASE.2021-06-17T00-10-46Z")
+        .with_env_var("MINIO_ROOT_USER", ACCESS_KEY)
+        .with_env_var("MINIO_ROOT_PASSWORD", SECRET_KEY)
+        .with_exposed_port(9000)
+        .with_wait_for(WaitFor::message_on_stdout("API:"));
+    RunnableImage::from((image, vec!["server".into(), "/data".into()]))
+}
+
+fn s3_client(endpoint: &str) -> S3Client {
+    S3Client::new_with(
+        HttpClient::new().unwrap(),
+        StaticProvider::new_minimal(ACCESS_KEY.into(), SECRET_KEY.into()),
+        Region::Custom { name: "us-east-1".into(), endpoint: endpoint.into() },
+    )
+}
+
+async fn put_object(s3: &S3Client, key: &str, content: &[u8]) {
+    s3.put_object(PutObjectRequest {
+        bucket: BUCKET.into(),
+        key: key.into(),
+        body: Some(content.to_vec().into()),
+        ..PutObjectRequest::default()
+    }).await.unwrap();
+}
+
+fn manifest_entry(name: &str, key: &str, content: &[u8]) -> serde_json::Value {
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(content);
+
+    json!({
+        "archive_name": name,
+        "source": format!("s3://{}/{}", BUCKET, key),
+        "length": content.len(),
+        "crc": crc.finalize(),
+        "last_modified": "2021-06-01T12:00:00Z",
+    })
+}
+
+/// Serve `manifest` as the zip manifest for `uri_path`
+async fn mount_manifest(upstream: &MockServer, uri_path: &str, manifest: serde_json::Value) {
+    Mock::given(method("GET"))
+        .and(path(uri_path))
+        .respond_with(ResponseTemplate::new(200).insert_header("X-Zip-Stream", "true").set_body_json(manifest))
+        .mount(upstream)
+        .await;
+}
+
+fn read_zip(body: Vec<u8>) -> Vec<(String, Vec<u8>)> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(body)).expect("invalid zip");
+    (0..archive.len()).map(|i| {
+        let mut file = archive.by_index(i).unwrap();
+        let mut content = Vec::new();
+        file.read_to_end(&mut content).unwrap();
+        (file.name().to_owned(), content)
+    }).collect()
+}
+
+#[tokio::test]
+async fn test_integration() {
+    let docker = clients::Cli::default();
+    let minio = docker.run(minio_image());
+    let s3_endpoint = format!("http://127.0.0.1:{}", minio.get_host_port_ipv4(9000));
+
+    let s3 = s3_client(&s3_endpoint);
+    s3.create_bucket(CreateBucketRequest { bucket: BUCKET.into(), ..CreateBucketRequest::default() }).await.unwrap();
+
+    let files: Vec<(String, Vec<u8>)> = (0..100)
+        .map(|i| (format!("dir{}/file{}.txt", i % 10, i), format!("File {}\n", i).repeat(i + 1).into_bytes()))
+        .collect();
+    for (key, content) in &files {
+        put_object(&s3, key, content).await;
+    }
+
+    let upstream = MockServer::start().await;
+    mount_manifest(&upstream, "/one.zip", json!({
+        "filename": "one.zip",
+        "entries": [manifest_entry("a.txt", &files[5].0, &files[5].1)],
+    })).await;
+    mount_manifest(&upstream, "/empty.zip", json!({ "filename": "empty.zip", "entries": [] })).await;
+    mount_manifest(&upstream, "/hundred.zip", json!({
+        "filename": "hundred.zip",
+        "entries": files.iter().map(|(key, content)| manifest_entry(key, key, content)).collect::<Vec<_>>(),
+    })).await;
+    mount_manifest(&upstream, "/missing.zip", json!({
+        "filename": "missing.zip",
+        "entries": [{
+            "archive_name": "missing.txt",
+            "source": format!("s3://{}/does/not/exist.txt", BUCKET),
+            "length": 10,
+            "last_modified": "2021-06-01T12:00:00Z",
+        }],
+    })).await;
+
+    // One entry assembled from three objects, with its CRC computed from the parts
+    let parts: Vec<Vec<u8>> = (0..3).map(|i| format!("Part {}\n", i).repeat(1000 * (i + 1)).into_bytes()).collect();
+    for (i, content) in parts.iter().enumerate() {
+        put_object(&s3, &format!("parts/video.{:03}", i), content).await;
+    }
+    mount_manifest(&upstream, "/parts.zip", json!({
+        "filename": "parts.zip",
+        "entries": [{
+            "archive_name": "video.txt",
+            "parts": parts.iter().enumerate().map(|(i, content)| json!({
+                "source": format!("s3://{}/parts/video.{:03}", BUCKET, i),
+                "length": content.len(),
+            })).collect::<Vec<_>>(),
+            "last_modified": "2021-06-01T12:00:00Z",
+        }],
+    })).await;
+
+    let zipstream = Zipstream::start(&upstream.uri(), &s3_endpoint);
+
+    // Full download
+    let (status, full) = zipstream.get("/one.zip", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(read_zip(full.clone()), vec![("a.txt".to_owned(), files[5].1.clone())]);
+
+    // Range download matches the same bytes of the full download
+    let (status, part) = zipstream.get("/one.zip", Some("bytes=10-49")).await;
+    assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(part, &full[10..50]);
+
+    // Entry concatenated from parts, and a range spanning all three of them
+    let (status, full) = zipstream.get("/parts.zip", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(read_zip(full.clone()), vec![("video.txt".to_owned(), parts.concat())]);
+    let range_end = full.len() - 100;
+    let (status, part) = zipstream.get("/parts.zip", Some(&format!("bytes=50-{}", range_end - 1))).await;
+    assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(part, &full[50..range_end]);
+
+    // Entry missing from S3, detected while computing its CRC before the response starts
+    let (status, _) = zipstream.get("/missing.zip", None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    // Manifest with no entries
+    let (status, body) = zipstream.get("/empty.zip", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(read_zip(body).is_empty());
+
+    // Manifest with 100 entries, returned sorted by name
+    let (status, body) = zipstream.get("/hundred.zip", None).await;
+    assert_eq!(status, StatusCode::OK);
+    let mut expected = files.clone();
+    expected.sort();
+    assert_eq!(read_zip(body), expected);
+}